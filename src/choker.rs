@@ -0,0 +1,188 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashSet;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+// the optimistic unchoke moves every third round
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+// peers connected this recently are more likely to get the optimistic unchoke, they have no
+// pieces to trade yet
+const NEW_PEER: Duration = Duration::from_secs(60);
+const NEW_PEER_WEIGHT: u64 = 3;
+
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub addr: SocketAddr,
+    pub interested: bool,
+    // bytes per second over the last round
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub connected: Instant,
+}
+
+// Tit-for-tat: the peers we download the most from get the upload slots, or those we upload
+// the most to when seeding, plus one optimistic unchoke so that newcomers get a chance.
+#[derive(Debug)]
+pub struct Choker {
+    slots: usize,
+    optimistic: Option<SocketAddr>,
+    round: u32,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Choker {
+            slots,
+            optimistic: None,
+            round: 0,
+        }
+    }
+
+    // Peers to unchoke until the next round.
+    pub fn rechoke(&mut self, candidates: &[Candidate], seeding: bool) -> HashSet<SocketAddr> {
+        let mut interested: Vec<_> = candidates.iter().filter(|c| c.interested).collect();
+        interested.sort_by_key(|c| {
+            let rate = if seeding {
+                c.upload_rate
+            } else {
+                c.download_rate
+            };
+            std::cmp::Reverse(rate)
+        });
+        let unchoked: HashSet<_> = interested.iter().take(self.slots).map(|c| c.addr).collect();
+
+        let kept = self
+            .optimistic
+            .filter(|addr| interested.iter().any(|c| c.addr == *addr) && !unchoked.contains(addr));
+        if kept.is_none() || self.round.is_multiple_of(OPTIMISTIC_ROUNDS) {
+            let others: Vec<_> = interested
+                .iter()
+                .filter(|c| !unchoked.contains(&c.addr))
+                .collect();
+            self.optimistic = pick_weighted(&others);
+        } else {
+            self.optimistic = kept;
+        }
+        self.round += 1;
+
+        unchoked.into_iter().chain(self.optimistic).collect()
+    }
+}
+
+fn pick_weighted(candidates: &[&&Candidate]) -> Option<SocketAddr> {
+    let weight = |c: &Candidate| {
+        if c.connected.elapsed() < NEW_PEER {
+            NEW_PEER_WEIGHT
+        } else {
+            1
+        }
+    };
+    let total: u64 = candidates.iter().map(|c| weight(c)).sum();
+    if total == 0 {
+        return None;
+    }
+    // every `RandomState` has new random keys
+    let mut pick = RandomState::new().hash_one(total) % total;
+    for c in candidates {
+        if pick < weight(c) {
+            return Some(c.addr);
+        }
+        pick -= weight(c);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(i: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, i], 6881))
+    }
+
+    // peer `i` downloads at `rates[i].0` and uploads at `rates[i].1`, all are interested
+    fn candidates(rates: &[(u64, u64)]) -> Vec<Candidate> {
+        rates
+            .iter()
+            .enumerate()
+            .map(|(i, &(download_rate, upload_rate))| Candidate {
+                addr: addr(i as u8),
+                interested: true,
+                download_rate,
+                upload_rate,
+                connected: Instant::now(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fastest_peers_get_the_slots() {
+        // the last is the fastest of all, but doesn't want anything
+        let mut peers = candidates(&[(10, 40), (30, 10), (20, 30), (40, 20), (100, 100)]);
+        peers[4].interested = false;
+
+        // 2 slots, and the optimistic unchoke for one of the others
+        for (seeding, slots, others) in [(false, [3, 1], [0, 2]), (true, [0, 2], [1, 3])] {
+            let mut choker = Choker::new(2);
+            let unchoked = choker.rechoke(&peers, seeding);
+            assert_eq!(unchoked.len(), 3);
+            assert!(slots.iter().all(|&i| unchoked.contains(&addr(i))));
+            assert!(others.map(addr).contains(&choker.optimistic.unwrap()));
+        }
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates() {
+        let mut peers = candidates(&[(50, 0), (0, 0), (0, 0), (0, 0), (0, 0)]);
+        let mut choker = Choker::new(1);
+        let unchoked = choker.rechoke(&peers, false);
+        assert_eq!(unchoked.len(), 2);
+        assert!(unchoked.contains(&addr(0)));
+        let optimistic = choker.optimistic.unwrap();
+
+        // kept until the round it moves in
+        for _ in 1..OPTIMISTIC_ROUNDS {
+            let unchoked = choker.rechoke(&peers, false);
+            assert_eq!(unchoked, HashSet::from([addr(0), optimistic]));
+        }
+
+        // given up early when it loses interest
+        let index = peers.iter().position(|c| c.addr == optimistic).unwrap();
+        peers[index].interested = false;
+        let unchoked = choker.rechoke(&peers, false);
+        assert_eq!(unchoked.len(), 2);
+        assert!(!unchoked.contains(&optimistic));
+
+        // or when it earns a slot
+        let optimistic = choker.optimistic.unwrap();
+        let index = peers.iter().position(|c| c.addr == optimistic).unwrap();
+        peers[index].download_rate = 100;
+        let unchoked = choker.rechoke(&peers, false);
+        assert_eq!(unchoked.len(), 2);
+        assert!(unchoked.contains(&optimistic));
+        assert_ne!(choker.optimistic, Some(optimistic));
+    }
+
+    #[test]
+    fn new_peers_are_favored() {
+        let old = Instant::now() - 2 * NEW_PEER;
+        let mut peers = candidates(&[(0, 0), (0, 0)]);
+        peers[0].connected = old;
+        let others: Vec<_> = peers.iter().collect();
+        let others: Vec<_> = others.iter().collect();
+        let new = (0..1000)
+            .filter(|_| pick_weighted(&others) == Some(addr(1)))
+            .count();
+        // 3 in 4 on average
+        assert!((600..900).contains(&new), "{new}");
+        assert_eq!(pick_weighted(&[]), None);
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Context};
+use sha1::{Digest, Sha1};
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::storage::FileLayout;
+use crate::torrent::{FileEntry, TorrentFile, TorrentInfo, UrlList};
+use crate::verify;
+
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+const MAX_PIECE_LENGTH: usize = 16 * 1024 * 1024;
+const TARGET_PIECES: usize = 1500;
+
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+    // picked from the total size when not set
+    pub piece_length: Option<usize>,
+    // tiers of tracker urls, the first url becomes `announce`
+    pub trackers: Vec<Vec<String>>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+    pub creation_date: Option<i64>,
+    pub private: bool,
+    pub source: Option<String>,
+    pub web_seeds: Vec<String>,
+    // glob patterns matched against relative paths and file names
+    pub exclude: Vec<String>,
+    pub threads: usize,
+}
+
+pub fn auto_piece_length(total_length: usize) -> usize {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while total_length.div_ceil(piece_length) > TARGET_PIECES && piece_length < MAX_PIECE_LENGTH {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+// Regular files below `dir`, as relative path components sorted by path.
+fn walk_dir(
+    dir: &Path,
+    prefix: &[String],
+    exclude: &[glob::Pattern],
+    out: &mut Vec<(Vec<String>, PathBuf, u64)>,
+) -> anyhow::Result<()> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Reading directory {}", dir.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|n| anyhow!("Non UTF-8 file name {n:?}"))?;
+        let mut components = prefix.to_vec();
+        components.push(name.clone());
+
+        let relative = components.join("/");
+        if exclude
+            .iter()
+            .any(|p| p.matches(&relative) || p.matches(&name))
+        {
+            continue;
+        }
+
+        let meta = std::fs::metadata(entry.path())?;
+        if meta.is_dir() {
+            walk_dir(&entry.path(), &components, exclude, out)?;
+        } else if meta.is_file() {
+            out.push((components, entry.path(), meta.len()));
+        }
+    }
+    Ok(())
+}
+
+pub fn create_torrent(path: &Path, options: &CreateOptions) -> anyhow::Result<TorrentFile> {
+    let name = path
+        .canonicalize()
+        .with_context(|| format!("Resolving {}", path.display()))?
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| anyhow!("Invalid name for {}", path.display()))?
+        .to_owned();
+
+    let exclude = options
+        .exclude
+        .iter()
+        .map(|p| glob::Pattern::new(p).with_context(|| format!("Invalid pattern {p}")))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let multi_file = path.is_dir();
+    let files = if multi_file {
+        let mut files = vec![];
+        walk_dir(path, &[], &exclude, &mut files)?;
+        anyhow::ensure!(!files.is_empty(), "No files found in {}", path.display());
+        files
+    } else {
+        let length = std::fs::metadata(path)
+            .with_context(|| format!("Reading {}", path.display()))?
+            .len();
+        vec![(vec![name.clone()], path.to_owned(), length)]
+    };
+
+    let total_length = files.iter().map(|(_, _, length)| *length as usize).sum();
+    let piece_length = options
+        .piece_length
+        .unwrap_or_else(|| auto_piece_length(total_length));
+    anyhow::ensure!(
+        piece_length >= MIN_PIECE_LENGTH && piece_length.is_power_of_two(),
+        "Piece length must be a power of two of at least {MIN_PIECE_LENGTH}"
+    );
+
+    let layout = FileLayout::from_files(
+        files
+            .iter()
+            .map(|(_, path, length)| (path.clone(), *length))
+            .collect(),
+        piece_length as u64,
+        multi_file,
+    );
+    let paths: Vec<_> = files.iter().map(|(_, path, _)| path.clone()).collect();
+
+    let hashes = verify::map_pieces(&layout, &paths, options.threads, |index, piece| {
+        piece
+            .map(|piece| Sha1::digest(&piece))
+            .with_context(|| format!("Hashing piece {index}"))
+    });
+    let mut pieces = Vec::with_capacity(hashes.len() * 20);
+    for hash in hashes {
+        pieces.extend_from_slice(&hash?);
+    }
+
+    let (length, files) = if multi_file {
+        let entries = files
+            .into_iter()
+            .map(|(path, _, length)| FileEntry {
+                length: length as usize,
+                path,
+                md5sum: None,
+                attr: None,
+            })
+            .collect();
+        (None, Some(entries))
+    } else {
+        (Some(total_length), None)
+    };
+
+    let info = TorrentInfo {
+        name,
+        piece_length,
+        pieces,
+        length,
+        files,
+        private: options.private.then_some(1),
+        source: options.source.clone(),
+        ..Default::default()
+    };
+
+    let trackers: Vec<Vec<String>> = options
+        .trackers
+        .iter()
+        .filter(|tier| !tier.is_empty())
+        .cloned()
+        .collect();
+
+    Ok(TorrentFile {
+        announce: trackers
+            .first()
+            .and_then(|tier| tier.first())
+            .cloned()
+            .unwrap_or_default(),
+        announce_list: (trackers.iter().map(|t| t.len()).sum::<usize>() > 1).then_some(trackers),
+        comment: options.comment.clone(),
+        created_by: options.created_by.clone(),
+        creation_date: options.creation_date,
+        encoding: None,
+        url_list: match options.web_seeds.len() {
+            0 => None,
+            1 => Some(UrlList::Single(options.web_seeds[0].clone())),
+            _ => Some(UrlList::Multiple(options.web_seeds.clone())),
+        },
+        httpseeds: None,
+        nodes: None,
+        piece_layers: None,
+        info,
+    })
+}
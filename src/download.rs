@@ -0,0 +1,1478 @@
+use anyhow::anyhow;
+use bytes::Bytes;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::bitfield::Bitfield;
+use crate::choker::{Candidate, Choker, CHOKE_INTERVAL, DEFAULT_UPLOAD_SLOTS};
+use crate::holepunch::{Holepunch, HolepunchError};
+use crate::mse::Encryption;
+use crate::net;
+use crate::peer::{BlockSource, Peer};
+use crate::peer_list::PeerList;
+use crate::picker::{PieceJob, PiecePicker};
+use crate::piece::{self, PieceCheck};
+use crate::priority::{self, FileSelection, Priority};
+use crate::ratelimit::{Rate, RateLimiter, Throttle};
+use crate::resume::ResumeState;
+use crate::storage::{self, FileLayout, FsStorage, PartStorage, PieceStore};
+use crate::torrent::TorrentFile;
+use crate::tracker::{Tracker, Transfer};
+use crate::utp::Transport;
+use crate::webseed::WebSeed;
+
+// most peers a download talks to at once, unless its options say
+pub const DEFAULT_MAX_PEERS: usize = 30;
+// connections being opened at once
+const MAX_DIALS: usize = 8;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// how often dropped connections are replaced
+const DIAL_INTERVAL: Duration = Duration::from_secs(1);
+// how long a relay gets to connect us to a peer before another is asked
+const RENDEZVOUS_TIMEOUT: Duration = Duration::from_secs(30);
+// how long we send to a peer a relay told us connects to us, to open our NAT for it
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(5);
+// how often progress is reported while running
+pub const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+// rates are averaged over this long
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+// trackers are asked again no sooner than this, or after this long when they failed
+const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+const ANNOUNCE_RETRY: Duration = Duration::from_secs(5 * 60);
+// a failing web seed waits this long, doubled after each failure in a row, and is given up
+// after the last
+const WEB_SEED_BACKOFF: Duration = Duration::from_secs(2);
+const WEB_SEED_MAX_FAILURES: u32 = 6;
+// the resume file is written in the background this often, or after this many changes
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+const SAVE_CHANGES: u64 = 32;
+
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    pub threads: usize,
+    pub selection: FileSelection,
+    // pieces just ahead of the playhead first, for streaming
+    pub sequential: bool,
+    // where peers reach us, announced to trackers
+    pub listen_port: Option<u16>,
+    // whether IPv6 peers reach us there too
+    pub listen_ipv6: bool,
+    pub encryption: Encryption,
+    // how we connect to peers
+    pub transport: Transport,
+    // bytes per second of this download, `None` for no limit
+    pub download_rate: Option<u64>,
+    pub upload_rate: Option<u64>,
+    // peers unchoked at once besides the optimistic one, `None` for the default
+    pub upload_slots: Option<usize>,
+    // peers connected at once, `None` for the default
+    pub max_peers: Option<usize>,
+    pub limits: Limits,
+}
+
+// Connection and bandwidth limits, shared by the downloads of a session.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub connections: Arc<Semaphore>,
+    pub download: Arc<RateLimiter>,
+    pub upload: Arc<RateLimiter>,
+    // what every peer gets on its own
+    pub peer_download: Rate,
+    pub peer_upload: Rate,
+}
+
+impl Limits {
+    // rates in bytes per second, `None` for no limit
+    pub fn new(max_connections: usize, download: Option<u64>, upload: Option<u64>) -> Self {
+        Limits {
+            connections: Arc::new(Semaphore::new(max_connections)),
+            download: Arc::new(RateLimiter::new(download)),
+            upload: Arc::new(RateLimiter::new(upload)),
+            peer_download: Rate::default(),
+            peer_upload: Rate::default(),
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits::new(Semaphore::MAX_PERMITS, None, None)
+    }
+}
+
+enum PieceEvent {
+    Downloaded(usize, Bytes),
+    // unverified blocks of a piece left behind by a failed peer
+    Partial(usize, Bytes),
+    // a ut_holepunch message from a peer
+    Holepunch(SocketAddr, Holepunch),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceState {
+    Done,
+    // handed to a peer or a web seed
+    Downloading,
+    Missing,
+    // not wanted
+    Skipped,
+}
+
+// Where a download stands, see `Download::progress`.
+#[derive(Debug, Clone, Default)]
+pub struct Progress {
+    // of the wanted pieces, the bytes verified and in total
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    // the wanted pieces by state
+    pub pieces_done: usize,
+    pub pieces_downloading: usize,
+    pub pieces_missing: usize,
+    // bytes per second received and sent over the last seconds, blocks only
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    // time left at the current rate, `None` while nothing comes in
+    pub eta: Option<Duration>,
+    pub peers: usize,
+    // connected peers that have every piece
+    pub seeds: usize,
+    // addresses we may connect to
+    pub known_peers: usize,
+}
+
+impl Progress {
+    pub fn is_complete(&self) -> bool {
+        self.bytes_done == self.bytes_total
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PeerStatus {
+    pub addr: SocketAddr,
+    pub id: [u8; 20],
+    // pieces the peer has
+    pub pieces: usize,
+    // pieces we got from it
+    pub downloaded: usize,
+    // bytes per second over the last choke round
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    // whether it wants our pieces, and whether we let it have them
+    pub interested: bool,
+    pub choked: bool,
+    pub encrypted: bool,
+}
+
+struct PeerEntry {
+    status: PeerStatus,
+    connected: Instant,
+    // what the choker decided for it
+    unchoke: bool,
+    // bytes downloaded from and uploaded to it, and their counts at the last choke round
+    transferred: (u64, u64),
+    counted: (u64, u64),
+    // where it takes holepunch connections, when it speaks ut_holepunch
+    holepunch: Option<SocketAddr>,
+    // ut_holepunch messages to send it
+    outbox: Vec<Holepunch>,
+}
+
+// State shared by the download loop, its peers and the readers.
+struct Shared {
+    store: Mutex<PieceStore>,
+    resume: Mutex<ResumeState>,
+    picker: Mutex<PiecePicker>,
+    // bumped for every verified piece, and once the download stops
+    progress: watch::Sender<usize>,
+    // readers stop waiting for pieces then
+    stopped: AtomicBool,
+    peers: Mutex<BTreeMap<SocketAddr, PeerEntry>>,
+    upload_slots: usize,
+    // addresses to connect to
+    candidates: Mutex<PeerList>,
+    // block bytes received and sent, by every peer and web seed so far
+    transferred: Mutex<(u64, u64)>,
+}
+
+impl Shared {
+    fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.progress.send_modify(|_| {});
+    }
+
+    fn has_piece(&self, index: usize) -> bool {
+        self.resume.lock().unwrap().have.has(index)
+    }
+
+    fn missing(&self) -> usize {
+        let resume = self.resume.lock().unwrap();
+        let picker = self.picker.lock().unwrap();
+        (0..resume.have.len())
+            .filter(|&i| !resume.have.has(i) && picker.is_wanted(i))
+            .count()
+    }
+
+    fn piece_done(&self, index: usize, piece: &[u8]) -> anyhow::Result<()> {
+        self.store.lock().unwrap().write_piece(index, piece)?;
+        self.resume.lock().unwrap().piece_done(index);
+        self.progress.send_modify(|n| *n += 1);
+        Ok(())
+    }
+
+    fn piece_partial(&self, index: usize, partial: &[u8]) -> anyhow::Result<()> {
+        let mut store = self.store.lock().unwrap();
+        let offset = store.layout.piece_offset(index);
+        store.write(offset, partial)?;
+        self.resume
+            .lock()
+            .unwrap()
+            .piece_partial(index, partial.len());
+        Ok(())
+    }
+
+    // a reader needs the piece now
+    fn want(&self, index: usize) {
+        let mut picker = self.picker.lock().unwrap();
+        picker.set_priority(index, Priority::High);
+        picker.set_playhead(index);
+    }
+}
+
+// Lists a peer in `Shared::peers` while its task runs.
+struct Connected {
+    shared: Arc<Shared>,
+    addr: SocketAddr,
+}
+
+impl Connected {
+    fn new(shared: Arc<Shared>, peer: &Peer) -> Self {
+        let addr = peer.remote_addr;
+        shared.peers.lock().unwrap().insert(
+            addr,
+            PeerEntry {
+                status: PeerStatus {
+                    addr,
+                    id: peer.remote_id,
+                    pieces: 0,
+                    downloaded: 0,
+                    download_rate: 0,
+                    upload_rate: 0,
+                    interested: false,
+                    choked: true,
+                    encrypted: peer.is_encrypted(),
+                },
+                connected: Instant::now(),
+                unchoke: false,
+                transferred: peer.transferred(),
+                counted: peer.transferred(),
+                holepunch: None,
+                outbox: vec![],
+            },
+        );
+        Connected { shared, addr }
+    }
+
+    fn update(&self, f: impl FnOnce(&mut PeerStatus)) {
+        if let Some(entry) = self.shared.peers.lock().unwrap().get_mut(&self.addr) {
+            f(&mut entry.status);
+        }
+    }
+
+    // Records the peer's state for the choker, returning whether it should be unchoked.
+    fn sync(&self, peer: &Peer) -> bool {
+        let mut peers = self.shared.peers.lock().unwrap();
+        let Some(entry) = peers.get_mut(&self.addr) else {
+            return false;
+        };
+        entry.status.pieces = peer.num_pieces();
+        entry.status.interested = peer.peer_interested();
+        let (downloaded, uploaded) = peer.transferred();
+        let mut total = self.shared.transferred.lock().unwrap();
+        total.0 += downloaded - entry.transferred.0;
+        total.1 += uploaded - entry.transferred.1;
+        drop(total);
+        entry.transferred = (downloaded, uploaded);
+        entry.holepunch = peer.supports_holepunch().then(|| peer.listen_addr());
+        // a free slot doesn't wait for the next round
+        if entry.status.interested && !entry.unchoke {
+            let unchoked = peers.values().filter(|e| e.unchoke).count();
+            let entry = peers.get_mut(&self.addr).unwrap();
+            entry.unchoke = unchoked < self.shared.upload_slots;
+        }
+        let entry = peers.get_mut(&self.addr).unwrap();
+        entry.status.choked = !entry.unchoke;
+        entry.unchoke
+    }
+
+    // ut_holepunch messages queued for the peer
+    fn take_outbox(&self) -> Vec<Holepunch> {
+        match self.shared.peers.lock().unwrap().get_mut(&self.addr) {
+            Some(entry) => std::mem::take(&mut entry.outbox),
+            None => vec![],
+        }
+    }
+}
+
+impl Drop for Connected {
+    fn drop(&mut self) {
+        let entry = self.shared.peers.lock().unwrap().remove(&self.addr);
+        let useful = entry.is_some_and(|e| e.transferred != (0, 0));
+        self.shared
+            .candidates
+            .lock()
+            .unwrap()
+            .disconnected(self.addr, useful);
+    }
+}
+
+impl BlockSource for Shared {
+    fn bitfield(&self) -> Bitfield {
+        self.resume.lock().unwrap().have.clone()
+    }
+
+    fn read_block(&self, index: usize, begin: usize, length: usize) -> Option<Bytes> {
+        if !self.has_piece(index) {
+            return None;
+        }
+        let mut store = self.store.lock().unwrap();
+        if begin + length > store.layout.piece_data_len(index) {
+            return None;
+        }
+        let offset = store.layout.piece_offset(index) + begin as u64;
+        let mut block = vec![0; length];
+        store.read(offset, &mut block).ok()?;
+        Some(block.into())
+    }
+}
+
+// the connection and download slots a peer takes
+type Permits = (OwnedSemaphorePermit, OwnedSemaphorePermit);
+
+// Peer and web seed tasks of a running download, aborted when dropped.
+struct Swarm {
+    tasks: JoinSet<anyhow::Result<()>>,
+    // connections being opened
+    dials: JoinSet<(SocketAddr, [u8; 20], anyhow::Result<Peer>, Permits)>,
+    event_tx: mpsc::Sender<PieceEvent>,
+    event_rx: mpsc::Receiver<PieceEvent>,
+    // announces again, kept out of `tasks` so that it doesn't keep a dead swarm going
+    trackers: JoinSet<()>,
+}
+
+impl Swarm {
+    fn new() -> Self {
+        let (event_tx, event_rx) = mpsc::channel(32);
+        Swarm {
+            tasks: JoinSet::new(),
+            dials: JoinSet::new(),
+            event_tx,
+            event_rx,
+            trackers: JoinSet::new(),
+        }
+    }
+
+    // the tasks log their own errors, in their spans
+    fn log_finished(result: Result<anyhow::Result<()>, tokio::task::JoinError>) {
+        if let Err(e) = result {
+            error!("Task failed: {e}");
+        }
+    }
+}
+
+// Downloads what the peer has and we want, uploading to it meanwhile.
+async fn run_peer(
+    shared: Arc<Shared>,
+    mut peer: Peer,
+    listen_port: Option<u16>,
+    event_tx: mpsc::Sender<PieceEvent>,
+) -> anyhow::Result<()> {
+    let connected = Connected::new(shared.clone(), &peer);
+    peer.set_source(shared.clone()).await?;
+    peer.send_extension_handshake(listen_port).await?;
+    loop {
+        peer.announce_pieces().await?;
+        let unchoke = connected.sync(&peer);
+        peer.set_choking(!unchoke).await?;
+        for msg in peer.take_holepunch() {
+            event_tx
+                .send(PieceEvent::Holepunch(peer.remote_addr, msg))
+                .await?;
+        }
+        for msg in connected.take_outbox() {
+            peer.send_holepunch(msg).await?;
+        }
+        let job = shared.picker.lock().unwrap().pick(|i| peer.has_piece(i));
+        let Some(mut job) = job else {
+            peer.idle(Duration::from_millis(100)).await?;
+            continue;
+        };
+
+        match peer
+            .continue_piece(job.index, job.length, job.check, &mut job.buf)
+            .await
+        {
+            Ok(bytes) => {
+                debug!(index = job.index, bytes = bytes.len(), "Downloaded piece");
+                connected.update(|status| status.downloaded += 1);
+                event_tx
+                    .send(PieceEvent::Downloaded(job.index, bytes))
+                    .await?
+            }
+            Err(e) => {
+                if !job.buf.is_empty() {
+                    event_tx
+                        .send(PieceEvent::Partial(job.index, job.buf.clone().freeze()))
+                        .await?;
+                }
+                shared.picker.lock().unwrap().push(job);
+                return Err(e);
+            }
+        }
+    }
+}
+
+// Announces the download to its tracker, once for each swarm it is in.
+struct Announcer {
+    shared: Arc<Shared>,
+    tracker: Tracker,
+    torrent: TorrentFile,
+    info_hashes: Vec<[u8; 20]>,
+    progress: watch::Receiver<Progress>,
+}
+
+impl Announcer {
+    // Lists the peers the tracker gives, returns when to announce again.
+    async fn announce(&self) -> Duration {
+        let (downloaded, uploaded) = *self.shared.transferred.lock().unwrap();
+        let progress = self.progress.borrow().clone();
+        let transfer = Transfer {
+            uploaded,
+            downloaded,
+            left: progress.bytes_total.saturating_sub(progress.bytes_done),
+        };
+        let mut wait = None;
+        for &info_hash in &self.info_hashes {
+            match self
+                .tracker
+                .announce(&self.torrent, info_hash, transfer)
+                .await
+            {
+                Ok(announced) => {
+                    let mut candidates = self.shared.candidates.lock().unwrap();
+                    for addr in announced.peers {
+                        candidates.add(addr, info_hash);
+                    }
+                    let interval = announced.interval.max(MIN_ANNOUNCE_INTERVAL);
+                    wait = Some(wait.map_or(interval, |w: Duration| w.min(interval)));
+                }
+                Err(e) => warn!("Tracker: {e:#}"),
+            }
+        }
+        wait.unwrap_or(ANNOUNCE_RETRY)
+    }
+}
+
+// Downloads pieces from a web seed until none are missing, backing off after failures.
+async fn run_web_seed(
+    shared: Arc<Shared>,
+    seed: WebSeed,
+    event_tx: mpsc::Sender<PieceEvent>,
+) -> anyhow::Result<()> {
+    let mut failures = 0;
+    loop {
+        let job = shared.picker.lock().unwrap().pick(|_| true);
+        let Some(job) = job else {
+            if shared.missing() == 0 {
+                return Ok(());
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            continue;
+        };
+
+        match seed.download_piece(job.index, job.check).await {
+            Ok(bytes) => {
+                failures = 0;
+                shared.transferred.lock().unwrap().0 += bytes.len() as u64;
+                event_tx
+                    .send(PieceEvent::Downloaded(job.index, bytes))
+                    .await?
+            }
+            Err(e) => {
+                shared.picker.lock().unwrap().push(job);
+                failures += 1;
+                if !seed.supports_ranges() || failures >= WEB_SEED_MAX_FAILURES {
+                    return Err(e);
+                }
+                debug!("Retrying: {e:#}");
+                tokio::time::sleep(WEB_SEED_BACKOFF * 2u32.pow(failures - 1)).await;
+            }
+        }
+    }
+}
+
+// Stops the readers when `run` or `seed` ends, also when their future is dropped.
+struct Stopping<'a>(&'a Shared);
+
+impl Drop for Stopping<'_> {
+    fn drop(&mut self) {
+        self.0.stop();
+    }
+}
+
+// the next peer that connected to us, never when there is no listener
+async fn next_incoming(incoming: &mut Option<&mut mpsc::Receiver<Peer>>) -> Option<Peer> {
+    match incoming {
+        Some(incoming) => incoming.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+pub struct Download {
+    pub torrent: TorrentFile,
+    pub output: PathBuf,
+    layout: FileLayout,
+    any_skipped: bool,
+    shared: Arc<Shared>,
+    listen_port: Option<u16>,
+    listen_ipv6: bool,
+    encryption: Encryption,
+    transport: Transport,
+    limits: Limits,
+    download_limit: Arc<RateLimiter>,
+    upload_limit: Arc<RateLimiter>,
+    peer_slots: Arc<Semaphore>,
+    // and when it last ran
+    choker: Mutex<(Choker, Instant)>,
+    // peers we couldn't reach that a relay was asked to connect us to, their swarm and when
+    rendezvous: Mutex<HashMap<SocketAddr, ([u8; 20], Instant)>>,
+    // relays take turns
+    relay_turn: AtomicUsize,
+    progress: watch::Sender<Progress>,
+    // when the transfer totals were taken, for the rates
+    samples: Mutex<VecDeque<(Instant, u64, u64)>>,
+    // what the download logs in
+    span: tracing::Span,
+    // the background write of the resume file
+    saving: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Download {
+    // Prepares the output, trusting the resume file or rechecking data already there.
+    pub fn new(
+        torrent: TorrentFile,
+        output: &Path,
+        options: &DownloadOptions,
+    ) -> anyhow::Result<Self> {
+        let layout = FileLayout::new(&torrent.info)?;
+        let data_paths = layout.paths(output);
+        let checks = piece::piece_checks(&torrent, &layout)?;
+
+        let mut resume = ResumeState::new(
+            output,
+            data_paths.clone(),
+            torrent.info.wire_hash()?,
+            checks.len(),
+        );
+        let trusted = resume.load()?;
+        let data_exists = resume.data_exists();
+
+        let file_priorities = options.selection.file_priorities(&layout);
+        let piece_priorities = priority::piece_priorities(&layout, &file_priorities);
+        let skipped: Vec<_> = file_priorities
+            .iter()
+            .map(|&p| p == Priority::Skip)
+            .collect();
+        let any_skipped = skipped.iter().any(|&s| s);
+
+        let mut storage = PartStorage::new(
+            Box::new(FsStorage::new(data_paths)),
+            &layout,
+            skipped,
+            storage::part_path(output),
+        );
+        storage.restore(&layout)?;
+
+        let mut store = PieceStore::new(layout.clone(), Box::new(storage));
+        store.allocate()?;
+
+        let span = info_span!("torrent", name = %torrent.info.name);
+        if !trusted && data_exists {
+            span.in_scope(|| {
+                info!("Rechecking existing data");
+                resume.recheck(&layout, &checks, options.threads);
+            });
+        }
+
+        // skipped pieces are queued too, a reader may still ask for them
+        let mut picker = PiecePicker::new(piece_priorities);
+        picker.set_sequential(options.sequential);
+        for (piece_id, &check) in checks.iter().enumerate() {
+            if resume.have.has(piece_id) {
+                continue;
+            }
+            picker.push(Download::job(&mut store, &resume, piece_id, check)?);
+        }
+
+        let upload_slots = options.upload_slots.unwrap_or(DEFAULT_UPLOAD_SLOTS);
+        let download = Download {
+            torrent,
+            output: output.to_owned(),
+            layout,
+            any_skipped,
+            shared: Arc::new(Shared {
+                store: Mutex::new(store),
+                resume: Mutex::new(resume),
+                picker: Mutex::new(picker),
+                progress: watch::channel(0).0,
+                stopped: AtomicBool::new(false),
+                peers: Mutex::new(BTreeMap::new()),
+                upload_slots,
+                candidates: Mutex::new(PeerList::new()),
+                transferred: Mutex::new((0, 0)),
+            }),
+            listen_port: options.listen_port,
+            listen_ipv6: options.listen_ipv6,
+            encryption: options.encryption,
+            transport: options.transport.clone(),
+            limits: options.limits.clone(),
+            download_limit: Arc::new(RateLimiter::new(options.download_rate)),
+            upload_limit: Arc::new(RateLimiter::new(options.upload_rate)),
+            peer_slots: Arc::new(Semaphore::new(
+                options.max_peers.unwrap_or(DEFAULT_MAX_PEERS),
+            )),
+            choker: Mutex::new((Choker::new(upload_slots), Instant::now())),
+            rendezvous: Mutex::new(HashMap::new()),
+            relay_turn: AtomicUsize::new(0),
+            progress: watch::channel(Progress::default()).0,
+            samples: Mutex::new(VecDeque::new()),
+            span,
+            saving: Mutex::new(None),
+        };
+        download.report();
+        Ok(download)
+    }
+
+    fn job(
+        store: &mut PieceStore,
+        resume: &ResumeState,
+        piece_id: usize,
+        check: PieceCheck,
+    ) -> anyhow::Result<PieceJob> {
+        let mut job = PieceJob::new(piece_id, store.layout.piece_data_len(piece_id), check);
+        if let Some(partial) = resume.partial.iter().find(|p| p.index == piece_id) {
+            job.buf
+                .extend_from_slice(&store.read_piece(piece_id)?[..partial.length]);
+        }
+        Ok(job)
+    }
+
+    pub fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    // wanted pieces not downloaded yet
+    pub fn missing(&self) -> usize {
+        self.shared.missing()
+    }
+
+    // pieces of the selected files
+    pub fn wanted(&self) -> usize {
+        let picker = self.shared.picker.lock().unwrap();
+        (0..self.layout.num_pieces())
+            .filter(|&i| picker.is_wanted(i))
+            .count()
+    }
+
+    // bytes per second, `None` for no limit
+    pub fn rate_limits(&self) -> (Option<u64>, Option<u64>) {
+        (self.download_limit.rate(), self.upload_limit.rate())
+    }
+
+    pub fn set_rate_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.download_limit.set_rate(download);
+        self.upload_limit.set_rate(upload);
+    }
+
+    pub fn peers(&self) -> Vec<PeerStatus> {
+        self.shared
+            .peers
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.status.clone())
+            .collect()
+    }
+
+    pub fn piece_states(&self) -> Vec<PieceState> {
+        let resume = self.shared.resume.lock().unwrap();
+        let picker = self.shared.picker.lock().unwrap();
+        let mut queued = vec![false; resume.have.len()];
+        for index in picker.queued() {
+            queued[index] = true;
+        }
+        (0..resume.have.len())
+            .map(|i| {
+                if !picker.is_wanted(i) {
+                    PieceState::Skipped
+                } else if resume.have.has(i) {
+                    PieceState::Done
+                } else if queued[i] {
+                    PieceState::Missing
+                } else {
+                    PieceState::Downloading
+                }
+            })
+            .collect()
+    }
+
+    // block bytes received and sent so far
+    pub fn transferred(&self) -> (u64, u64) {
+        *self.shared.transferred.lock().unwrap()
+    }
+
+    // Watches the progress, updated every `PROGRESS_INTERVAL` while the download runs.
+    pub fn progress(&self) -> watch::Receiver<Progress> {
+        self.progress.subscribe()
+    }
+
+    fn report(&self) {
+        let mut progress = Progress::default();
+        for (index, state) in self.piece_states().into_iter().enumerate() {
+            let len = self.layout.piece_data_len(index) as u64;
+            match state {
+                PieceState::Done => {
+                    progress.pieces_done += 1;
+                    progress.bytes_done += len;
+                }
+                PieceState::Downloading => progress.pieces_downloading += 1,
+                PieceState::Missing => progress.pieces_missing += 1,
+                PieceState::Skipped => continue,
+            }
+            progress.bytes_total += len;
+        }
+
+        let (downloaded, uploaded) = *self.shared.transferred.lock().unwrap();
+        let mut samples = self.samples.lock().unwrap();
+        let now = Instant::now();
+        samples.push_back((now, downloaded, uploaded));
+        while samples.len() > 2 && now - samples[1].0 >= RATE_WINDOW {
+            samples.pop_front();
+        }
+        let (then, downloaded_then, uploaded_then) = samples[0];
+        let elapsed = (now - then).as_secs_f64();
+        if elapsed > 0.0 {
+            progress.download_rate = ((downloaded - downloaded_then) as f64 / elapsed) as u64;
+            progress.upload_rate = ((uploaded - uploaded_then) as f64 / elapsed) as u64;
+        }
+        drop(samples);
+        progress.eta = (progress.bytes_total - progress.bytes_done)
+            .checked_div(progress.download_rate)
+            .map(Duration::from_secs);
+
+        let num_pieces = self.layout.num_pieces();
+        let peers = self.shared.peers.lock().unwrap();
+        progress.peers = peers.len();
+        progress.seeds = peers
+            .values()
+            .filter(|e| e.status.pieces == num_pieces)
+            .count();
+        drop(peers);
+        progress.known_peers = self.shared.candidates.lock().unwrap().len();
+        self.progress.send_replace(progress);
+    }
+
+    // Lists a peer found on the local network, connected to before the others.
+    pub fn add_local_peer(&self, addr: SocketAddr, info_hash: [u8; 20]) {
+        self.shared
+            .candidates
+            .lock()
+            .unwrap()
+            .add_local(addr, info_hash);
+    }
+
+    // Picks the peers to upload to until the next round, going by their rates since the last.
+    fn rechoke(&self) {
+        let seeding = self.missing() == 0;
+        let (choker, rechoked) = &mut *self.choker.lock().unwrap();
+        let elapsed = rechoked.elapsed().as_secs_f64().max(1.0);
+        *rechoked = Instant::now();
+
+        let mut peers = self.shared.peers.lock().unwrap();
+        let mut candidates = vec![];
+        for (addr, entry) in peers.iter_mut() {
+            let rate = |now: u64, then: u64| ((now - then) as f64 / elapsed) as u64;
+            entry.status.download_rate = rate(entry.transferred.0, entry.counted.0);
+            entry.status.upload_rate = rate(entry.transferred.1, entry.counted.1);
+            entry.counted = entry.transferred;
+            candidates.push(Candidate {
+                addr: *addr,
+                interested: entry.status.interested,
+                download_rate: entry.status.download_rate,
+                upload_rate: entry.status.upload_rate,
+                connected: entry.connected,
+            });
+        }
+        let unchoked = choker.rechoke(&candidates, seeding);
+        for (addr, entry) in peers.iter_mut() {
+            entry.unchoke = unchoked.contains(addr);
+        }
+    }
+
+    // Downloads the wanted pieces from web seeds and tracker peers.
+    pub async fn run(&self) -> anyhow::Result<()> {
+        let _stopping = Stopping(&self.shared);
+        async {
+            if self.missing() > 0 {
+                let mut swarm = self.start().await?;
+                self.fetch(&mut swarm, None).await?;
+            }
+            self.finish()?;
+            self.report();
+            Ok(())
+        }
+        .instrument(self.span.clone())
+        .await
+    }
+
+    // Like `run`, also taking the peers that connected to us, and then keeps uploading until
+    // `incoming` is closed. `complete` is called once the wanted pieces are there.
+    pub async fn seed(
+        &self,
+        mut incoming: mpsc::Receiver<Peer>,
+        complete: impl FnOnce(),
+    ) -> anyhow::Result<()> {
+        let _stopping = Stopping(&self.shared);
+        async {
+            let mut swarm = self.start().await?;
+            if self.missing() > 0 {
+                self.fetch(&mut swarm, Some(&mut incoming)).await?;
+            }
+            self.finish()?;
+            complete();
+
+            let mut rechoke = tokio::time::interval(CHOKE_INTERVAL);
+            let mut dial = tokio::time::interval(DIAL_INTERVAL);
+            let mut report = tokio::time::interval(PROGRESS_INTERVAL);
+            let mut save = tokio::time::interval(SAVE_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = rechoke.tick() => self.rechoke(),
+                    _ = report.tick() => self.report(),
+                    _ = save.tick() => self.save_soon(true),
+                    _ = dial.tick() => self.dial(&mut swarm),
+                    Some(result) = swarm.dials.join_next() => self.dialed(&mut swarm, result),
+                    Some(event) = swarm.event_rx.recv() => self.handle_event(&mut swarm, event)?,
+                    peer = incoming.recv() => match peer {
+                        Some(peer) => self.add_peer(&mut swarm, peer),
+                        None => return Ok(()),
+                    },
+                    Some(result) = swarm.tasks.join_next() => Swarm::log_finished(result),
+                }
+            }
+        }
+        .instrument(self.span.clone())
+        .await
+    }
+
+    // Starts the web seeds and lists the tracker peers, connected to by `dial`.
+    async fn start(&self) -> anyhow::Result<Swarm> {
+        let torrent = &self.torrent;
+        let mut swarm = Swarm::new();
+
+        if self.missing() > 0 {
+            for url in torrent.web_seeds() {
+                let seed = match WebSeed::new(url, &torrent.info.name, self.layout.clone()) {
+                    Ok(seed) => seed,
+                    Err(e) => {
+                        warn!("Web seed {url}: {e:#}");
+                        continue;
+                    }
+                };
+                let event_tx = swarm.event_tx.clone();
+                let shared = self.shared.clone();
+                let span = info_span!("web_seed", url = %url);
+                swarm.tasks.spawn(
+                    async move {
+                        let result = run_web_seed(shared, seed, event_tx).await;
+                        if let Err(e) = &result {
+                            warn!("Stopped: {e:#}");
+                        }
+                        result
+                    }
+                    .instrument(span),
+                );
+            }
+        }
+
+        let mut tracker = match self.listen_port {
+            Some(port) => Tracker::with_port(port),
+            None => Tracker::new(),
+        };
+        if let Some(ip) = net::local_ipv6().filter(|_| self.listen_ipv6) {
+            tracker = tracker.with_ipv6(ip);
+        }
+        // the first peers before anything is dialed, more after the interval
+        if !torrent.announce.is_empty() {
+            let announcer = Announcer {
+                shared: self.shared.clone(),
+                tracker,
+                torrent: torrent.clone(),
+                info_hashes: torrent.info.swarm_hashes()?,
+                progress: self.progress.subscribe(),
+            };
+            let mut wait = announcer.announce().await;
+            swarm.trackers.spawn(
+                async move {
+                    loop {
+                        tokio::time::sleep(wait).await;
+                        wait = announcer.announce().await;
+                    }
+                }
+                .instrument(info_span!("tracker")),
+            );
+        }
+
+        self.dial(&mut swarm);
+        Ok(swarm)
+    }
+
+    // Connects to listed peers while there is room for them in the download and the session.
+    fn dial(&self, swarm: &mut Swarm) {
+        let (v2, encryption) = (self.torrent.info.is_v2(), self.encryption);
+        while swarm.dials.len() < MAX_DIALS {
+            let Ok(slot) = self.peer_slots.clone().try_acquire_owned() else {
+                break;
+            };
+            let Ok(connection) = self.limits.connections.clone().try_acquire_owned() else {
+                break;
+            };
+            let Some((addr, info_hash)) = self.shared.candidates.lock().unwrap().dial() else {
+                break;
+            };
+            let transport = self.transport.clone();
+            let span = info_span!("peer", addr = %addr);
+            swarm.dials.spawn(
+                async move {
+                    let peer = tokio::time::timeout(
+                        CONNECT_TIMEOUT,
+                        Peer::connect_with(addr, info_hash, v2, &transport, encryption),
+                    )
+                    .await
+                    .unwrap_or_else(|_| Err(anyhow!("Connecting to {addr} timed out")));
+                    if let Err(e) = &peer {
+                        debug!("Connecting: {e:#}");
+                    }
+                    (addr, info_hash, peer, (slot, connection))
+                }
+                .instrument(span),
+            );
+        }
+    }
+
+    fn dialed(
+        &self,
+        swarm: &mut Swarm,
+        result: Result<
+            (SocketAddr, [u8; 20], anyhow::Result<Peer>, Permits),
+            tokio::task::JoinError,
+        >,
+    ) {
+        match result {
+            Ok((_, _, Ok(peer), permits)) => {
+                self.shared
+                    .candidates
+                    .lock()
+                    .unwrap()
+                    .connected(peer.remote_addr);
+                self.run_peer(swarm, peer, permits);
+            }
+            Ok((addr, info_hash, Err(_), _)) => {
+                self.shared.candidates.lock().unwrap().failed(addr);
+                self.ask_rendezvous(addr, info_hash);
+            }
+            Err(e) => error!("Dial failed: {e}"),
+        }
+    }
+
+    // Runs a peer that connected to us while there is room for it in the download and the
+    // session.
+    fn add_peer(&self, swarm: &mut Swarm, peer: Peer) {
+        let Ok(slot) = self.peer_slots.clone().try_acquire_owned() else {
+            debug!("Too many peers, dropping {}", peer.remote_addr);
+            return;
+        };
+        let Ok(connection) = self.limits.connections.clone().try_acquire_owned() else {
+            debug!("Connection limit reached, dropping {}", peer.remote_addr);
+            return;
+        };
+        self.run_peer(swarm, peer, (slot, connection));
+    }
+
+    fn run_peer(&self, swarm: &mut Swarm, mut peer: Peer, permits: Permits) {
+        peer.set_rate_limits(
+            Throttle::new(vec![
+                Arc::new(RateLimiter::with_rate(self.limits.peer_download.clone())),
+                self.download_limit.clone(),
+                self.limits.download.clone(),
+            ]),
+            Throttle::new(vec![
+                Arc::new(RateLimiter::with_rate(self.limits.peer_upload.clone())),
+                self.upload_limit.clone(),
+                self.limits.upload.clone(),
+            ]),
+        );
+
+        let shared = self.shared.clone();
+        let (listen_port, event_tx) = (self.listen_port, swarm.event_tx.clone());
+        let span = info_span!("peer", addr = %peer.remote_addr);
+        swarm.tasks.spawn(
+            async move {
+                let _permits = permits;
+                debug!("Connected");
+                let result = run_peer(shared, peer, listen_port, event_tx).await;
+                if let Err(e) = &result {
+                    debug!("Disconnected: {e:#}");
+                }
+                result
+            }
+            .instrument(span),
+        );
+    }
+
+    fn handle_event(&self, swarm: &mut Swarm, event: PieceEvent) -> anyhow::Result<()> {
+        match event {
+            PieceEvent::Downloaded(piece_id, piece) => {
+                self.shared.piece_done(piece_id, &piece[..])?;
+            }
+            PieceEvent::Partial(piece_id, partial) => {
+                self.shared.piece_partial(piece_id, &partial[..])?;
+            }
+            PieceEvent::Holepunch(from, msg) => self.holepunch(swarm, from, msg),
+        }
+        self.save_soon(false);
+        Ok(())
+    }
+
+    // Writes the resume file in the background once enough changed, or anything with `force`.
+    // `save` writes it at once.
+    fn save_soon(&self, force: bool) {
+        let mut saving = self.saving.lock().unwrap();
+        if saving.as_ref().is_some_and(|task| !task.is_finished()) {
+            return;
+        }
+        let resume = self.shared.resume.lock().unwrap();
+        let unsaved = resume.unsaved();
+        if unsaved == 0 || (!force && unsaved < SAVE_CHANGES) {
+            return;
+        }
+        let snapshot = resume.snapshot();
+        drop(resume);
+        *saving = Some(tokio::task::spawn_blocking(move || {
+            if let Err(e) = snapshot.write() {
+                warn!("Saving resume file: {e:#}");
+            }
+        }));
+    }
+
+    // Asks a connected peer to get us and a peer we couldn't reach to connect at once, which
+    // gets through NATs. Without peer exchange we don't know who is connected to the peer, so
+    // relays are asked in turn.
+    fn ask_rendezvous(&self, addr: SocketAddr, info_hash: [u8; 20]) {
+        if !matches!(self.transport, Transport::Utp(_)) {
+            return;
+        }
+        let mut rendezvous = self.rendezvous.lock().unwrap();
+        rendezvous.retain(|_, (_, asked)| asked.elapsed() < RENDEZVOUS_TIMEOUT);
+        if rendezvous.contains_key(&addr) {
+            return;
+        }
+        let mut peers = self.shared.peers.lock().unwrap();
+        let relays: Vec<_> = peers
+            .iter()
+            .filter(|(_, e)| e.holepunch.is_some_and(|a| a != addr))
+            .map(|(relay, _)| *relay)
+            .collect();
+        if relays.is_empty() {
+            return;
+        }
+        let relay = relays[self.relay_turn.fetch_add(1, Ordering::Relaxed) % relays.len()];
+        peers
+            .get_mut(&relay)
+            .unwrap()
+            .outbox
+            .push(Holepunch::Rendezvous(addr));
+        rendezvous.insert(addr, (info_hash, Instant::now()));
+    }
+
+    fn holepunch(&self, swarm: &mut Swarm, from: SocketAddr, msg: Holepunch) {
+        match msg {
+            Holepunch::Rendezvous(target) => self.relay(from, target),
+            Holepunch::Connect(addr) => {
+                let asked = self.rendezvous.lock().unwrap().remove(&addr);
+                match (asked, &self.transport) {
+                    // the peer we asked for connects to us now, we connect to it at the same
+                    // time; it may have failed too often and been dropped meanwhile
+                    (Some((info_hash, _)), _) => {
+                        let mut candidates = self.shared.candidates.lock().unwrap();
+                        candidates.add(addr, info_hash);
+                        candidates.retry_now(addr);
+                        drop(candidates);
+                        self.dial(swarm);
+                    }
+                    // a peer that couldn't reach us connects now: sending to it opens our NAT
+                    // for it, the connection is its own to make
+                    (None, Transport::Utp(socket)) => {
+                        let socket = socket.clone();
+                        swarm.tasks.spawn(async move {
+                            let _ = tokio::time::timeout(PUNCH_TIMEOUT, socket.connect(addr)).await;
+                            Ok(())
+                        });
+                    }
+                    (None, Transport::Tcp) => {}
+                }
+            }
+            Holepunch::Error(addr, e) => {
+                debug!("Holepunch to {addr} through {from}: {e}");
+                self.rendezvous.lock().unwrap().remove(&addr);
+            }
+        }
+    }
+
+    // Tells both peers to connect to each other, `from` asked to reach `target`.
+    fn relay(&self, from: SocketAddr, target: SocketAddr) {
+        let mut peers = self.shared.peers.lock().unwrap();
+        let Some(initiator) = peers.get(&from).and_then(|e| e.holepunch) else {
+            return;
+        };
+        let found = peers
+            .iter()
+            .find(|(addr, e)| **addr == target || e.holepunch == Some(target))
+            .map(|(addr, e)| (*addr, e.holepunch));
+        let error = match found {
+            _ if initiator == target => HolepunchError::NoSelf,
+            None => HolepunchError::NotConnected,
+            Some((_, None)) => HolepunchError::NoSupport,
+            Some((addr, Some(_))) => {
+                peers
+                    .get_mut(&addr)
+                    .unwrap()
+                    .outbox
+                    .push(Holepunch::Connect(initiator));
+                peers
+                    .get_mut(&from)
+                    .unwrap()
+                    .outbox
+                    .push(Holepunch::Connect(target));
+                return;
+            }
+        };
+        peers
+            .get_mut(&from)
+            .unwrap()
+            .outbox
+            .push(Holepunch::Error(target, error));
+    }
+
+    async fn fetch(
+        &self,
+        swarm: &mut Swarm,
+        mut incoming: Option<&mut mpsc::Receiver<Peer>>,
+    ) -> anyhow::Result<()> {
+        let mut rechoke = tokio::time::interval(CHOKE_INTERVAL);
+        let mut dial = tokio::time::interval(DIAL_INTERVAL);
+        let mut report = tokio::time::interval(PROGRESS_INTERVAL);
+        let mut save = tokio::time::interval(SAVE_INTERVAL);
+        while self.missing() > 0 {
+            if swarm.tasks.is_empty()
+                && swarm.dials.is_empty()
+                && swarm.event_rx.is_empty()
+                && self.shared.candidates.lock().unwrap().is_empty()
+                && incoming.is_none()
+            {
+                self.save()?;
+                anyhow::bail!("All peers disconnected, {} pieces missing", self.missing());
+            }
+
+            tokio::select! {
+                _ = rechoke.tick() => self.rechoke(),
+                _ = report.tick() => self.report(),
+                _ = save.tick() => self.save_soon(true),
+                _ = dial.tick() => self.dial(swarm),
+                Some(result) = swarm.dials.join_next() => self.dialed(swarm, result),
+                Some(event) = swarm.event_rx.recv() => self.handle_event(swarm, event)?,
+                Some(result) = swarm.tasks.join_next() => Swarm::log_finished(result),
+                Some(peer) = next_incoming(&mut incoming) => self.add_peer(swarm, peer),
+            }
+        }
+        Ok(())
+    }
+
+    // Writes everything out, also used when the download is interrupted.
+    pub fn save(&self) -> anyhow::Result<()> {
+        self.shared.store.lock().unwrap().flush()?;
+        self.shared.resume.lock().unwrap().save()
+    }
+
+    fn finish(&self) -> anyhow::Result<()> {
+        self.save()?;
+        if !self.any_skipped {
+            match std::fs::remove_file(storage::part_path(&self.output)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Reads one file of the torrent while it downloads.
+    pub fn reader(&self, file_index: usize) -> anyhow::Result<FileReader> {
+        let file = self
+            .layout
+            .files
+            .get(file_index)
+            .ok_or_else(|| anyhow!("Invalid file index {file_index}"))?;
+        Ok(FileReader {
+            shared: self.shared.clone(),
+            piece_length: self.layout.piece_length,
+            offset: file.offset,
+            length: file.length,
+            pos: 0,
+            progress: self.shared.progress.subscribe(),
+            waiting: None,
+        })
+    }
+}
+
+// `AsyncRead + AsyncSeek` over a file of a download. Reading data that isn't there yet raises
+// the priority of its piece and waits until the piece is verified.
+pub struct FileReader {
+    shared: Arc<Shared>,
+    piece_length: u64,
+    offset: u64,
+    length: u64,
+    pos: u64,
+    progress: watch::Receiver<usize>,
+    waiting: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl FileReader {
+    pub fn len(&self) -> u64 {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+}
+
+impl AsyncRead for FileReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(waiting) = &mut this.waiting {
+                std::task::ready!(waiting.as_mut().poll(cx));
+                this.waiting = None;
+            }
+
+            if this.pos >= this.length || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            // up to the end of the piece under the read position
+            let global = this.offset + this.pos;
+            let piece = (global / this.piece_length) as usize;
+            let piece_end = (piece as u64 + 1) * this.piece_length;
+            let len = (buf.remaining() as u64)
+                .min(this.length - this.pos)
+                .min(piece_end - global) as usize;
+
+            // mark what we've seen before checking, so that no piece is missed
+            let mut progress = this.progress.clone();
+            progress.borrow_and_update();
+
+            if this.shared.has_piece(piece) {
+                let mut data = vec![0; len];
+                this.shared
+                    .store
+                    .lock()
+                    .unwrap()
+                    .read(global, &mut data)
+                    .map_err(std::io::Error::other)?;
+                buf.put_slice(&data);
+                this.pos += len as u64;
+                return Poll::Ready(Ok(()));
+            }
+
+            if this.shared.stopped.load(Ordering::Relaxed) {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Download stopped",
+                )));
+            }
+            this.shared.want(piece);
+            this.waiting = Some(Box::pin(async move {
+                let _ = progress.changed().await;
+            }));
+        }
+    }
+}
+
+impl AsyncSeek for FileReader {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+        let this = self.get_mut();
+        let pos = match position {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => this.length.checked_add_signed(delta),
+            SeekFrom::Current(delta) => this.pos.checked_add_signed(delta),
+        };
+        this.pos = pos.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seek before start")
+        })?;
+        if this.pos < this.length {
+            let piece = ((this.offset + this.pos) / this.piece_length) as usize;
+            this.shared.picker.lock().unwrap().set_playhead(piece);
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use crate::create::{self, CreateOptions};
+    use crate::http::{self, Request};
+
+    #[tokio::test]
+    async fn reader_stops_with_the_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        std::fs::write(&source, vec![7; 40000]).unwrap();
+        let options = CreateOptions {
+            threads: 1,
+            ..Default::default()
+        };
+        let torrent = create::create_torrent(&source, &options).unwrap();
+        let options = DownloadOptions {
+            threads: 1,
+            ..Default::default()
+        };
+        let download = Download::new(torrent, &dir.path().join("out"), &options).unwrap();
+
+        let mut reader = download.reader(0).unwrap();
+        let read = tokio::spawn(async move { reader.read_to_end(&mut vec![]).await });
+        // without trackers or web seeds there is no one to download from
+        assert!(download.run().await.is_err());
+        let read = tokio::time::timeout(Duration::from_secs(5), read)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(read.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+    // A web seed on loopback taking its time over every range of `data`.
+    async fn slow_web_seed(data: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data = Arc::new(data);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let data = data.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    while let Ok(request) = Request::read(&mut stream).await {
+                        let range = request.header("range").unwrap();
+                        let (start, end) = http::byte_range(range, data.len() as u64).unwrap();
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        let headers = [
+                            ("Content-Length", (end - start + 1).to_string()),
+                            (
+                                "Content-Range",
+                                format!("bytes {start}-{end}/{}", data.len()),
+                            ),
+                        ];
+                        http::write_head(&mut stream, 206, &headers).await.unwrap();
+                        stream
+                            .write_all(&data[start as usize..=end as usize])
+                            .await
+                            .unwrap();
+                        stream.flush().await.unwrap();
+                    }
+                });
+            }
+        });
+        format!("http://{addr}/data.bin")
+    }
+
+    #[tokio::test]
+    async fn progress_until_the_end() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..16 * 16384).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+        let options = CreateOptions {
+            piece_length: Some(16384),
+            web_seeds: vec![slow_web_seed(data).await],
+            threads: 1,
+            ..Default::default()
+        };
+        let torrent = create::create_torrent(&source, &options).unwrap();
+        let options = DownloadOptions {
+            threads: 1,
+            ..Default::default()
+        };
+        let download = Download::new(torrent, &dir.path().join("out"), &options).unwrap();
+
+        let mut progress = download.progress();
+        let run = tokio::spawn(async move { download.run().await });
+        let mut reports = vec![];
+        // until the download is dropped at the end of its task
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while progress.changed().await.is_ok() {
+                reports.push(progress.borrow_and_update().clone());
+            }
+        })
+        .await
+        .unwrap();
+        run.await.unwrap().unwrap();
+
+        assert!(reports
+            .windows(2)
+            .all(|w| w[0].bytes_done <= w[1].bytes_done));
+        assert!(reports
+            .iter()
+            .any(|p| p.download_rate > 0 && p.eta.is_some() && !p.is_complete()));
+        let last = reports.last().unwrap();
+        assert!(last.is_complete());
+        assert_eq!(last.pieces_done, 16);
+    }
+}
@@ -0,0 +1,187 @@
+use anyhow::{anyhow, bail, ensure};
+use bytes::{Buf, BufMut, BytesMut};
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+// The ut_holepunch extension (BEP 55): a peer connected to two others that can't reach each
+// other tells both to connect at once over uTP, which opens their NATs for each other.
+
+const RENDEZVOUS: u8 = 0;
+const CONNECT: u8 = 1;
+const ERROR: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HolepunchError {
+    // the relay doesn't know the target
+    NoSuchPeer,
+    NotConnected,
+    // the target doesn't speak the extension
+    NoSupport,
+    // we asked the relay to connect us to itself
+    NoSelf,
+    Other(u32),
+}
+
+impl From<u32> for HolepunchError {
+    fn from(code: u32) -> Self {
+        match code {
+            1 => HolepunchError::NoSuchPeer,
+            2 => HolepunchError::NotConnected,
+            3 => HolepunchError::NoSupport,
+            4 => HolepunchError::NoSelf,
+            code => HolepunchError::Other(code),
+        }
+    }
+}
+
+impl HolepunchError {
+    fn code(self) -> u32 {
+        match self {
+            HolepunchError::NoSuchPeer => 1,
+            HolepunchError::NotConnected => 2,
+            HolepunchError::NoSupport => 3,
+            HolepunchError::NoSelf => 4,
+            HolepunchError::Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for HolepunchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HolepunchError::NoSuchPeer => write!(f, "no such peer"),
+            HolepunchError::NotConnected => write!(f, "not connected"),
+            HolepunchError::NoSupport => write!(f, "no holepunch support"),
+            HolepunchError::NoSelf => write!(f, "that is the relay"),
+            HolepunchError::Other(code) => write!(f, "error {code}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Holepunch {
+    // to the relay: connect us to this peer
+    Rendezvous(SocketAddr),
+    // from the relay: connect to this peer now, it is connecting to us
+    Connect(SocketAddr),
+    // from the relay: the rendezvous with this peer failed
+    Error(SocketAddr, HolepunchError),
+}
+
+impl Holepunch {
+    pub fn addr(&self) -> SocketAddr {
+        match *self {
+            Holepunch::Rendezvous(addr) | Holepunch::Connect(addr) | Holepunch::Error(addr, _) => {
+                addr
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        buf.put_u8(match self {
+            Holepunch::Rendezvous(_) => RENDEZVOUS,
+            Holepunch::Connect(_) => CONNECT,
+            Holepunch::Error(..) => ERROR,
+        });
+        let addr = self.addr();
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                buf.put_u8(0);
+                buf.put_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                buf.put_u8(1);
+                buf.put_slice(&ip.octets());
+            }
+        }
+        buf.put_u16(addr.port());
+        if let Holepunch::Error(_, e) = self {
+            buf.put_u32(e.code());
+        }
+        buf.to_vec()
+    }
+
+    pub fn from_bytes(mut buf: &[u8]) -> anyhow::Result<Self> {
+        ensure!(buf.len() >= 2, "Short holepunch message");
+        let kind = buf.get_u8();
+        let ip: IpAddr = match buf.get_u8() {
+            0 if buf.len() >= 4 + 2 => <[u8; 4]>::try_from(&buf[..4]).unwrap().into(),
+            1 if buf.len() >= 16 + 2 => <[u8; 16]>::try_from(&buf[..16]).unwrap().into(),
+            kind => bail!("Bad holepunch address of type {kind}"),
+        };
+        buf.advance(if ip.is_ipv4() { 4 } else { 16 });
+        let addr = SocketAddr::new(ip, buf.get_u16());
+        match kind {
+            RENDEZVOUS => Ok(Holepunch::Rendezvous(addr)),
+            CONNECT => Ok(Holepunch::Connect(addr)),
+            ERROR => {
+                ensure!(buf.len() >= 4, "Holepunch error without a code");
+                Ok(Holepunch::Error(addr, buf.get_u32().into()))
+            }
+            kind => Err(anyhow!("Unknown holepunch message {kind}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let v4: SocketAddr = "1.2.3.4:6881".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:6882".parse().unwrap();
+        for addr in [v4, v6] {
+            for msg in [
+                Holepunch::Rendezvous(addr),
+                Holepunch::Connect(addr),
+                Holepunch::Error(addr, HolepunchError::NoSupport),
+                Holepunch::Error(addr, HolepunchError::Other(9)),
+            ] {
+                assert_eq!(Holepunch::from_bytes(&msg.to_bytes()).unwrap(), msg);
+            }
+        }
+
+        assert_eq!(
+            Holepunch::Connect(v4).to_bytes(),
+            [1, 0, 1, 2, 3, 4, 0x1a, 0xe1]
+        );
+        let mut error = vec![2, 1];
+        error.extend(
+            "2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        error.extend([0x1a, 0xe2, 0, 0, 0, 2]);
+        assert_eq!(
+            Holepunch::Error(v6, HolepunchError::NotConnected).to_bytes(),
+            error
+        );
+    }
+
+    #[test]
+    fn error_codes() {
+        for code in 0..6 {
+            assert_eq!(HolepunchError::from(code).code(), code);
+        }
+        assert_eq!(HolepunchError::from(1), HolepunchError::NoSuchPeer);
+        assert_eq!(HolepunchError::from(4), HolepunchError::NoSelf);
+        assert_eq!(HolepunchError::from(5), HolepunchError::Other(5));
+    }
+
+    #[test]
+    fn invalid() {
+        // unknown address type, short address, error without a code and unknown message
+        for bytes in [
+            &[0, 2, 1, 2, 3, 4, 0, 1][..],
+            &[0, 1, 1, 2, 3, 4, 0, 1],
+            &[2, 0, 1, 2, 3, 4, 0, 1],
+            &[3, 0, 1, 2, 3, 4, 0, 1],
+        ] {
+            assert!(Holepunch::from_bytes(bytes).is_err(), "{bytes:?}");
+        }
+    }
+}
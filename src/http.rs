@@ -0,0 +1,174 @@
+use anyhow::{anyhow, Context};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Just enough HTTP/1.1 for the local servers: one request per connection.
+const MAX_HEAD: usize = 64 * 1024;
+const MAX_BODY: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    // path with the query string, still percent-encoded
+    pub target: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub async fn read<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<Self> {
+        let mut head = 0;
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        head += line.len();
+
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            anyhow::bail!("Invalid request line {line:?}");
+        };
+        let (method, target) = (method.to_owned(), target.to_owned());
+
+        let mut headers = vec![];
+        loop {
+            line.clear();
+            anyhow::ensure!(reader.read_line(&mut line).await? > 0, "Truncated request");
+            head += line.len();
+            anyhow::ensure!(head <= MAX_HEAD, "Request head too long");
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| anyhow!("Invalid header {line:?}"))?;
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+        }
+
+        let mut request = Request {
+            method,
+            target,
+            headers,
+            body: vec![],
+        };
+
+        if let Some(length) = request.header("content-length") {
+            let length: usize = length.parse().context("Invalid content length")?;
+            anyhow::ensure!(length <= MAX_BODY, "Request body too long");
+            request.body = vec![0; length];
+            reader.read_exact(&mut request.body).await?;
+        }
+        Ok(request)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or_default()
+    }
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        416 => "Range Not Satisfiable",
+        _ => "Internal Server Error",
+    }
+}
+
+pub async fn write_head<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: u16,
+    headers: &[(&str, String)],
+) -> anyhow::Result<()> {
+    let mut head = format!("HTTP/1.1 {status} {}\r\n", reason(status));
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    writer.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+pub async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    status: u16,
+    content_type: &str,
+    body: &[u8],
+) -> anyhow::Result<()> {
+    write_head(
+        writer,
+        status,
+        &[
+            ("Content-Type", content_type.to_owned()),
+            ("Content-Length", body.len().to_string()),
+        ],
+    )
+    .await?;
+    writer.write_all(body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+// First range of a `Range: bytes=...` header as an inclusive range, `None` when it can't be
+// satisfied.
+pub fn byte_range(header: &str, length: u64) -> Option<(u64, u64)> {
+    let spec = header
+        .trim()
+        .strip_prefix("bytes=")?
+        .split(',')
+        .next()?
+        .trim();
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().ok()?;
+            (
+                length.checked_sub(suffix.min(length))?,
+                length.checked_sub(1)?,
+            )
+        }
+        (start, "") => (start.parse().ok()?, length.checked_sub(1)?),
+        (start, end) => (
+            start.parse().ok()?,
+            end.parse::<u64>().ok()?.min(length.checked_sub(1)?),
+        ),
+    };
+    (start <= end && start < length).then_some((start, end))
+}
+
+pub fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(b)) => {
+                out.push(b);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (b, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
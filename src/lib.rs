@@ -1,4 +1,33 @@
+pub mod bitfield;
+pub mod choker;
+pub mod create;
+pub mod download;
+pub mod holepunch;
+pub mod http;
+pub mod lsd;
+pub mod magnet;
+pub mod merkle;
+pub mod metadata;
+pub mod mse;
+pub mod natpmp;
+pub mod net;
 pub mod parser;
 pub mod peer;
+pub mod peer_list;
+pub mod picker;
+pub mod piece;
+pub mod port_map;
+pub mod priority;
+pub mod ratelimit;
+pub mod resume;
+pub mod rpc;
+pub mod serve;
+pub mod session;
+pub mod storage;
 pub mod torrent;
-pub mod tracker;
\ No newline at end of file
+pub mod tracker;
+pub mod transmission;
+pub mod upnp;
+pub mod utp;
+pub mod verify;
+pub mod webseed;
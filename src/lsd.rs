@@ -0,0 +1,199 @@
+use anyhow::Context;
+use tokio::net::UdpSocket;
+use tracing::warn;
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+pub const LSD_PORT: u16 = 6771;
+const GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f);
+
+// how often each torrent is announced, and never more often than the minimum
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+pub const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(60);
+// keeps an announce within one datagram
+const MAX_HASHES_PER_ANNOUNCE: usize = 16;
+
+// A `BT-SEARCH` from another client on the network.
+#[derive(Debug, Clone)]
+pub struct Announce {
+    // where the peer listens
+    pub addr: SocketAddr,
+    pub info_hashes: Vec<[u8; 20]>,
+}
+
+// Local Service Discovery (BEP 14): torrents are announced to multicast groups of the local
+// network, so that clients there find each other without a tracker.
+pub struct Lsd {
+    v4: UdpSocket,
+    v6: Option<UdpSocket>,
+    // where peers reach us
+    port: u16,
+    // of the groups, `LSD_PORT` but in tests
+    lsd_port: u16,
+    // tells our own announces apart when they loop back
+    cookie: String,
+}
+
+impl Lsd {
+    // Joins the groups. Only one process on a host can listen on the LSD port, others only
+    // announce. IPv6 is left out when the host has none.
+    pub async fn bind(port: u16) -> anyhow::Result<Self> {
+        Lsd::bind_at(port, LSD_PORT).await
+    }
+
+    // Like `bind`, with the groups on `lsd_port`.
+    pub async fn bind_at(port: u16, lsd_port: u16) -> anyhow::Result<Self> {
+        let v4 = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, lsd_port)).await {
+            Ok(socket) => {
+                socket
+                    .join_multicast_v4(GROUP_V4, Ipv4Addr::UNSPECIFIED)
+                    .context("Joining the LSD group")?;
+                socket
+            }
+            Err(e) => {
+                warn!("Not listening for local peers: {e}");
+                UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?
+            }
+        };
+        // bound to the group, the unspecified address would clash with the IPv4 socket
+        let v6 = match UdpSocket::bind((GROUP_V6, lsd_port)).await {
+            Ok(socket) => socket.join_multicast_v6(&GROUP_V6, 0).ok().map(|_| socket),
+            Err(_) => UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await.ok(),
+        };
+        let cookie = RandomState::new().hash_one(SystemTime::now());
+        Ok(Lsd {
+            v4,
+            v6,
+            port,
+            lsd_port,
+            cookie: format!("{cookie:016x}"),
+        })
+    }
+
+    // Announces the torrents to both groups.
+    pub async fn announce(&self, info_hashes: &[[u8; 20]]) -> anyhow::Result<()> {
+        for chunk in info_hashes.chunks(MAX_HASHES_PER_ANNOUNCE) {
+            let host = SocketAddr::from((GROUP_V4, self.lsd_port));
+            self.v4
+                .send_to(self.message(host, chunk).as_bytes(), host)
+                .await
+                .context("Announcing to the local network")?;
+            if let Some(v6) = &self.v6 {
+                let host = SocketAddr::from((GROUP_V6, self.lsd_port));
+                // many hosts have no IPv6 route, that is fine
+                let _ = v6.send_to(self.message(host, chunk).as_bytes(), host).await;
+            }
+        }
+        Ok(())
+    }
+
+    fn message(&self, host: SocketAddr, info_hashes: &[[u8; 20]]) -> String {
+        let mut message = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {host}\r\nPort: {}\r\n",
+            self.port
+        );
+        for info_hash in info_hashes {
+            message.push_str(&format!("Infohash: {}\r\n", hex::encode(info_hash)));
+        }
+        message.push_str(&format!("cookie: {}\r\n\r\n\r\n", self.cookie));
+        message
+    }
+
+    // The next announce of another client, skipping anything else sent to the groups.
+    pub async fn recv(&self) -> anyhow::Result<Announce> {
+        let mut buf_v4 = [0; 1500];
+        let mut buf_v6 = [0; 1500];
+        loop {
+            let (buf, (len, from)) = tokio::select! {
+                received = self.v4.recv_from(&mut buf_v4) => (&buf_v4, received?),
+                received = recv_from(&self.v6, &mut buf_v6) => (&buf_v6, received?),
+            };
+            if let Some(announce) = self.parse(&buf[..len], from) {
+                return Ok(announce);
+            }
+        }
+    }
+
+    fn parse(&self, message: &[u8], from: SocketAddr) -> Option<Announce> {
+        let message = std::str::from_utf8(message).ok()?;
+        let mut lines = message.split("\r\n");
+        if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+            return None;
+        }
+        let mut port = None;
+        let mut info_hashes = vec![];
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse::<u16>().ok(),
+                "infohash" => {
+                    let mut info_hash = [0; 20];
+                    if hex::decode_to_slice(value, &mut info_hash).is_ok() {
+                        info_hashes.push(info_hash);
+                    }
+                }
+                "cookie" if value == self.cookie => return None,
+                _ => {}
+            }
+        }
+        let mut addr = from;
+        addr.set_port(port.filter(|&p| p != 0)?);
+        (!info_hashes.is_empty()).then_some(Announce { addr, info_hashes })
+    }
+}
+
+// never when there is no socket
+async fn recv_from(
+    socket: &Option<UdpSocket>,
+    buf: &mut [u8],
+) -> std::io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a port no one listens on
+    async fn free_port() -> u16 {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await.unwrap();
+        socket.local_addr().unwrap().port()
+    }
+
+    #[tokio::test]
+    async fn announce_over_multicast() {
+        let lsd_port = free_port().await;
+        let listening = Lsd::bind_at(6881, lsd_port).await.unwrap();
+        // the port is taken, this one only announces
+        let other = Lsd::bind_at(7000, lsd_port).await.unwrap();
+
+        other.announce(&[[1; 20], [2; 20]]).await.unwrap();
+        let announce = tokio::time::timeout(Duration::from_secs(5), listening.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(announce.addr.port(), 7000);
+        assert_eq!(announce.info_hashes, [[1; 20], [2; 20]]);
+
+        // our own announces are skipped, the first one may still arrive over the other group
+        listening.announce(&[[3; 20]]).await.unwrap();
+        other.announce(&[[4; 20]]).await.unwrap();
+        loop {
+            let announce = listening.recv().await.unwrap();
+            assert_ne!(announce.info_hashes, [[3; 20]]);
+            if announce.info_hashes == [[4; 20]] {
+                break;
+            }
+        }
+    }
+}
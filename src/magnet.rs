@@ -0,0 +1,258 @@
+use anyhow::{anyhow, Context};
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::http;
+use crate::mse::Encryption;
+use crate::peer::Peer;
+use crate::torrent::TorrentFile;
+use crate::tracker::{Tracker, Transfer};
+use crate::utp::Transport;
+
+// peers asked for the metadata at once
+const MAX_FETCHES: usize = 8;
+// to connect to a peer and get the whole metadata from it
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+// `magnet:?xt=urn:btih:<info hash>&dn=<name>&tr=<tracker>` link, the info hash in hex or
+// base32.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl FromStr for Magnet {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let query = s
+            .strip_prefix("magnet:?")
+            .ok_or_else(|| anyhow!("Not a magnet link {s:?}"))?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = vec![];
+        for pair in query.split('&') {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = http::percent_decode(value);
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_btih(hash)?);
+                    }
+                }
+                "dn" => name = Some(value),
+                "tr" => trackers.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(Magnet {
+            info_hash: info_hash.ok_or_else(|| anyhow!("Magnet link without a btih hash"))?,
+            name,
+            trackers,
+        })
+    }
+}
+
+impl Magnet {
+    // Gets the torrent from peers of the swarm, found through the link's trackers.
+    pub async fn fetch_torrent(
+        &self,
+        listen_port: u16,
+        transport: &Transport,
+        encryption: Encryption,
+    ) -> anyhow::Result<TorrentFile> {
+        let tracker = Tracker::with_port(listen_port);
+        // the size is unknown until we have the metadata, anything but 0 as we aren't a seed
+        let transfer = Transfer {
+            left: 1,
+            ..Transfer::default()
+        };
+        let mut peers: Vec<SocketAddr> = vec![];
+        for url in &self.trackers {
+            match tracker.announce_to(url, self.info_hash, transfer).await {
+                Ok(announced) => peers.extend(announced.peers),
+                Err(e) => warn!("Announcing to {url}: {e:#}"),
+            }
+        }
+        peers.sort();
+        peers.dedup();
+        anyhow::ensure!(
+            !peers.is_empty(),
+            "No peers for magnet {}",
+            hex::encode(self.info_hash)
+        );
+
+        let mut peers = peers.into_iter();
+        let mut fetches = JoinSet::new();
+        loop {
+            while fetches.len() < MAX_FETCHES {
+                let Some(addr) = peers.next() else {
+                    break;
+                };
+                let (info_hash, transport) = (self.info_hash, transport.clone());
+                fetches.spawn(async move {
+                    let fetch = async {
+                        let mut peer =
+                            Peer::connect_with(addr, info_hash, false, &transport, encryption)
+                                .await?;
+                        peer.fetch_metadata().await
+                    };
+                    let result = tokio::time::timeout(FETCH_TIMEOUT, fetch).await;
+                    (addr, result.unwrap_or_else(|_| Err(anyhow!("Timed out"))))
+                });
+            }
+            let Some(joined) = fetches.join_next().await else {
+                anyhow::bail!(
+                    "No peer sent the metadata of {}",
+                    hex::encode(self.info_hash)
+                );
+            };
+            match joined? {
+                (_, Ok(info)) => return self.torrent_file(&info),
+                (addr, Err(e)) => debug!("Metadata from {addr}: {e:#}"),
+            }
+        }
+    }
+
+    // The torrent file of the info dictionary, announcing to the link's trackers.
+    fn torrent_file(&self, info: &[u8]) -> anyhow::Result<TorrentFile> {
+        let mut content = b"d4:info".to_vec();
+        content.extend_from_slice(info);
+        content.push(b'e');
+        let mut torrent = TorrentFile::from_bytes(&content)?;
+        torrent.announce = self.trackers.first().cloned().unwrap_or_default();
+        if self.trackers.len() > 1 {
+            torrent.announce_list = Some(self.trackers.iter().map(|t| vec![t.clone()]).collect());
+        }
+        Ok(torrent)
+    }
+}
+
+fn parse_btih(hash: &str) -> anyhow::Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).context("Invalid hex info hash")?,
+        32 => base32_decode(hash).ok_or_else(|| anyhow!("Invalid base32 info hash"))?,
+        _ => anyhow::bail!("Invalid info hash {hash:?}"),
+    };
+    Ok(bytes.try_into().unwrap())
+}
+
+// RFC 4648 base32 without padding
+fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let (mut bits, mut acc) = (0, 0u32);
+    for c in s.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        acc = (acc << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::http::{self, Request};
+    use crate::metadata::{self, Metadata};
+
+    async fn send_extended(stream: &mut TcpStream, id: u8, payload: &[u8]) {
+        let mut msg = ((2 + payload.len()) as u32).to_be_bytes().to_vec();
+        msg.extend([20, id]);
+        msg.extend(payload);
+        stream.write_all(&msg).await.unwrap();
+    }
+
+    // a peer that has nothing but the metadata
+    async fn serve_metadata(listener: TcpListener, info: Vec<u8>) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut handshake = [0; 68];
+        stream.read_exact(&mut handshake).await.unwrap();
+        handshake[20..28].copy_from_slice(&[0, 0, 0, 0, 0, 0x10, 0, 0]);
+        stream.write_all(&handshake).await.unwrap();
+        let extensions = format!("d1:md11:ut_metadatai3ee13:metadata_sizei{}ee", info.len());
+        send_extended(&mut stream, 0, extensions.as_bytes()).await;
+
+        loop {
+            let len = stream.read_u32().await.unwrap() as usize;
+            let mut frame = vec![0; len];
+            stream.read_exact(&mut frame).await.unwrap();
+            // the client's extension handshake, its ut_metadata id is 2
+            if frame[..2] != [20, 3] {
+                continue;
+            }
+            let Metadata::Request(piece) = Metadata::from_bytes(&frame[2..]).unwrap() else {
+                panic!("Not a request");
+            };
+            let start = piece * metadata::PIECE_LEN;
+            let data = info[start..info.len().min(start + metadata::PIECE_LEN)].to_vec();
+            let msg = Metadata::Data {
+                piece,
+                total_size: info.len(),
+                data,
+            };
+            send_extended(&mut stream, 2, &msg.to_bytes()).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_torrent() {
+        // two metadata pieces long
+        let pieces = "a".repeat(20 * 1000);
+        let info = format!(
+            "d6:lengthi1000e4:name1:a12:piece lengthi16384e6:pieces{}:{pieces}e",
+            pieces.len()
+        )
+        .into_bytes();
+        let peer = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        tokio::spawn(serve_metadata(peer, info.clone()));
+
+        let tracker = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = tracker.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            Request::read(&mut stream).await.unwrap();
+            let mut body = b"d8:intervali900e5:peers6:".to_vec();
+            body.extend([127, 0, 0, 1]);
+            body.extend(peer_addr.port().to_be_bytes());
+            body.extend(b"e");
+            http::write_response(&mut stream, 200, "text/plain", &body)
+                .await
+                .unwrap();
+        });
+
+        let magnet = Magnet {
+            info_hash: Sha1::digest(&info).into(),
+            name: None,
+            trackers: vec![announce.clone()],
+        };
+        let torrent = magnet
+            .fetch_torrent(6881, &Transport::Tcp, Encryption::Disabled)
+            .await
+            .unwrap();
+        assert_eq!(torrent.announce, announce);
+        assert_eq!(torrent.info.name, "a");
+        assert_eq!(torrent.info.wire_hash().unwrap(), magnet.info_hash);
+    }
+}
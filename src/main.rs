@@ -1,20 +1,34 @@
 use anyhow::{anyhow, Context};
+use bittorrent_starter_rust::create::{self, CreateOptions};
+use bittorrent_starter_rust::download::{Download, DownloadOptions, Progress};
+use bittorrent_starter_rust::mse::Encryption;
 use bittorrent_starter_rust::parser::decode_bencoded_value;
 use bittorrent_starter_rust::peer::Peer;
+use bittorrent_starter_rust::piece;
+use bittorrent_starter_rust::priority::{FileFilter, FileSelection};
+use bittorrent_starter_rust::rpc;
+use bittorrent_starter_rust::serve;
+use bittorrent_starter_rust::session::{Session, SessionOptions};
+use bittorrent_starter_rust::storage::FileLayout;
 use bittorrent_starter_rust::torrent::TorrentFile;
 use bittorrent_starter_rust::tracker::Tracker;
+use bittorrent_starter_rust::transmission;
+use bittorrent_starter_rust::utp::{Transport, UtpSocket};
+use bittorrent_starter_rust::verify;
 use clap::Parser;
 use clap::Subcommand;
+use serde_json::{json, Value};
 
-use clap;
-use serde_bencode;
-use std::collections::BTreeMap;
 use std::fs;
-use tokio::sync::mpsc;
-
-use bytes::Bytes;
-use std::io::Write;
-use std::net::SocketAddr;
+use std::io::IsTerminal;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tracing::{info, warn};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::EnvFilter;
+
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -22,6 +36,11 @@ use std::path::PathBuf;
 struct Args {
     #[command(subcommand)]
     command: Command,
+    // more log output for each -v, less for each -q, over RUST_LOG for this crate
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    quiet: u8,
 }
 
 #[derive(Subcommand)]
@@ -46,19 +65,494 @@ enum Command {
         path: PathBuf,
         piece_id: usize,
     },
+    // resumes from `<output>.resume` when present
     Download {
         #[arg(short)]
         output: PathBuf,
         path: PathBuf,
+        #[arg(long, default_value_t = verify::default_threads())]
+        threads: usize,
+        // files to download, by index or glob, all of them when not given
+        #[arg(long)]
+        include: Vec<FileFilter>,
+        #[arg(long)]
+        exclude: Vec<FileFilter>,
+        #[arg(long)]
+        high: Vec<FileFilter>,
+        #[arg(long)]
+        low: Vec<FileFilter>,
+        #[arg(long)]
+        sequential: bool,
+        // bytes per second
+        #[arg(long)]
+        download_rate: Option<u64>,
+        #[arg(long)]
+        upload_rate: Option<u64>,
+        // peers uploaded to at once
+        #[arg(long)]
+        upload_slots: Option<usize>,
+        // peers connected at once
+        #[arg(long)]
+        max_peers: Option<usize>,
+        // disabled, prefer or require
+        #[arg(long, default_value_t)]
+        encryption: Encryption,
+        // try uTP before TCP
+        #[arg(long)]
+        utp: bool,
+        // newline-delimited JSON progress on stdout instead of the progress bar
+        #[arg(long)]
+        json: bool,
+    },
+    // writes one file of the torrent to stdout while it downloads
+    Stream {
+        #[arg(short)]
+        output: PathBuf,
+        path: PathBuf,
+        // file index or glob, the first file when not given
+        #[arg(long, default_value = "0")]
+        file: FileFilter,
+        #[arg(long, default_value_t = verify::default_threads())]
+        threads: usize,
+    },
+    // serves the files of the torrent over http while it downloads
+    Serve {
+        #[arg(short)]
+        output: PathBuf,
+        path: PathBuf,
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: IpAddr,
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        #[arg(long, default_value_t = verify::default_threads())]
+        threads: usize,
+    },
+    Create {
+        #[arg(short)]
+        output: PathBuf,
+        path: PathBuf,
+        // tracker tier, comma separated urls, can be repeated
+        #[arg(long)]
+        announce: Vec<String>,
+        #[arg(long)]
+        piece_length: Option<usize>,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long)]
+        created_by: Option<String>,
+        // unix timestamp, defaults to now
+        #[arg(long)]
+        creation_date: Option<i64>,
+        #[arg(long)]
+        no_creation_date: bool,
+        #[arg(long)]
+        private: bool,
+        #[arg(long)]
+        source: Option<String>,
+        #[arg(long)]
+        web_seed: Vec<String>,
+        #[arg(long)]
+        exclude: Vec<String>,
+        #[arg(long, default_value_t = verify::default_threads())]
+        threads: usize,
+    },
+    Verify {
+        path: PathBuf,
+        data_path: PathBuf,
+        #[arg(long, default_value_t = verify::default_threads())]
+        threads: usize,
+    },
+    // runs a session controlled through the JSON-RPC api, see `remote`
+    Daemon {
+        #[arg(long, default_value = "0.0.0.0:6881")]
+        listen: SocketAddr,
+        #[arg(long, default_value = "127.0.0.1:9091")]
+        rpc: SocketAddr,
+        #[arg(long, default_value_t = 200)]
+        max_connections: usize,
+        // bytes per second
+        #[arg(long)]
+        download_rate: Option<u64>,
+        #[arg(long)]
+        upload_rate: Option<u64>,
+        // what every peer gets on its own
+        #[arg(long)]
+        peer_download_rate: Option<u64>,
+        #[arg(long)]
+        peer_upload_rate: Option<u64>,
+        // peers each torrent uploads to at once
+        #[arg(long)]
+        upload_slots: Option<usize>,
+        // peers each torrent connects to at once
+        #[arg(long)]
+        max_peers: Option<usize>,
+        // disabled, prefer or require
+        #[arg(long, default_value_t)]
+        encryption: Encryption,
+        // TCP only
+        #[arg(long)]
+        no_utp: bool,
+        // no local peer discovery
+        #[arg(long)]
+        no_lsd: bool,
+        // no UPnP or NAT-PMP port forwarding
+        #[arg(long)]
+        no_port_mapping: bool,
+        // for torrents added without an output path
+        #[arg(long, default_value = ".")]
+        download_dir: PathBuf,
+    },
+    Remote {
+        #[arg(long, default_value = "127.0.0.1:9091")]
+        rpc: SocketAddr,
+        #[command(subcommand)]
+        command: RemoteCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum RemoteCommand {
+    // torrent file or magnet link
+    Add {
+        #[arg(short)]
+        output: PathBuf,
+        torrent: String,
+        #[arg(long)]
+        threads: Option<usize>,
+        #[arg(long)]
+        include: Vec<String>,
+        #[arg(long)]
+        exclude: Vec<String>,
+        #[arg(long)]
+        high: Vec<String>,
+        #[arg(long)]
+        low: Vec<String>,
+        #[arg(long)]
+        sequential: bool,
+    },
+    List,
+    Status {
+        id: usize,
+    },
+    Pause {
+        id: usize,
+    },
+    Resume {
+        id: usize,
+    },
+    Remove {
+        id: usize,
+        #[arg(long)]
+        delete_data: bool,
+    },
+    Priority {
+        id: usize,
+        #[arg(long)]
+        include: Vec<String>,
+        #[arg(long)]
+        exclude: Vec<String>,
+        #[arg(long)]
+        high: Vec<String>,
+        #[arg(long)]
+        low: Vec<String>,
+    },
+    Peers {
+        id: usize,
     },
+    // shows the session rate limits
+    Limits,
+    // sets the session rate limits, or those of a torrent or every peer, in bytes per second
+    Limit {
+        #[arg(long, conflicts_with = "peer")]
+        id: Option<usize>,
+        #[arg(long)]
+        peer: bool,
+        #[arg(long)]
+        download: Option<u64>,
+        #[arg(long)]
+        upload: Option<u64>,
+    },
+}
+
+fn format_rate(rate: &Value) -> String {
+    match rate.as_u64() {
+        Some(rate) => format!("{rate} B/s"),
+        None => "unlimited".to_owned(),
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+fn progress_json(progress: &Progress) -> Value {
+    json!({
+        "bytes_done": progress.bytes_done,
+        "bytes_total": progress.bytes_total,
+        "pieces_done": progress.pieces_done,
+        "pieces_downloading": progress.pieces_downloading,
+        "pieces_missing": progress.pieces_missing,
+        "download_rate": progress.download_rate,
+        "upload_rate": progress.upload_rate,
+        "eta": progress.eta.map(|eta| eta.as_secs()),
+        "peers": progress.peers,
+        "seeds": progress.seeds,
+        "known_peers": progress.known_peers,
+        "complete": progress.is_complete(),
+    })
 }
 
-const NUM_CONCURRENT_PEERS: usize = 5;
+fn progress_bar(progress: &Progress) -> String {
+    const WIDTH: usize = 30;
+    let fraction = match progress.bytes_total {
+        0 => 1.0,
+        total => progress.bytes_done as f64 / total as f64,
+    };
+    let filled = (fraction * WIDTH as f64) as usize;
+    let eta = match progress.eta {
+        Some(eta) => {
+            let secs = eta.as_secs();
+            format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+        }
+        None => "--".to_owned(),
+    };
+    format!(
+        "[{}{}] {:5.1}% {}/{} down {}/s up {}/s peers {} ({} seeds) eta {eta}",
+        "#".repeat(filled),
+        "-".repeat(WIDTH - filled),
+        fraction * 100.0,
+        format_bytes(progress.bytes_done),
+        format_bytes(progress.bytes_total),
+        format_bytes(progress.download_rate),
+        format_bytes(progress.upload_rate),
+        progress.peers,
+        progress.seeds,
+    )
+}
+
+// A JSON line on stdout, or the bar redrawn in place when stderr is a terminal.
+fn print_progress(progress: &Progress, json: bool) {
+    if json {
+        println!("{}", progress_json(progress));
+    } else if std::io::stderr().is_terminal() {
+        eprint!("\r{}\x1b[K", progress_bar(progress));
+    }
+}
+
+async fn show_progress(mut progress: watch::Receiver<Progress>, json: bool) {
+    while progress.changed().await.is_ok() {
+        print_progress(&progress.borrow_and_update(), json);
+    }
+}
+
+async fn remote(rpc: SocketAddr, command: RemoteCommand) -> anyhow::Result<()> {
+    match command {
+        RemoteCommand::Add {
+            output,
+            torrent,
+            threads,
+            include,
+            exclude,
+            high,
+            low,
+            sequential,
+        } => {
+            let mut params = json!({
+                "output": std::path::absolute(&output)?,
+                "threads": threads,
+                "include": include,
+                "exclude": exclude,
+                "high": high,
+                "low": low,
+                "sequential": sequential,
+            });
+            if torrent.starts_with("magnet:") {
+                params["magnet"] = json!(torrent);
+            } else {
+                let content = fs::read(&torrent).context("Reading torrent file")?;
+                params["metainfo"] = json!(hex::encode(content));
+            }
+            let result = rpc::call(rpc, "add", params).await?;
+            println!("Added torrent {}", result["id"]);
+        }
+        RemoteCommand::List => {
+            let result = rpc::call(rpc, "list", Value::Null).await?;
+            for torrent in result.as_array().into_iter().flatten() {
+                let done = match (torrent["pieces"].as_u64(), torrent["missing"].as_u64()) {
+                    (Some(pieces), Some(missing)) => format!("{}/{pieces}", pieces - missing),
+                    _ => "-".to_owned(),
+                };
+                println!(
+                    "{} {} {done} {}",
+                    torrent["id"],
+                    torrent["state"].as_str().unwrap_or_default(),
+                    torrent["name"].as_str().unwrap_or_default()
+                );
+            }
+        }
+        RemoteCommand::Status { id } => {
+            let result = rpc::call(rpc, "status", json!({ "id": id })).await?;
+            println!("{}", serde_json::to_string_pretty(&result)?);
+        }
+        RemoteCommand::Pause { id } => {
+            rpc::call(rpc, "pause", json!({ "id": id })).await?;
+        }
+        RemoteCommand::Resume { id } => {
+            rpc::call(rpc, "resume", json!({ "id": id })).await?;
+        }
+        RemoteCommand::Remove { id, delete_data } => {
+            let params = json!({ "id": id, "delete_data": delete_data });
+            rpc::call(rpc, "remove", params).await?;
+        }
+        RemoteCommand::Priority {
+            id,
+            include,
+            exclude,
+            high,
+            low,
+        } => {
+            let params = json!({
+                "id": id,
+                "include": include,
+                "exclude": exclude,
+                "high": high,
+                "low": low,
+            });
+            rpc::call(rpc, "set_priorities", params).await?;
+        }
+        RemoteCommand::Peers { id } => {
+            let result = rpc::call(rpc, "peers", json!({ "id": id })).await?;
+            for peer in result.as_array().into_iter().flatten() {
+                println!(
+                    "{} {} {} pieces, {} downloaded, down {} B/s, up {} B/s{}",
+                    peer["addr"].as_str().unwrap_or_default(),
+                    peer["id"].as_str().unwrap_or_default(),
+                    peer["pieces"],
+                    peer["downloaded"],
+                    peer["download_rate"],
+                    peer["upload_rate"],
+                    if peer["choked"].as_bool() == Some(false) {
+                        ", unchoked"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+        RemoteCommand::Limits => {
+            let result = rpc::call(rpc, "rate_limits", Value::Null).await?;
+            println!("Download: {}", format_rate(&result["download"]));
+            println!("Upload: {}", format_rate(&result["upload"]));
+            println!("Peer Download: {}", format_rate(&result["peer_download"]));
+            println!("Peer Upload: {}", format_rate(&result["peer_upload"]));
+        }
+        RemoteCommand::Limit {
+            id,
+            peer,
+            download,
+            upload,
+        } => {
+            let params = json!({
+                "id": id,
+                "peer": peer,
+                "download": download,
+                "upload": upload,
+            });
+            rpc::call(rpc, "set_rate_limits", params).await?;
+        }
+    }
+    Ok(())
+}
+
+fn print_metainfo(torrent: &TorrentFile) {
+    if let Some(tiers) = &torrent.announce_list {
+        for (i, tier) in tiers.iter().enumerate() {
+            println!("Tracker Tier {i}: {}", tier.join(" "));
+        }
+    }
+    if let Some(comment) = &torrent.comment {
+        println!("Comment: {comment}");
+    }
+    if let Some(created_by) = &torrent.created_by {
+        println!("Created By: {created_by}");
+    }
+    if let Some(creation_date) = torrent.creation_date {
+        println!("Creation Date: {creation_date}");
+    }
+    if let Some(encoding) = &torrent.encoding {
+        println!("Encoding: {encoding}");
+    }
+    if torrent.is_private() {
+        println!("Private: yes");
+    }
+    if let Some(source) = &torrent.info.source {
+        println!("Source: {source}");
+    }
+    if let Some(md5sum) = &torrent.info.md5sum {
+        println!("MD5: {md5sum}");
+    }
+    for url in torrent.web_seeds() {
+        println!("Web Seed: {url}");
+    }
+    for url in torrent.httpseeds.iter().flatten() {
+        println!("HTTP Seed: {url}");
+    }
+    for (host, port) in torrent.nodes.iter().flatten() {
+        println!("DHT Node: {host}:{port}");
+    }
+    if torrent.info.is_v2() {
+        println!("File Tree:");
+        for file in torrent.info.v2_files().unwrap_or_default() {
+            let root = file.pieces_root.map(hex::encode).unwrap_or_default();
+            println!("{} {} {root}", file.path.join("/"), file.length);
+        }
+    }
+    if let Some(files) = &torrent.info.files {
+        println!("Files:");
+        for file in files {
+            match &file.md5sum {
+                Some(md5sum) => println!("{} {} {md5sum}", file.path.join("/"), file.length),
+                None => println!("{} {}", file.path.join("/"), file.length),
+            }
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
 
+    let mut filter = EnvFilter::builder()
+        .with_default_directive(LevelFilter::INFO.into())
+        .from_env_lossy();
+    let verbosity = args.verbose as i32 - args.quiet as i32;
+    if verbosity != 0 {
+        let level = match verbosity {
+            ..=-3 => "off",
+            -2 => "error",
+            -1 => "warn",
+            1 => "debug",
+            _ => "trace",
+        };
+        filter = filter.add_directive(format!("{}={level}", env!("CARGO_CRATE_NAME")).parse()?);
+    }
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .init();
+
     match args.command {
         Command::Decode { value } => {
             let decoded_value = decode_bencoded_value(&value);
@@ -66,13 +560,23 @@ async fn main() -> anyhow::Result<()> {
         }
         Command::Info { path } => {
             let content = fs::read(path).context("Reading torrent file")?;
-            let torrent =
-                serde_bencode::from_bytes::<TorrentFile>(&content).context("parse torrent file")?;
+            let torrent = TorrentFile::from_bytes(&content)?;
 
             println!("Tracker URL: {}", torrent.announce);
-            println!("Length: {}", torrent.info.length);
-            println!("Info Hash: {}", hex::encode(torrent.info.hash()?));
+            println!("Length: {}", torrent.info.total_length());
+            if torrent.info.is_v1() {
+                println!("Info Hash: {}", hex::encode(torrent.info.hash()?));
+            } else {
+                println!(
+                    "Info Hash: {}",
+                    hex::encode(torrent.info.hash_v2_truncated()?)
+                );
+            }
+            if torrent.info.is_v2() {
+                println!("Info Hash v2: {}", hex::encode(torrent.info.hash_v2()?));
+            }
             println!("Piece Length: {}", torrent.info.piece_length);
+            print_metainfo(&torrent);
             println!("Piece Hashes:");
             torrent
                 .info
@@ -82,8 +586,7 @@ async fn main() -> anyhow::Result<()> {
         }
         Command::Peers { path } => {
             let content = fs::read(path).context("Reading torrent file")?;
-            let torrent =
-                serde_bencode::from_bytes::<TorrentFile>(&content).context("parse torrent file")?;
+            let torrent = TorrentFile::from_bytes(&content)?;
 
             let tracker = Tracker::new();
             let peers: Vec<_> = tracker.req_peers(&torrent).await?;
@@ -92,10 +595,8 @@ async fn main() -> anyhow::Result<()> {
         }
 
         Command::Handshake { path, peer } => {
-            eprintln!("{path:?} {peer:?}");
             let content = fs::read(path).context("Reading torrent file")?;
-            let torrent =
-                serde_bencode::from_bytes::<TorrentFile>(&content).context("parse torrent file")?;
+            let torrent = TorrentFile::from_bytes(&content)?;
 
             let peer = Peer::connect(peer, &torrent).await?;
 
@@ -107,8 +608,7 @@ async fn main() -> anyhow::Result<()> {
             piece_id,
         } => {
             let content = fs::read(path).context("Reading torrent file")?;
-            let torrent =
-                serde_bencode::from_bytes::<TorrentFile>(&content).context("parse torrent file")?;
+            let torrent = TorrentFile::from_bytes(&content)?;
 
             let tracker = Tracker::new();
             let peers: Vec<_> = tracker.req_peers(&torrent).await?;
@@ -117,111 +617,340 @@ async fn main() -> anyhow::Result<()> {
                 Peer::connect(*peers.first().ok_or_else(|| anyhow!("No peers"))?, &torrent).await?;
 
             peer.recv_bitfield().await?;
-            let piece_length = if piece_id == torrent.info.piece_hashes()?.len() - 1 {
-                torrent.info.length % torrent.info.piece_length
-            } else {
-                torrent.info.piece_length
-            };
+            let layout = FileLayout::new(&torrent.info)?;
+            let check = *piece::piece_checks(&torrent, &layout)?
+                .get(piece_id)
+                .ok_or_else(|| anyhow!("No piece {piece_id}"))?;
 
             let bytes = peer
-                .download_piece(
-                    piece_id,
-                    piece_length,
-                    *torrent.info.piece_hashes()?[piece_id],
-                )
+                .download_piece(piece_id, layout.piece_data_len(piece_id), check)
                 .await?;
 
             fs::write(&output, bytes)?;
             println!("Piece {piece_id} downloaded to {}", output.display());
         }
-        Command::Download { output, path } => {
+        Command::Download {
+            output,
+            path,
+            threads,
+            include,
+            exclude,
+            high,
+            low,
+            sequential,
+            download_rate,
+            upload_rate,
+            upload_slots,
+            max_peers,
+            encryption,
+            utp,
+            json,
+        } => {
             let content = fs::read(&path).context("Reading torrent file")?;
-            let torrent =
-                serde_bencode::from_bytes::<TorrentFile>(&content).context("parse torrent file")?;
-
-            let tracker = Tracker::new();
-            let peer_addrs: Vec<_> = tracker.req_peers(&torrent).await?;
-
-            let mut peers: Vec<_> = Vec::new();
-
-            let (peer_tx, mut dl_rx) = mpsc::channel(32);
-            for peer_addr in peer_addrs {
-                match Peer::connect(peer_addr, &torrent).await {
-                    Ok(mut peer) => {
-                        let (dl_tx, mut peer_rx) = mpsc::channel(32);
-                        let peer_tx = peer_tx.clone();
-                        tokio::spawn(async move {
-                            peer.recv_bitfield().await?;
-                            loop {
-                                if let Some((piece_id, piece_length, piece_hash)) =
-                                    peer_rx.recv().await
-                                {
-                                    let bytes = peer
-                                        .download_piece(piece_id, piece_length, piece_hash)
-                                        .await?;
-                                    peer_tx.send((piece_id, bytes)).await?;
-                                }
-                            }
-                            #[allow(unreachable_code)]
-                            Ok::<(), anyhow::Error>(())
-                        });
-
-                        peers.push(dl_tx);
-                    }
-                    Err(e) => eprintln!("Error {e:?}"),
+            let torrent = TorrentFile::from_bytes(&content)?;
+
+            let options = DownloadOptions {
+                threads,
+                selection: FileSelection {
+                    include,
+                    exclude,
+                    high,
+                    low,
+                },
+                sequential,
+                download_rate,
+                upload_rate,
+                upload_slots,
+                max_peers,
+                encryption,
+                transport: if utp {
+                    Transport::Utp(Arc::new(UtpSocket::bind("0.0.0.0:0").await?))
+                } else {
+                    Transport::Tcp
+                },
+                ..Default::default()
+            };
+            let download = Download::new(torrent, &output, &options)?;
+
+            let shown = tokio::spawn(show_progress(download.progress(), json));
+            let result = tokio::select! {
+                result = download.run() => result,
+                _ = tokio::signal::ctrl_c() => {
+                    download.save()?;
+                    Err(anyhow!("Interrupted, {} pieces missing", download.missing()))
                 }
-
-                if peers.len() >= NUM_CONCURRENT_PEERS {
-                    break;
-                };
+            };
+            shown.abort();
+            print_progress(&download.progress().borrow(), json);
+            if !json && std::io::stderr().is_terminal() {
+                eprintln!();
             }
+            result?;
 
-            //let pieces_info = torrent.info.piece_hashes()?.iter().map(|i| )
+            if !json {
+                println!("Downloaded {} to {}.", path.display(), output.display());
+            }
+        }
+        Command::Stream {
+            output,
+            path,
+            file,
+            threads,
+        } => {
+            let content = fs::read(&path).context("Reading torrent file")?;
+            let torrent = TorrentFile::from_bytes(&content)?;
 
-            for ((piece_id, piece_length, &piece_hash), peer_tx) in torrent
-                .info
-                .piece_hashes()?
+            let layout = FileLayout::new(&torrent.info)?;
+            let file_index = layout
+                .files
                 .iter()
                 .enumerate()
-                .map(|(i, h)| {
-                    (
-                        i,
-                        if i == torrent.info.piece_hashes().unwrap().len() - 1 {
-                            torrent.info.length % torrent.info.piece_length
-                        } else {
-                            torrent.info.piece_length
-                        },
-                        h,
-                    )
-                })
-                .zip(peers.iter().cycle())
-            {
-                peer_tx.send((piece_id, piece_length, piece_hash.clone())).await?;
+                .position(|(i, f)| file.matches(i, &f.path))
+                .ok_or_else(|| anyhow!("No file matches"))?;
+
+            let options = DownloadOptions {
+                threads,
+                selection: FileSelection {
+                    include: vec![FileFilter::Index(file_index)],
+                    ..Default::default()
+                },
+                sequential: true,
+                ..Default::default()
+            };
+            let download = Download::new(torrent, &output, &options)?;
+            let mut reader = download.reader(file_index)?;
+            let mut stdout = tokio::io::stdout();
+
+            let run = download.run();
+            let copy = tokio::io::copy(&mut reader, &mut stdout);
+            tokio::pin!(run, copy);
+            let mut running = true;
+            loop {
+                tokio::select! {
+                    result = &mut run, if running => {
+                        result?;
+                        running = false;
+                    }
+                    result = &mut copy => {
+                        result?;
+                        break;
+                    }
+                    _ = tokio::signal::ctrl_c() => {
+                        download.save()?;
+                        anyhow::bail!("Interrupted");
+                    }
+                }
             }
+        }
+        Command::Serve {
+            output,
+            path,
+            bind,
+            port,
+            threads,
+        } => {
+            let content = fs::read(&path).context("Reading torrent file")?;
+            let torrent = TorrentFile::from_bytes(&content)?;
 
-            let mut downloaded_pieces: BTreeMap<usize, Bytes> = BTreeMap::new();
-
-            while downloaded_pieces.len() < torrent.info.piece_hashes()?.len() {
-                if let Some((piece_id, piece)) = dl_rx.recv().await {
-                    eprintln!("Piece {piece_id} downloaded");
-                    downloaded_pieces.insert(piece_id, piece);
+            let options = DownloadOptions {
+                threads,
+                sequential: true,
+                ..Default::default()
+            };
+            let download = Arc::new(Download::new(torrent, &output, &options)?);
+            let listener = TcpListener::bind((bind, port)).await?;
+            println!(
+                "Serving {} on http://{}/",
+                path.display(),
+                listener.local_addr()?
+            );
+
+            let running = download.clone();
+            let mut run = tokio::spawn(async move { running.run().await });
+            let mut finished = false;
+            let server = serve::serve(download.clone(), listener);
+            tokio::pin!(server);
+            loop {
+                tokio::select! {
+                    result = &mut run, if !finished => {
+                        match result? {
+                            Ok(()) => info!("Download complete"),
+                            Err(e) => warn!("Download stopped: {e:#}"),
+                        }
+                        finished = true;
+                    }
+                    result = &mut server => return result,
+                    _ = tokio::signal::ctrl_c() => {
+                        download.save()?;
+                        return Ok(());
+                    }
                 }
             }
+        }
+        Command::Create {
+            output,
+            path,
+            announce,
+            piece_length,
+            comment,
+            created_by,
+            creation_date,
+            no_creation_date,
+            private,
+            source,
+            web_seed,
+            exclude,
+            threads,
+        } => {
+            let options = CreateOptions {
+                piece_length,
+                trackers: announce
+                    .iter()
+                    .map(|tier| tier.split(',').map(|url| url.trim().to_owned()).collect())
+                    .collect(),
+                comment,
+                created_by: created_by.or_else(|| {
+                    Some(format!(
+                        "{}/{}",
+                        env!("CARGO_PKG_NAME"),
+                        env!("CARGO_PKG_VERSION")
+                    ))
+                }),
+                creation_date: if no_creation_date {
+                    None
+                } else {
+                    creation_date.or_else(|| Some(create::now()))
+                },
+                private,
+                source,
+                web_seeds: web_seed,
+                exclude,
+                threads,
+            };
 
-            // write pieces into a file in order
-            let mut file = std::fs::OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(&output)?;
+            let torrent = create::create_torrent(&path, &options)?;
+            fs::write(&output, serde_bencode::to_bytes(&torrent)?)
+                .with_context(|| format!("Writing {}", output.display()))?;
 
-            for (_, bytes) in downloaded_pieces.iter() {
-                file.write(&bytes[..])?;
+            println!("Info Hash: {}", hex::encode(torrent.info.hash()?));
+            println!("Created {} from {}.", output.display(), path.display());
+        }
+        Command::Verify {
+            path,
+            data_path,
+            threads,
+        } => {
+            let content = fs::read(&path).context("Reading torrent file")?;
+            let torrent = TorrentFile::from_bytes(&content)?;
+
+            let (mut failed, mut bad_files) = (0, 0);
+
+            if torrent.info.is_v1() {
+                let layout = FileLayout::new(&torrent.info)?;
+                let data_paths = layout.paths(&data_path);
+                let checks = piece::v1_checks(&torrent, &layout)?;
+
+                let have = verify::check_pieces(&layout, &data_paths, &checks, threads);
+
+                for piece_id in 0..have.len() {
+                    let status = if have.has(piece_id) { "ok" } else { "FAILED" };
+                    println!("Piece {piece_id}: {status}");
+                }
+
+                for report in verify::file_reports(&layout, &data_paths, &have) {
+                    let status = if !report.exists {
+                        "MISSING".to_owned()
+                    } else if report.size != report.length {
+                        format!("FAILED ({} bytes on disk)", report.size)
+                    } else if report.is_complete() {
+                        "ok".to_owned()
+                    } else {
+                        format!("FAILED ({}/{} pieces)", report.verified, report.pieces)
+                    };
+                    if !report.is_complete() {
+                        bad_files += 1;
+                    }
+                    println!(
+                        "File {} ({} bytes): {status}",
+                        report.path.display(),
+                        report.length
+                    );
+                }
+
+                failed += have.len() - have.count();
+                println!("{}/{} pieces ok", have.count(), have.len());
             }
 
-            file.flush()?;
+            if torrent.info.is_v2() {
+                let data_paths = verify::v2_paths(&torrent, &data_path)?;
+                for report in verify::check_files_v2(&torrent, &data_paths, threads)? {
+                    let status = match &report.error {
+                        Some(e) => format!("FAILED ({e})"),
+                        None if report.is_complete() => "ok".to_owned(),
+                        None => format!("FAILED ({}/{} pieces)", report.verified, report.pieces),
+                    };
+                    if !report.is_complete() {
+                        failed += std::cmp::max(1, report.pieces - report.verified);
+                    }
+                    println!(
+                        "Merkle {} ({} bytes): {status}",
+                        report.path.display(),
+                        report.length
+                    );
+                }
+            }
 
-            println!("Downloaded {} to {}.", path.display(), output.display());
+            if failed > 0 || bad_files > 0 {
+                anyhow::bail!("{failed} pieces and {bad_files} files failed verification");
+            }
+        }
+        Command::Daemon {
+            listen,
+            rpc,
+            max_connections,
+            download_rate,
+            upload_rate,
+            peer_download_rate,
+            peer_upload_rate,
+            upload_slots,
+            max_peers,
+            encryption,
+            no_utp,
+            no_lsd,
+            no_port_mapping,
+            download_dir,
+        } => {
+            let options = SessionOptions {
+                listen,
+                max_connections,
+                download_rate,
+                upload_rate,
+                peer_download_rate,
+                peer_upload_rate,
+                upload_slots,
+                max_peers,
+                encryption,
+                utp: !no_utp,
+                lsd: !no_lsd,
+                port_mapping: !no_port_mapping,
+                download_dir: std::path::absolute(download_dir)?,
+            };
+            let session = Arc::new(Session::new(options).await?);
+            let listener = TcpListener::bind(rpc).await?;
+            println!(
+                "Listening for peers on {}, RPC on http://{addr}{} and http://{addr}{}",
+                session.local_addr(),
+                rpc::RPC_PATH,
+                transmission::RPC_PATH,
+                addr = listener.local_addr()?,
+            );
+
+            tokio::select! {
+                result = rpc::serve(session.clone(), listener) => result?,
+                _ = tokio::signal::ctrl_c() => session.shutdown().await?,
+            }
         }
+        Command::Remote { rpc, command } => remote(rpc, command).await?,
     }
 
     Ok(())
@@ -0,0 +1,155 @@
+use sha2::{Digest, Sha256};
+
+// BEP 52 merkle trees: leaves are SHA-256 hashes of 16 KiB blocks, missing leaves are zero.
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+pub type Hash = [u8; 32];
+
+pub fn hash_block(block: &[u8]) -> Hash {
+    Sha256::digest(block).into()
+}
+
+pub fn hash_pair(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// root of a subtree of `height` made only of zero leaves
+pub fn pad_hash(height: u32) -> Hash {
+    (0..height).fold([0; 32], |h, _| hash_pair(&h, &h))
+}
+
+pub fn num_leaves(num_blocks: usize) -> usize {
+    num_blocks.max(1).next_power_of_two()
+}
+
+// Root of the tree over `leaves`, padded with `pad` up to `width` leaves (a power of two).
+pub fn root(leaves: &[Hash], width: usize, pad: Hash) -> Hash {
+    layers(leaves, width, pad).last().unwrap()[0]
+}
+
+// All layers of the tree, from the padded leaves up to the root.
+pub fn layers(leaves: &[Hash], width: usize, pad: Hash) -> Vec<Vec<Hash>> {
+    debug_assert!(width.is_power_of_two() && leaves.len() <= width);
+
+    let mut layer = leaves.to_vec();
+    layer.resize(width, pad);
+    let mut layers = vec![layer];
+
+    while layers.last().unwrap().len() > 1 {
+        let next = layers
+            .last()
+            .unwrap()
+            .chunks_exact(2)
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        layers.push(next);
+    }
+    layers
+}
+
+pub fn block_hashes(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE).map(hash_block).collect()
+}
+
+// Hash of one piece of a file, as found in the piece layer.
+pub fn piece_hash(piece: &[u8], piece_length: usize) -> Hash {
+    root(&block_hashes(piece), piece_length / BLOCK_SIZE, [0; 32])
+}
+
+// Root of a file of at most one piece.
+pub fn small_file_root(data: &[u8]) -> Hash {
+    let leaves = block_hashes(data);
+    root(&leaves, num_leaves(leaves.len()), [0; 32])
+}
+
+// Root of the file tree built on top of its piece layer.
+pub fn piece_layer_root(piece_layer: &[Hash], piece_length: usize) -> Hash {
+    let blocks_per_piece = piece_length / BLOCK_SIZE;
+    root(
+        piece_layer,
+        num_leaves(piece_layer.len()),
+        pad_hash(blocks_per_piece.trailing_zeros()),
+    )
+}
+
+// Uncle hashes from the leaf at `index` up to (not including) the root.
+pub fn proof(layers: &[Vec<Hash>], mut index: usize) -> Vec<Hash> {
+    let mut proof = vec![];
+    for layer in &layers[..layers.len() - 1] {
+        proof.push(layer[index ^ 1]);
+        index /= 2;
+    }
+    proof
+}
+
+pub fn verify_proof(leaf: &Hash, mut index: usize, proof: &[Hash], root: &Hash) -> bool {
+    let mut hash = *leaf;
+    for uncle in proof {
+        hash = if index.is_multiple_of(2) {
+            hash_pair(&hash, uncle)
+        } else {
+            hash_pair(uncle, &hash)
+        };
+        index /= 2;
+    }
+    hash == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // five blocks, the last one short
+    fn file() -> Vec<u8> {
+        (0..80920).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn hash(hex: &str) -> Hash {
+        hex::decode(hex).unwrap().try_into().unwrap()
+    }
+
+    const FILE_ROOT: &str = "b421a8cb4bb5e027dd9dad0ecc45866fc3895a783585a5382a1da145f799773e";
+
+    #[test]
+    fn multi_block_root() {
+        let data = file();
+        let leaves = block_hashes(&data);
+        assert_eq!(leaves.len(), 5);
+        assert_eq!(
+            root(&leaves, num_leaves(leaves.len()), [0; 32]),
+            hash(FILE_ROOT)
+        );
+    }
+
+    #[test]
+    fn padded_last_piece() {
+        let data = file();
+        let piece_length = 2 * BLOCK_SIZE;
+        let layer: Vec<_> = data
+            .chunks(piece_length)
+            .map(|piece| piece_hash(piece, piece_length))
+            .collect();
+        // half a block, padded with a zero leaf
+        assert_eq!(
+            layer[2],
+            hash("0f2ccbcd43470aa0dfe7d4958cdaf7cb30584e577404171cdf065109d41b9b02")
+        );
+        // three pieces padded up to four
+        assert_eq!(piece_layer_root(&layer, piece_length), hash(FILE_ROOT));
+    }
+
+    #[test]
+    fn proofs() {
+        let leaves = block_hashes(&file());
+        let layers = layers(&leaves, 8, [0; 32]);
+        let root = hash(FILE_ROOT);
+        for (index, leaf) in leaves.iter().enumerate() {
+            let proof = proof(&layers, index);
+            assert!(verify_proof(leaf, index, &proof, &root));
+            assert!(!verify_proof(leaf, index ^ 1, &proof, &root));
+        }
+    }
+}
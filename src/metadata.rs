@@ -0,0 +1,108 @@
+use anyhow::{anyhow, ensure};
+use serde::{Deserialize, Serialize};
+
+use crate::torrent;
+
+// The ut_metadata extension (BEP 9): peers send the info dictionary of their torrent in
+// pieces, so that the info hash of a magnet link is enough to get it.
+
+// every piece but the last is this long
+pub const PIECE_LEN: usize = 16 * 1024;
+
+// largest info dictionary we fetch
+pub const MAX_SIZE: usize = 8 * 1024 * 1024;
+
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Metadata {
+    Request(usize),
+    // `total_size` is the length of the whole info dictionary
+    Data {
+        piece: usize,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    Reject(usize),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Header {
+    msg_type: i64,
+    piece: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<i64>,
+}
+
+impl Metadata {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (msg_type, piece, total_size) = match *self {
+            Metadata::Request(piece) => (REQUEST, piece, None),
+            Metadata::Data {
+                piece, total_size, ..
+            } => (DATA, piece, Some(total_size as i64)),
+            Metadata::Reject(piece) => (REJECT, piece, None),
+        };
+        let header = Header {
+            msg_type,
+            piece: piece as i64,
+            total_size,
+        };
+        let mut buf = serde_bencode::to_bytes(&header).unwrap();
+        if let Metadata::Data { data, .. } = self {
+            buf.extend_from_slice(data);
+        }
+        buf
+    }
+
+    // The data of a piece follows its bencoded header.
+    pub fn from_bytes(buf: &[u8]) -> anyhow::Result<Self> {
+        let end = torrent::bencode_end(buf, 0)?;
+        let header = serde_bencode::from_bytes::<Header>(&buf[..end])?;
+        let piece = usize::try_from(header.piece)?;
+        match header.msg_type {
+            REQUEST => Ok(Metadata::Request(piece)),
+            DATA => {
+                let total_size = header
+                    .total_size
+                    .ok_or_else(|| anyhow!("Metadata piece without a total size"))?;
+                let data = buf[end..].to_vec();
+                ensure!(data.len() <= PIECE_LEN, "Metadata piece too long");
+                Ok(Metadata::Data {
+                    piece,
+                    total_size: usize::try_from(total_size)?,
+                    data,
+                })
+            }
+            REJECT => Ok(Metadata::Reject(piece)),
+            kind => Err(anyhow!("Unknown metadata message {kind}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_follows_the_header() {
+        let bytes = b"d8:msg_typei1e5:piecei0e10:total_sizei3eeabc";
+        let msg = Metadata::from_bytes(bytes).unwrap();
+        assert_eq!(
+            msg,
+            Metadata::Data {
+                piece: 0,
+                total_size: 3,
+                data: b"abc".to_vec()
+            }
+        );
+        assert_eq!(msg.to_bytes(), bytes);
+
+        for msg in [Metadata::Request(2), Metadata::Reject(1)] {
+            assert_eq!(Metadata::from_bytes(&msg.to_bytes()).unwrap(), msg);
+        }
+        assert!(Metadata::from_bytes(b"d8:msg_typei1e5:piecei0ee").is_err());
+    }
+}
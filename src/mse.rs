@@ -0,0 +1,637 @@
+use anyhow::{anyhow, Context};
+use bytes::{Buf, BytesMut};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use std::fmt;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context as TaskContext, Poll};
+
+use crate::utp::Transport;
+
+// Message Stream Encryption, also called Protocol Encryption: a Diffie-Hellman key exchange
+// followed by RC4 on both directions, hiding the BitTorrent handshake from traffic shaping.
+
+// 768 bit safe prime of the key exchange, the generator is 2
+const PRIME: &str = concat!(
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74",
+    "020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437",
+    "4FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563",
+);
+const KEY_LEN: usize = 96;
+const PRIVATE_KEY_LEN: usize = 20;
+// random padding after the public key, and longest run of bytes searched for a sync pattern
+const MAX_PAD: usize = 512;
+// verification constant, eight zero bytes
+const VC: [u8; 8] = [0; 8];
+
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+// what a plaintext connection starts with
+const PLAIN_HEADER: &[u8; 20] = b"\x13BitTorrent protocol";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encryption {
+    // plaintext connections only
+    Disabled,
+    // encrypt outgoing connections, falling back to plaintext, and accept both
+    #[default]
+    Prefer,
+    // encrypted connections only
+    Require,
+}
+
+impl FromStr for Encryption {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "disabled" => Ok(Encryption::Disabled),
+            "prefer" => Ok(Encryption::Prefer),
+            "require" => Ok(Encryption::Require),
+            _ => anyhow::bail!("Invalid encryption {s:?}, expected disabled, prefer or require"),
+        }
+    }
+}
+
+impl fmt::Display for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Encryption::Disabled => "disabled",
+            Encryption::Prefer => "prefer",
+            Encryption::Require => "require",
+        })
+    }
+}
+
+// Any reliable byte stream a peer connection runs over, TCP or uTP.
+pub trait ByteStream: AsyncRead + AsyncWrite + Unpin + Send + Sync + fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + Sync + fmt::Debug> ByteStream for T {}
+
+// The connection to a peer, RC4 encrypted or not.
+#[derive(Debug)]
+pub struct PeerStream {
+    inner: Box<dyn ByteStream>,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    // plaintext received during the handshake, read before the rest of the stream
+    buffered: BytesMut,
+}
+
+impl PeerStream {
+    pub fn plain(inner: Box<dyn ByteStream>) -> Self {
+        PeerStream {
+            inner,
+            read_cipher: None,
+            write_cipher: None,
+            buffered: BytesMut::new(),
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.write_cipher.is_some()
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        if !this.buffered.is_empty() {
+            let n = this.buffered.len().min(buf.remaining());
+            buf.put_slice(&this.buffered[..n]);
+            this.buffered.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(cipher)) = (&result, &mut this.read_cipher) {
+            cipher.apply(&mut buf.filled_mut()[filled..]);
+        }
+        result
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let Some(cipher) = &mut this.write_cipher else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        // the key stream only moves on by what the socket took
+        let mut encrypted = buf.to_vec();
+        cipher.clone().apply(&mut encrypted);
+        let result = Pin::new(&mut this.inner).poll_write(cx, &encrypted);
+        if let Poll::Ready(Ok(n)) = result {
+            cipher.skip(n);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// Opens an outgoing connection with the policy, `info_hash` is the swarm we connect for.
+pub async fn connect(
+    addr: SocketAddr,
+    transport: &Transport,
+    info_hash: [u8; 20],
+    encryption: Encryption,
+) -> anyhow::Result<PeerStream> {
+    match encryption {
+        Encryption::Disabled => Ok(PeerStream::plain(transport.dial(addr).await?)),
+        Encryption::Require => initiate(transport.dial(addr).await?, info_hash, false).await,
+        Encryption::Prefer => match initiate(transport.dial(addr).await?, info_hash, true).await {
+            Ok(stream) => Ok(stream),
+            // the peer doesn't speak it, most likely
+            Err(_) => Ok(PeerStream::plain(transport.dial(addr).await?)),
+        },
+    }
+}
+
+// Runs the initiating side of the encryption handshake.
+async fn initiate(
+    mut stream: Box<dyn ByteStream>,
+    info_hash: [u8; 20],
+    allow_plain: bool,
+) -> anyhow::Result<PeerStream> {
+    let (private, public) = key_pair()?;
+    let mut out = public.to_vec();
+    out.extend(random_pad()?);
+    stream.write_all(&out).await?;
+
+    let mut remote = [0; KEY_LEN];
+    stream.read_exact(&mut remote).await?;
+    let secret = shared_secret(&private, &remote);
+
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let provide = if allow_plain {
+        CRYPTO_RC4 | CRYPTO_PLAINTEXT
+    } else {
+        CRYPTO_RC4
+    };
+    let mut out = hash(&[b"req1", &secret]).to_vec();
+    out.extend(xor(
+        &hash(&[b"req2", &info_hash]),
+        &hash(&[b"req3", &secret]),
+    ));
+    let mut negotiation = VC.to_vec();
+    negotiation.extend(provide.to_be_bytes());
+    // no padding and no initial payload, the BitTorrent handshake follows
+    negotiation.extend(0u16.to_be_bytes());
+    negotiation.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut negotiation);
+    out.extend(negotiation);
+    stream.write_all(&out).await?;
+
+    // the answer starts with the encrypted verification constant, after the peer's padding
+    let mut vc = VC;
+    decrypt.clone().apply(&mut vc);
+    sync(&mut stream, &vc).await?;
+    decrypt.skip(VC.len());
+
+    let mut answer = [0; 6];
+    stream.read_exact(&mut answer).await?;
+    decrypt.apply(&mut answer);
+    let select = u32::from_be_bytes(answer[..4].try_into().unwrap());
+    let pad_len = u16::from_be_bytes([answer[4], answer[5]]) as usize;
+    anyhow::ensure!(pad_len <= MAX_PAD, "Invalid encryption padding");
+    let mut pad = vec![0; pad_len];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+
+    if select == CRYPTO_RC4 {
+        Ok(PeerStream {
+            inner: stream,
+            read_cipher: Some(decrypt),
+            write_cipher: Some(encrypt),
+            buffered: BytesMut::new(),
+        })
+    } else if select == CRYPTO_PLAINTEXT && allow_plain {
+        Ok(PeerStream::plain(stream))
+    } else {
+        Err(anyhow!("Peer selected unsupported encryption {select:#x}"))
+    }
+}
+
+// Answers an incoming connection, encrypted or not as the policy allows. `info_hashes` are
+// the swarms we serve, one of them must be the obfuscated one of an encrypted handshake.
+pub async fn accept(
+    mut stream: Box<dyn ByteStream>,
+    encryption: Encryption,
+    info_hashes: &[[u8; 20]],
+) -> anyhow::Result<PeerStream> {
+    let mut header = [0; PLAIN_HEADER.len()];
+    stream.read_exact(&mut header).await?;
+    if &header == PLAIN_HEADER {
+        anyhow::ensure!(
+            encryption != Encryption::Require,
+            "Plaintext connection refused"
+        );
+        let mut plain = PeerStream::plain(stream);
+        plain.buffered.extend_from_slice(&header);
+        return Ok(plain);
+    }
+    anyhow::ensure!(
+        encryption != Encryption::Disabled,
+        "Encrypted connection refused"
+    );
+
+    let mut remote = [0; KEY_LEN];
+    remote[..header.len()].copy_from_slice(&header);
+    stream.read_exact(&mut remote[header.len()..]).await?;
+    let (private, public) = key_pair()?;
+    let mut out = public.to_vec();
+    out.extend(random_pad()?);
+    stream.write_all(&out).await?;
+    let secret = shared_secret(&private, &remote);
+
+    sync(&mut stream, &hash(&[b"req1", &secret])).await?;
+    let mut obfuscated = [0; 20];
+    stream.read_exact(&mut obfuscated).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = info_hashes
+        .iter()
+        .find(|h| xor(&hash(&[b"req2", *h]), &req3) == obfuscated)
+        .ok_or_else(|| anyhow!("Encrypted connection for an unknown torrent"))?;
+
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    let mut negotiation = [0; 14];
+    stream.read_exact(&mut negotiation).await?;
+    decrypt.apply(&mut negotiation);
+    anyhow::ensure!(negotiation[..8] == VC, "Invalid verification constant");
+    let provide = u32::from_be_bytes(negotiation[8..12].try_into().unwrap());
+    let pad_len = u16::from_be_bytes([negotiation[12], negotiation[13]]) as usize;
+    anyhow::ensure!(pad_len <= MAX_PAD, "Invalid encryption padding");
+    let mut pad = vec![0; pad_len + 2];
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+    let initial_len = u16::from_be_bytes([pad[pad_len], pad[pad_len + 1]]) as usize;
+    let mut initial = vec![0; initial_len];
+    stream.read_exact(&mut initial).await?;
+    decrypt.apply(&mut initial);
+
+    let select = if provide & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if provide & CRYPTO_PLAINTEXT != 0 && encryption == Encryption::Prefer {
+        CRYPTO_PLAINTEXT
+    } else {
+        anyhow::bail!("No common encryption, peer provides {provide:#x}");
+    };
+    let mut answer = VC.to_vec();
+    answer.extend(select.to_be_bytes());
+    answer.extend(0u16.to_be_bytes());
+    encrypt.apply(&mut answer);
+    stream.write_all(&answer).await?;
+
+    let mut peer = if select == CRYPTO_RC4 {
+        PeerStream {
+            inner: stream,
+            read_cipher: Some(decrypt),
+            write_cipher: Some(encrypt),
+            buffered: BytesMut::new(),
+        }
+    } else {
+        PeerStream::plain(stream)
+    };
+    // the initial payload is usually the BitTorrent handshake
+    peer.buffered.extend_from_slice(&initial);
+    Ok(peer)
+}
+
+// Reads up to the end of `pattern`, which must come within the padding.
+async fn sync(stream: &mut Box<dyn ByteStream>, pattern: &[u8]) -> anyhow::Result<()> {
+    let mut window = Vec::with_capacity(MAX_PAD + pattern.len());
+    while window.len() < MAX_PAD + pattern.len() {
+        window.push(stream.read_u8().await?);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    Err(anyhow!("Encryption handshake out of sync"))
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn xor(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    std::array::from_fn(|i| a[i] ^ b[i])
+}
+
+fn random_bytes(len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![0; len];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .context("Reading random bytes")?;
+    Ok(bytes)
+}
+
+fn random_pad() -> anyhow::Result<Vec<u8>> {
+    let len = u16::from_be_bytes(random_bytes(2)?.try_into().unwrap()) as usize % (MAX_PAD + 1);
+    random_bytes(len)
+}
+
+fn key_pair() -> anyhow::Result<(Vec<u8>, [u8; KEY_LEN])> {
+    let private = random_bytes(PRIVATE_KEY_LEN)?;
+    let public = pow_mod(&[2], &private).to_bytes();
+    Ok((private, public))
+}
+
+fn shared_secret(private: &[u8], remote: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    pow_mod(remote, private).to_bytes()
+}
+
+// `base ^ exp mod PRIME`, both big endian
+fn pow_mod(base: &[u8], exp: &[u8]) -> Uint {
+    let prime = Uint::prime();
+    let mut base = Uint::from_bytes(base);
+    if !base.lt(&prime) {
+        base.sub(&prime);
+    }
+    let mut result = Uint::from_bytes(&[1]);
+    for byte in exp {
+        for bit in (0..8).rev() {
+            result = result.mul_mod(&result, &prime);
+            if byte >> bit & 1 == 1 {
+                result = result.mul_mod(&base, &prime);
+            }
+        }
+    }
+    result
+}
+
+const LIMBS: usize = KEY_LEN / 8;
+
+// Just enough unsigned 768 bit arithmetic for the key exchange, little endian limbs.
+#[derive(Debug, Clone, Copy)]
+struct Uint([u64; LIMBS]);
+
+impl Uint {
+    fn prime() -> Self {
+        Uint::from_bytes(&hex::decode(PRIME).unwrap())
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let mut limbs = [0; LIMBS];
+        for (i, &byte) in bytes.iter().rev().enumerate().take(KEY_LEN) {
+            limbs[i / 8] |= (byte as u64) << (i % 8 * 8);
+        }
+        Uint(limbs)
+    }
+
+    fn to_bytes(self) -> [u8; KEY_LEN] {
+        let mut bytes = [0; KEY_LEN];
+        for (i, byte) in bytes.iter_mut().rev().enumerate() {
+            *byte = (self.0[i / 8] >> (i % 8 * 8)) as u8;
+        }
+        bytes
+    }
+
+    fn lt(&self, other: &Uint) -> bool {
+        self.0.iter().rev().lt(other.0.iter().rev())
+    }
+
+    fn bit(&self, i: usize) -> bool {
+        self.0[i / 64] >> (i % 64) & 1 == 1
+    }
+
+    // returns the carry
+    fn add(&mut self, other: &Uint) -> bool {
+        let mut carry = false;
+        for (a, &b) in self.0.iter_mut().zip(&other.0) {
+            let (sum, c1) = a.overflowing_add(b);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            *a = sum;
+            carry = c1 || c2;
+        }
+        carry
+    }
+
+    fn sub(&mut self, other: &Uint) {
+        let mut borrow = false;
+        for (a, &b) in self.0.iter_mut().zip(&other.0) {
+            let (diff, b1) = a.overflowing_sub(b);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            *a = diff;
+            borrow = b1 || b2;
+        }
+    }
+
+    // both below `modulus`
+    fn add_mod(&mut self, other: &Uint, modulus: &Uint) {
+        if self.add(other) || !self.lt(modulus) {
+            self.sub(modulus);
+        }
+    }
+
+    fn mul_mod(&self, other: &Uint, modulus: &Uint) -> Uint {
+        let mut result = Uint([0; LIMBS]);
+        for i in (0..LIMBS * 64).rev() {
+            let doubled = result;
+            result.add_mod(&doubled, modulus);
+            if self.bit(i) {
+                result.add_mod(other, modulus);
+            }
+        }
+        result
+    }
+}
+
+// RC4 with the first 1024 bytes of key stream dropped, as the handshake wants.
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Rc4")
+    }
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state: [u8; 256] = std::array::from_fn(|i| i as u8);
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        let mut rc4 = Rc4 { state, i: 0, j: 0 };
+        rc4.skip(1024);
+        rc4
+    }
+
+    fn next(&mut self) -> u8 {
+        self.i = self.i.wrapping_add(1);
+        self.j = self.j.wrapping_add(self.state[self.i as usize]);
+        self.state.swap(self.i as usize, self.j as usize);
+        let k = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+        self.state[k as usize]
+    }
+
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            *byte ^= self.next();
+        }
+    }
+
+    fn skip(&mut self, n: usize) {
+        for _ in 0..n {
+            self.next();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    const INFO_HASH: [u8; 20] = [1; 20];
+
+    #[test]
+    fn rc4_drops_1024_bytes() {
+        // RFC 6229, 40 bit key, key stream at offset 1024
+        let mut rc4 = Rc4::new(&[1, 2, 3, 4, 5]);
+        let mut stream = [0; 16];
+        rc4.apply(&mut stream);
+        assert_eq!(hex::encode(stream), "30abbcc7c20b01609f23ee2d5f6bb7df");
+    }
+
+    #[test]
+    fn key_exchange() {
+        let a: Vec<u8> = (1..=20).collect();
+        let b: Vec<u8> = (21..=40).collect();
+        let public_a = pow_mod(&[2], &a).to_bytes();
+        let public_b = pow_mod(&[2], &b).to_bytes();
+        assert_eq!(
+            hex::encode(public_a),
+            "96e112dab29e8c5272accb9b17b26887ce54a144a4e3b697c7d159b7a817e556\
+             b0918db2b4c658e02a87f7e5fb14b18a553e084cbf3dad2d30f16596ccb982d4\
+             06258c61b30c5c1dae2ddc60bdbd48d79896312aad63238c39e1a633821eb693"
+        );
+        let secret = shared_secret(&a, &public_b);
+        assert_eq!(secret, shared_secret(&b, &public_a));
+        assert_eq!(
+            hex::encode(secret),
+            "994aac6c359990cf4f678a1742b587eb1a5248ec7fcc0d0bcfcb12d2461bc1fe\
+             25417b70869697d9ca884832f1c5f2a2fd3318c22a5a6ba170d36aac91405457\
+             c1e8137b1534a776865ed353f12422ff6afc58435f8bd443f61dd051a37bcdeb"
+        );
+    }
+
+    // Connects with one policy to a peer accepting with another, serving three torrents, and
+    // exchanges a little data. Whether the connection is encrypted, `None` when it fails.
+    async fn handshake(
+        initiator: Encryption,
+        acceptor: Encryption,
+        info_hash: [u8; 20],
+    ) -> Option<bool> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let info_hashes = [[2; 20], INFO_HASH, [3; 20]];
+                let result = accept(Box::new(stream), acceptor, &info_hashes).await;
+                if tx.send(result).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut client = connect(addr, &Transport::Tcp, info_hash, initiator)
+            .await
+            .ok()?;
+        client.write_all(PLAIN_HEADER).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        // the refused encrypted attempt before the plaintext one
+        let attempts = if initiator == Encryption::Prefer && !client.is_encrypted() {
+            2
+        } else {
+            1
+        };
+        let mut result = None;
+        for _ in 0..attempts {
+            result = rx.recv().await;
+        }
+        let mut server = result.unwrap().ok()?;
+
+        let mut received = [0; PLAIN_HEADER.len() + 4];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received[..PLAIN_HEADER.len()], PLAIN_HEADER[..]);
+        assert_eq!(&received[PLAIN_HEADER.len()..], b"ping");
+        server.write_all(b"pong").await.unwrap();
+        let mut received = [0; 4];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"pong");
+
+        assert_eq!(client.is_encrypted(), server.is_encrypted());
+        Some(client.is_encrypted())
+    }
+
+    #[tokio::test]
+    async fn policies() {
+        use Encryption::*;
+        let expected = [
+            (Disabled, Disabled, Some(false)),
+            (Disabled, Prefer, Some(false)),
+            (Disabled, Require, None),
+            // falls back to plaintext
+            (Prefer, Disabled, Some(false)),
+            (Prefer, Prefer, Some(true)),
+            (Prefer, Require, Some(true)),
+            (Require, Disabled, None),
+            (Require, Prefer, Some(true)),
+            (Require, Require, Some(true)),
+        ];
+        for (initiator, acceptor, encrypted) in expected {
+            assert_eq!(
+                handshake(initiator, acceptor, INFO_HASH).await,
+                encrypted,
+                "{initiator} to {acceptor}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_info_hash() {
+        let encrypted = handshake(Encryption::Require, Encryption::Require, [9; 20]).await;
+        assert_eq!(encrypted, None);
+    }
+}
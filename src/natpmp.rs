@@ -0,0 +1,283 @@
+use anyhow::{anyhow, bail, Context};
+use tokio::net::UdpSocket;
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::{Duration, SystemTime};
+
+use crate::net;
+use crate::port_map::Protocol;
+
+// NAT-PMP (RFC 6886) and its successor PCP (RFC 6887), which routers answer on the same port.
+pub const PORT: u16 = 5351;
+
+const NATPMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const PCP_MAP: u8 = 1;
+const PCP_RESPONSE: u8 = 0x80;
+// what a NAT-PMP router answers to a PCP request
+const UNSUPPORTED_VERSION: u16 = 1;
+
+// the first wait for an answer, doubled for every retry
+const INITIAL_TIMEOUT: Duration = Duration::from_millis(250);
+const RETRIES: u32 = 4;
+
+// A port the router forwards to us.
+#[derive(Debug, Clone, Copy)]
+pub struct Mapped {
+    pub external_port: u16,
+    pub lifetime: Duration,
+}
+
+// A router speaking PCP, or only NAT-PMP.
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    addr: SocketAddr,
+    pcp: bool,
+    // PCP mappings are renewed and deleted with the nonce they were made with
+    nonce: [u8; 12],
+}
+
+impl Gateway {
+    pub fn new(addr: SocketAddr) -> Self {
+        let random = || RandomState::new().hash_one(SystemTime::now()).to_be_bytes();
+        let mut nonce = [0; 12];
+        nonce[..8].copy_from_slice(&random());
+        nonce[8..].copy_from_slice(&random()[..4]);
+        Gateway {
+            addr,
+            pcp: true,
+            nonce,
+        }
+    }
+
+    // Asks for `external_port` to be forwarded to `internal_port` for `lifetime`, the router
+    // may pick another external port. A lifetime of zero deletes the mapping.
+    pub async fn map(
+        &mut self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> anyhow::Result<Mapped> {
+        if self.pcp {
+            match self
+                .map_pcp(protocol, internal_port, external_port, lifetime)
+                .await?
+            {
+                Some(mapped) => return Ok(mapped),
+                None => self.pcp = false,
+            }
+        }
+        self.map_natpmp(protocol, internal_port, external_port, lifetime)
+            .await
+    }
+
+    // `None` when the router only speaks NAT-PMP
+    async fn map_pcp(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> anyhow::Result<Option<Mapped>> {
+        let client = match net::local_ip_for(self.addr.ip()) {
+            Some(IpAddr::V4(ip)) => ip.to_ipv6_mapped(),
+            Some(IpAddr::V6(ip)) => ip,
+            None => bail!("No route to {}", self.addr),
+        };
+        let mut request = vec![PCP_VERSION, PCP_MAP, 0, 0];
+        request.extend((lifetime.as_secs() as u32).to_be_bytes());
+        request.extend(client.octets());
+        request.extend(self.nonce);
+        request.extend([protocol.number(), 0, 0, 0]);
+        request.extend(internal_port.to_be_bytes());
+        request.extend(external_port.to_be_bytes());
+        // any external address
+        request.extend(Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+
+        let response = self
+            .request(&request, |r| {
+                // NAT-PMP routers answer in their own format
+                r.len() >= 4 && (r[0] == NATPMP_VERSION || r[1] == PCP_RESPONSE | PCP_MAP)
+            })
+            .await?;
+        if response[0] == NATPMP_VERSION {
+            return Ok(None);
+        }
+        match response[3] as u16 {
+            0 => {}
+            UNSUPPORTED_VERSION => return Ok(None),
+            code => bail!("PCP error {code}"),
+        }
+        if response.len() < 60 || response[24..36] != self.nonce {
+            bail!("Malformed PCP response");
+        }
+        Ok(Some(Mapped {
+            external_port: u16::from_be_bytes([response[42], response[43]]),
+            lifetime: Duration::from_secs(u32_at(&response, 4) as u64),
+        }))
+    }
+
+    async fn map_natpmp(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lifetime: Duration,
+    ) -> anyhow::Result<Mapped> {
+        let opcode = match protocol {
+            Protocol::Udp => 1,
+            Protocol::Tcp => 2,
+        };
+        let mut request = vec![NATPMP_VERSION, opcode, 0, 0];
+        request.extend(internal_port.to_be_bytes());
+        request.extend(external_port.to_be_bytes());
+        request.extend((lifetime.as_secs() as u32).to_be_bytes());
+
+        let response = self
+            .request(&request, |r| {
+                r.len() >= 16 && r[0] == NATPMP_VERSION && r[1] == 128 + opcode
+            })
+            .await?;
+        match u16::from_be_bytes([response[2], response[3]]) {
+            0 => Ok(Mapped {
+                external_port: u16::from_be_bytes([response[10], response[11]]),
+                lifetime: Duration::from_secs(u32_at(&response, 12) as u64),
+            }),
+            code => bail!("NAT-PMP error {code}"),
+        }
+    }
+
+    // Sends the request until an answer it takes comes back.
+    async fn request(
+        &self,
+        request: &[u8],
+        is_response: impl Fn(&[u8]) -> bool,
+    ) -> anyhow::Result<Vec<u8>> {
+        let bind: SocketAddr = match self.addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(bind).await?;
+        socket.connect(self.addr).await?;
+        let mut timeout = INITIAL_TIMEOUT;
+        let mut buf = [0; 1100];
+        for _ in 0..RETRIES {
+            socket.send(request).await?;
+            let deadline = tokio::time::Instant::now() + timeout;
+            while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await
+            {
+                // ICMP port unreachable, nothing listens there
+                let len = received.with_context(|| format!("No port mapping on {}", self.addr))?;
+                if is_response(&buf[..len]) {
+                    return Ok(buf[..len].to_vec());
+                }
+            }
+            timeout *= 2;
+        }
+        Err(anyhow!("No answer from {}", self.addr))
+    }
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A router on loopback answering one request after another with `answer`, which gets
+    // what was asked. Returns the requests once done.
+    async fn router(
+        answer: impl Fn(&[u8]) -> Vec<u8> + Send + 'static,
+        count: usize,
+    ) -> (SocketAddr, tokio::task::JoinHandle<Vec<Vec<u8>>>) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let task = tokio::spawn(async move {
+            let mut requests = vec![];
+            let mut buf = [0; 1100];
+            for _ in 0..count {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                socket.send_to(&answer(&buf[..len]), from).await.unwrap();
+                requests.push(buf[..len].to_vec());
+            }
+            requests
+        });
+        (addr, task)
+    }
+
+    #[tokio::test]
+    async fn natpmp() {
+        let (addr, requests) = router(
+            |request| match request[0] {
+                // unsupported version, with the newest NAT-PMP one
+                PCP_VERSION => vec![NATPMP_VERSION, 128 + request[1], 0, 1, 0, 0, 0, 0],
+                _ => {
+                    let mut response = vec![NATPMP_VERSION, 128 + request[1], 0, 0];
+                    response.extend(7u32.to_be_bytes());
+                    response.extend(&request[4..6]);
+                    response.extend(40000u16.to_be_bytes());
+                    response.extend(1800u32.to_be_bytes());
+                    response
+                }
+            },
+            2,
+        )
+        .await;
+
+        let mut gateway = Gateway::new(addr);
+        let mapped = gateway
+            .map(Protocol::Tcp, 6881, 6881, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(mapped.external_port, 40000);
+        assert_eq!(mapped.lifetime, Duration::from_secs(1800));
+        assert!(!gateway.pcp);
+
+        let requests = requests.await.unwrap();
+        assert_eq!(requests[0][..2], [PCP_VERSION, PCP_MAP]);
+        // TCP, internal and external port, lifetime
+        assert_eq!(
+            requests[1],
+            [0, 2, 0, 0, 0x1a, 0xe1, 0x1a, 0xe1, 0, 0, 0x0e, 0x10]
+        );
+    }
+
+    #[tokio::test]
+    async fn pcp() {
+        let (addr, requests) = router(
+            |request| {
+                let mut response = vec![PCP_VERSION, PCP_RESPONSE | PCP_MAP, 0, 0];
+                response.extend(600u32.to_be_bytes());
+                response.extend([0; 16]);
+                // nonce, protocol, internal port
+                response.extend(&request[24..42]);
+                response.extend(40000u16.to_be_bytes());
+                response.extend(Ipv4Addr::new(203, 0, 113, 1).to_ipv6_mapped().octets());
+                response
+            },
+            1,
+        )
+        .await;
+
+        let mut gateway = Gateway::new(addr);
+        let mapped = gateway
+            .map(Protocol::Udp, 6881, 6881, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(mapped.external_port, 40000);
+        assert_eq!(mapped.lifetime, Duration::from_secs(600));
+
+        let request = &requests.await.unwrap()[0];
+        assert_eq!(request.len(), 60);
+        assert_eq!(request[..4], [PCP_VERSION, PCP_MAP, 0, 0]);
+        assert_eq!(u32_at(request, 4), 3600);
+        assert_eq!(request[24..36], gateway.nonce);
+        assert_eq!(request[36], Protocol::Udp.number());
+        assert_eq!(request[40..44], [0x1a, 0xe1, 0x1a, 0xe1]);
+    }
+}
@@ -0,0 +1,81 @@
+use tokio::net::TcpListener;
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+// An IPv4 peer seen by a dual-stack socket has a mapped IPv6 address, this gives the IPv4 one
+// back so that it is listed once.
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+// Where to send a datagram from a socket bound to `local`: a dual-stack socket takes IPv4
+// addresses mapped.
+pub fn to_family(addr: SocketAddr, local: SocketAddr) -> SocketAddr {
+    match (addr, local) {
+        (SocketAddr::V4(v4), SocketAddr::V6(_)) => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        _ => addr,
+    }
+}
+
+// Listens on `addr`, on both families when it is the unspecified IPv4 address and the host
+// has IPv6. Returns whether IPv6 peers can reach us.
+pub async fn bind_dual_stack(addr: SocketAddr) -> io::Result<(Vec<TcpListener>, bool)> {
+    if addr.ip() != Ipv4Addr::UNSPECIFIED {
+        let listener = TcpListener::bind(addr).await?;
+        return Ok((vec![listener], addr.is_ipv6()));
+    }
+    let Ok(v6) = TcpListener::bind((Ipv6Addr::UNSPECIFIED, addr.port())).await else {
+        return Ok((vec![TcpListener::bind(addr).await?], false));
+    };
+    // taken by the IPv6 socket already unless the host keeps the families apart
+    let port = v6.local_addr()?.port();
+    match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+        Ok(v4) => Ok((vec![v6, v4], true)),
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => Ok((vec![v6], true)),
+        Err(e) => Err(e),
+    }
+}
+
+// Our global IPv6 address, told to trackers.
+pub fn local_ipv6() -> Option<Ipv6Addr> {
+    match local_ip_for("2001:4860:4860::8888".parse().unwrap())? {
+        IpAddr::V6(ip)
+            if !ip.is_loopback() && !ip.is_unspecified() && !ip.is_unicast_link_local() =>
+        {
+            Some(ip)
+        }
+        _ => None,
+    }
+}
+
+// The router of the default route, read from the kernel's routing table.
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+    routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        // in the byte order of the host
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes())).filter(|ip| !ip.is_unspecified())
+    })
+}
+
+// The address the host uses to reach `remote`. Nothing is sent, connecting a UDP socket only
+// picks the route.
+pub fn local_ip_for(remote: IpAddr) -> Option<IpAddr> {
+    let socket = UdpSocket::bind(SocketAddr::new(
+        match remote {
+            IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        },
+        0,
+    ))
+    .ok()?;
+    socket.connect((remote, 9)).ok()?;
+    Some(socket.local_addr().ok()?.ip())
+}
@@ -1,19 +1,31 @@
-use anyhow::{anyhow, Context};
+use anyhow::anyhow;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tracing::{debug, instrument, trace};
+
+use serde::{Deserialize, Serialize};
 use sha1::{Digest, Sha1};
 
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 
-use tokio::net::TcpStream;
-
+use crate::bitfield::Bitfield;
+use crate::holepunch::Holepunch;
+use crate::merkle;
+use crate::metadata::{self, Metadata};
+use crate::mse::{self, ByteStream, Encryption, PeerStream};
+use crate::piece::PieceCheck;
+use crate::ratelimit::Throttle;
 use crate::torrent::TorrentFile;
+use crate::utp::Transport;
 
 #[derive(Debug)]
 struct Handshake {
+    pub reserved: [u8; 8],
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
@@ -21,11 +33,41 @@ struct Handshake {
 const BITTORRENT: &[u8; 19] = b"BitTorrent protocol";
 const HANDSHAKE_LEN: usize = 1 + BITTORRENT.len() + 8 + 20 + 20;
 
+// BEP 52 support bit, in the last reserved byte
+const RESERVED_V2: u8 = 0x10;
+// BEP 10 extension protocol bit, in the sixth reserved byte
+const RESERVED_EXTENSIONS: u8 = 0x10;
+
+// the extended message id of the extension handshake
+const EXTENSION_HANDSHAKE: u8 = 0;
+// the extended message id peers send us ut_holepunch messages with
+const UT_HOLEPUNCH: u8 = 1;
+// and ut_metadata ones, only announced while fetching the metadata
+const UT_METADATA: u8 = 2;
+
+// most hashes a peer answers in one hash request
+const MAX_HASHES: usize = 512;
+
+// largest message we accept, big enough for the bitfield of any sane torrent
+const MAX_MESSAGE_LEN: usize = 2 * 1024 * 1024;
+
+// largest block we upload
+const MAX_BLOCK_LEN: usize = 128 * 1024;
+
+// What we can upload to peers.
+pub trait BlockSource: Send + Sync {
+    fn bitfield(&self) -> Bitfield;
+
+    // `None` while we don't have the piece
+    fn read_block(&self, index: usize, begin: usize, length: usize) -> Option<Bytes>;
+}
+
 impl Handshake {
-    fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+    fn new(info_hash: [u8; 20], peer_id: [u8; 20], reserved: [u8; 8]) -> Self {
         Handshake {
-            info_hash: info_hash,
-            peer_id: peer_id,
+            reserved,
+            info_hash,
+            peer_id,
         }
     }
 
@@ -34,7 +76,7 @@ impl Handshake {
 
         buf.put_u8(BITTORRENT.len() as u8);
         buf.put_slice(BITTORRENT);
-        buf.put_bytes(0, 8); // reserved
+        buf.put_slice(&self.reserved);
         buf.put_slice(&self.info_hash);
         buf.put_slice(&self.peer_id);
 
@@ -43,9 +85,13 @@ impl Handshake {
 
     fn from_bytes(mut buf: &[u8]) -> anyhow::Result<Self> {
         let len = buf[0] as usize;
-        buf.advance(1 + len + 8);
+        anyhow::ensure!(len == BITTORRENT.len(), "Invalid handshake");
+        buf.advance(1 + len);
+        let reserved = buf[..8].try_into()?;
+        buf.advance(8);
 
         Ok(Handshake {
+            reserved,
             info_hash: buf[..20].try_into()?,
             peer_id: buf[20..].try_into()?,
         })
@@ -71,9 +117,48 @@ enum MessageType {
     Request,
     Piece,
     Cancel,
+    Extended = 20,
+    HashRequest = 21,
+    Hashes = 22,
+    HashReject = 23,
     Ping = 255,
 }
 
+// BEP 52 request for `length` hashes of a layer of the merkle tree of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HashRequest {
+    pub pieces_root: merkle::Hash,
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashRequest {
+    const LEN: usize = 32 + 4 * 4;
+
+    fn put(&self, buf: &mut BytesMut) {
+        buf.put_slice(&self.pieces_root);
+        buf.put_u32(self.base_layer);
+        buf.put_u32(self.index);
+        buf.put_u32(self.length);
+        buf.put_u32(self.proof_layers);
+    }
+
+    fn get(buf: &mut &[u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(buf.len() >= Self::LEN, "Short hash request");
+        let pieces_root = buf[..32].try_into()?;
+        buf.advance(32);
+        Ok(HashRequest {
+            pieces_root,
+            base_layer: buf.get_u32(),
+            index: buf.get_u32(),
+            length: buf.get_u32(),
+            proof_layers: buf.get_u32(),
+        })
+    }
+}
+
 #[derive(Debug)]
 enum MessagePayload<'a> {
     None,
@@ -89,6 +174,29 @@ enum MessagePayload<'a> {
         begin: u32,
         piece: &'a [u8],
     },
+    HashRequest(HashRequest),
+    Hashes {
+        request: HashRequest,
+        hashes: &'a [u8],
+    },
+    Extended {
+        id: u8,
+        payload: &'a [u8],
+    },
+}
+
+// BEP 10 extension handshake, only the extensions are of interest
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ExtensionHandshake {
+    // extension names to the extended message ids to send them with, zero when disabled
+    #[serde(default)]
+    m: BTreeMap<String, i64>,
+    // the port the peer listens on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    p: Option<i64>,
+    // length of the info dictionary, for ut_metadata
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata_size: Option<i64>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -106,7 +214,7 @@ impl<'a> Message<'a> {
     fn status(kind: MessageType) -> Self {
         Message {
             length: 1,
-            kind: kind,
+            kind,
             payload: MessagePayload::None,
         }
     }
@@ -123,6 +231,50 @@ impl<'a> Message<'a> {
         }
     }
 
+    fn have(piece_id: usize) -> Self {
+        Message {
+            length: 1 + 4,
+            kind: MessageType::Have,
+            payload: MessagePayload::Have(piece_id as u32),
+        }
+    }
+
+    fn bitfield(bits: &'a [u8]) -> Self {
+        Message {
+            length: 1 + bits.len(),
+            kind: MessageType::Bitfield,
+            payload: MessagePayload::Bitfield(bits),
+        }
+    }
+
+    fn piece(piece_id: usize, begin: usize, block: &'a [u8]) -> Self {
+        Message {
+            length: 1 + 4 * 2 + block.len(),
+            kind: MessageType::Piece,
+            payload: MessagePayload::Piece {
+                index: piece_id as u32,
+                begin: begin as u32,
+                piece: block,
+            },
+        }
+    }
+
+    fn hash_request(kind: MessageType, request: HashRequest) -> Self {
+        Message {
+            length: 1 + HashRequest::LEN,
+            kind,
+            payload: MessagePayload::HashRequest(request),
+        }
+    }
+
+    fn extended(id: u8, payload: &'a [u8]) -> Self {
+        Message {
+            length: 1 + 1 + payload.len(),
+            kind: MessageType::Extended,
+            payload: MessagePayload::Extended { id, payload },
+        }
+    }
+
     fn to_bytes(&self) -> Bytes {
         let mut buf = BytesMut::with_capacity(self.length);
         buf.put_u32(self.length as u32);
@@ -149,12 +301,28 @@ impl<'a> Message<'a> {
                 buf.put_u32(*begin);
                 buf.put_slice(piece)
             }
+            MessagePayload::HashRequest(request) => request.put(&mut buf),
+            MessagePayload::Hashes { request, hashes } => {
+                request.put(&mut buf);
+                buf.put_slice(hashes)
+            }
+            MessagePayload::Extended { id, payload } => {
+                buf.put_u8(*id);
+                buf.put_slice(payload)
+            }
         }
 
         buf.freeze()
     }
 
     fn from_bytes(mut buf: &'a [u8]) -> anyhow::Result<Self> {
+        if buf.len() == 4 {
+            return Ok(Message {
+                length: 0,
+                kind: MessageType::Ping,
+                payload: MessagePayload::None,
+            });
+        }
         anyhow::ensure!(buf.len() >= 5);
 
         let len = buf.get_u32() as usize;
@@ -165,8 +333,11 @@ impl<'a> Message<'a> {
             1 => (MessageType::Unchoke, MessagePayload::None),
             2 => (MessageType::Interested, MessagePayload::None),
             3 => (MessageType::NotInterested, MessagePayload::None),
-            4 => (MessageType::Have, MessagePayload::Have(buf.get_u32())),
-            5 => (MessageType::Bitfield, MessagePayload::Bitfield(&buf[..])),
+            4 => {
+                anyhow::ensure!(buf.len() == 4);
+                (MessageType::Have, MessagePayload::Have(buf.get_u32()))
+            }
+            5 => (MessageType::Bitfield, MessagePayload::Bitfield(buf)),
             6 => {
                 anyhow::ensure!(buf.len() == 4 * 3);
                 (
@@ -185,7 +356,7 @@ impl<'a> Message<'a> {
                     MessagePayload::Piece {
                         index: buf.get_u32(),
                         begin: buf.get_u32(),
-                        piece: &buf[..],
+                        piece: buf,
                     },
                 )
             }
@@ -200,13 +371,43 @@ impl<'a> Message<'a> {
                     },
                 )
             }
+            20 => {
+                anyhow::ensure!(!buf.is_empty());
+                (
+                    MessageType::Extended,
+                    MessagePayload::Extended {
+                        id: buf.get_u8(),
+                        payload: buf,
+                    },
+                )
+            }
+            21 => (
+                MessageType::HashRequest,
+                MessagePayload::HashRequest(HashRequest::get(&mut buf)?),
+            ),
+            22 => {
+                let request = HashRequest::get(&mut buf)?;
+                anyhow::ensure!(buf.len().is_multiple_of(32));
+                (
+                    MessageType::Hashes,
+                    MessagePayload::Hashes {
+                        request,
+                        hashes: buf,
+                    },
+                )
+            }
+            23 => (
+                MessageType::HashReject,
+                MessagePayload::HashRequest(HashRequest::get(&mut buf)?),
+            ),
             _ => anyhow::bail!("Invalid message type"),
         };
 
+        trace!(?kind, len, "Received");
         Ok(Message {
             length: len,
-            kind: kind,
-            payload: payload,
+            kind,
+            payload,
         })
     }
 }
@@ -215,66 +416,178 @@ pub struct Peer {
     pub remote_addr: SocketAddr,
     pub local_id: [u8; 20],
     pub remote_id: [u8; 20],
+    pub info_hash: [u8; 20],
     pub pieces_bitfield: Vec<u8>,
-    stream: TcpStream,
+    remote_reserved: [u8; 8],
+    stream: PeerStream,
+    // bytes read from the stream that don't make a whole message yet
+    read_buf: BytesMut,
     local_state: LocalState,
     remote_state: PeerState,
+    // the other direction: whether we let the peer download from us, and if it wants to
+    am_choking: bool,
+    peer_interested: bool,
+    source: Option<Arc<dyn BlockSource>>,
+    // our pieces the peer knows about
+    announced: Bitfield,
+    // block bytes received from and sent to the peer
+    downloaded: u64,
+    uploaded: u64,
+    // only blocks are throttled, other messages go through right away
+    download_limit: Throttle,
+    upload_limit: Throttle,
+    // the frame at the head of `read_buf` went through `download_limit` already
+    read_charged: bool,
+    // the extended message id the peer takes ut_holepunch messages with
+    holepunch_id: Option<u8>,
+    // the same for ut_metadata, and the size of the peer's info dictionary
+    metadata_id: Option<u8>,
+    metadata_size: Option<usize>,
+    // the port the peer listens on, as it told us
+    listen_port: Option<u16>,
+    // ut_holepunch messages received and not taken yet
+    holepunch: Vec<Holepunch>,
+}
+
+fn local_reserved(v2: bool) -> [u8; 8] {
+    let mut reserved = [0; 8];
+    reserved[5] |= RESERVED_EXTENSIONS;
+    if v2 {
+        reserved[7] |= RESERVED_V2;
+    }
+    reserved
 }
 
 impl Peer {
     pub async fn connect(addr: SocketAddr, torrent: &TorrentFile) -> anyhow::Result<Self> {
-        let mut tcp_peer = TcpStream::connect(addr)
-            .await
-            .context("Connecting to peer")?;
+        Peer::connect_with(
+            addr,
+            torrent.info.wire_hash()?,
+            torrent.info.is_v2(),
+            &Transport::Tcp,
+            Encryption::Disabled,
+        )
+        .await
+    }
+
+    // `info_hash` selects the swarm: v1 hash or truncated v2 hash for hybrid torrents
+    pub async fn connect_with(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        v2: bool,
+        transport: &Transport,
+        encryption: Encryption,
+    ) -> anyhow::Result<Self> {
+        let mut tcp_peer = mse::connect(addr, transport, info_hash, encryption).await?;
 
         let local_id = b"00112233445566778899";
 
-        let hs = Handshake::new(torrent.info.hash()?, local_id.to_owned());
+        let hs = Handshake::new(info_hash, local_id.to_owned(), local_reserved(v2));
         tcp_peer.write_all(&hs.to_bytes()).await?;
 
         let mut buf = [0; HANDSHAKE_LEN];
         tcp_peer.read_exact(&mut buf).await?;
 
-        //eprintln!("{buf:?}");
-
         let hs_resp = Handshake::from_bytes(&buf)?;
+        anyhow::ensure!(
+            hs_resp.info_hash == info_hash,
+            "Peer sent another info hash"
+        );
+
+        Ok(Peer::new(addr, tcp_peer, info_hash, hs_resp))
+    }
 
-        Ok(Peer {
+    // Answers the handshake of an incoming connection. `info_hashes` are the swarms we serve,
+    // `lookup` tells whether we still serve the one asked for, and if it is a v2 torrent.
+    pub async fn accept(
+        stream: Box<dyn ByteStream>,
+        addr: SocketAddr,
+        encryption: Encryption,
+        info_hashes: &[[u8; 20]],
+        lookup: impl Fn(&[u8; 20]) -> Option<bool>,
+    ) -> anyhow::Result<Self> {
+        let mut stream = mse::accept(stream, encryption, info_hashes).await?;
+
+        let mut buf = [0; HANDSHAKE_LEN];
+        stream.read_exact(&mut buf).await?;
+        let hs_req = Handshake::from_bytes(&buf)?;
+        let v2 = lookup(&hs_req.info_hash)
+            .ok_or_else(|| anyhow!("Unknown info hash {}", hex::encode(hs_req.info_hash)))?;
+
+        let local_id = b"00112233445566778899";
+        let hs = Handshake::new(hs_req.info_hash, local_id.to_owned(), local_reserved(v2));
+        stream.write_all(&hs.to_bytes()).await?;
+
+        Ok(Peer::new(addr, stream, hs_req.info_hash, hs_req))
+    }
+
+    fn new(addr: SocketAddr, stream: PeerStream, info_hash: [u8; 20], remote: Handshake) -> Self {
+        Peer {
             remote_addr: addr,
-            stream: tcp_peer,
-            local_id: local_id.to_owned(),
-            remote_id: hs_resp.peer_id,
+            stream,
+            local_id: *b"00112233445566778899",
+            remote_id: remote.peer_id,
+            info_hash,
             pieces_bitfield: vec![],
+            remote_reserved: remote.reserved,
+            read_buf: BytesMut::new(),
             local_state: LocalState::Uninterested,
             remote_state: PeerState::Choked,
-        })
-    }
-
-    async fn recv_message<'a>(&mut self, buf: &'a mut [u8]) -> anyhow::Result<Message<'a>> {
-        self.stream.read_exact(&mut buf[..4]).await?; // length
-                                                      //eprintln!("Read {:?}", &buf[..5]);
-        let len = u32::from_be_bytes(buf[..4].try_into()?);
-        if len == 0 {
-            return Ok(Message {
-                length: 0,
-                kind: MessageType::Ping,
-                payload: MessagePayload::None,
-            });
+            am_choking: true,
+            peer_interested: false,
+            source: None,
+            announced: Bitfield::new(0),
+            downloaded: 0,
+            uploaded: 0,
+            download_limit: Throttle::default(),
+            upload_limit: Throttle::default(),
+            read_charged: false,
+            holepunch_id: None,
+            metadata_id: None,
+            metadata_size: None,
+            listen_port: None,
+            holepunch: vec![],
         }
+    }
 
-        self.stream
-            .read_exact(&mut buf[4..4 + len as usize])
-            .await?; // type + payload
-        let msg = Message::from_bytes(&buf[..4 + len as usize])?;
+    // Throttles what we read from the peer and the blocks we upload to it.
+    pub fn set_rate_limits(&mut self, download: Throttle, upload: Throttle) {
+        self.download_limit = download;
+        self.upload_limit = upload;
+    }
 
-        eprintln!("Received: {:?}", msg.kind);
+    async fn send(&mut self, msg: &Message<'_>) -> anyhow::Result<()> {
+        trace!(kind = ?msg.kind, len = msg.length, "Sending");
+        self.stream.write_all(&msg.to_bytes()).await?;
+        Ok(())
+    }
 
-        Ok(msg)
+    // Reads one whole message, length prefix included. Cancel safe: bytes read before a
+    // cancellation stay buffered for the next call.
+    async fn recv_frame(&mut self) -> anyhow::Result<Bytes> {
+        loop {
+            if self.read_buf.len() >= 4 {
+                let len = u32::from_be_bytes(self.read_buf[..4].try_into()?) as usize;
+                anyhow::ensure!(len <= MAX_MESSAGE_LEN, "Message too long: {len}");
+                if self.read_buf.len() >= 4 + len {
+                    if len > 0 && self.read_buf[4] == MessageType::Piece as u8 && !self.read_charged
+                    {
+                        self.read_charged = true;
+                        self.download_limit.acquire(len).await;
+                    }
+                    self.read_charged = false;
+                    return Ok(self.read_buf.split_to(4 + len).freeze());
+                }
+            }
+            if self.stream.read_buf(&mut self.read_buf).await? == 0 {
+                anyhow::bail!("Connection closed by peer");
+            }
+        }
     }
 
     pub async fn recv_bitfield(&mut self) -> anyhow::Result<()> {
-        let mut buf = [0; 1028];
-        let msg = self.recv_message(&mut buf).await?;
+        let frame = self.recv_frame().await?;
+        let msg = Message::from_bytes(&frame)?;
         if let MessagePayload::Bitfield(bf) = msg.payload {
             self.pieces_bitfield.extend_from_slice(bf);
             Ok(())
@@ -283,13 +596,390 @@ impl Peer {
         }
     }
 
+    // Lets the peer download from us, must be called before any other message is sent.
+    pub async fn set_source(&mut self, source: Arc<dyn BlockSource>) -> anyhow::Result<()> {
+        let have = source.bitfield();
+        if have.count() > 0 {
+            let msg = Message::bitfield(have.as_bytes());
+            self.send(&msg).await?;
+        }
+        self.announced = have;
+        self.source = Some(source);
+        Ok(())
+    }
+
+    // Tells the peer about pieces we got since the last call.
+    pub async fn announce_pieces(&mut self) -> anyhow::Result<()> {
+        let Some(source) = &self.source else {
+            return Ok(());
+        };
+        let have = source.bitfield();
+        let mut buf = BytesMut::new();
+        let mut count = 0;
+        for index in have.iter_set().filter(|&i| !self.announced.has(i)) {
+            buf.put(Message::have(index).to_bytes());
+            count += 1;
+        }
+        self.announced = have;
+        if !buf.is_empty() {
+            trace!("Sending {count} Have");
+            self.stream.write_all(&buf).await?;
+        }
+        Ok(())
+    }
+
+    // Handles the messages of a peer downloading from us, and piece announcements.
+    async fn handle_remote(
+        &mut self,
+        kind: MessageType,
+        payload: MessagePayload<'_>,
+    ) -> anyhow::Result<()> {
+        match (kind, payload) {
+            (MessageType::Have, MessagePayload::Have(index)) => self.set_piece(index as usize),
+            (MessageType::Bitfield, MessagePayload::Bitfield(bf)) => {
+                self.pieces_bitfield = bf.to_vec();
+            }
+            // the choker decides what to do about it
+            (MessageType::Interested, _) => self.peer_interested = true,
+            (MessageType::NotInterested, _) => self.peer_interested = false,
+            (
+                MessageType::Request,
+                MessagePayload::PieceInfo {
+                    index,
+                    begin,
+                    length,
+                },
+            ) => {
+                let (index, begin, length) = (index as usize, begin as usize, length as usize);
+                // requests of choked peers are dropped
+                let block = match &self.source {
+                    Some(source) if !self.am_choking && length <= MAX_BLOCK_LEN => {
+                        source.read_block(index, begin, length)
+                    }
+                    _ => None,
+                };
+                if let Some(block) = block {
+                    self.upload_limit.acquire(block.len()).await;
+                    let msg = Message::piece(index, begin, &block);
+                    self.send(&msg).await?;
+                    self.uploaded += block.len() as u64;
+                }
+            }
+            (MessageType::HashRequest, MessagePayload::HashRequest(request)) => {
+                self.reject_hashes(request).await?;
+            }
+            (MessageType::Extended, MessagePayload::Extended { id, payload }) => {
+                self.handle_extended(id, payload)?;
+            }
+            // requests are answered right away, there is nothing left to cancel
+            (
+                MessageType::Cancel
+                | MessageType::Ping
+                | MessageType::Hashes
+                | MessageType::HashReject,
+                _,
+            ) => {}
+            (k, _) => anyhow::bail!("unexpected msg {:?} state {:?}", k, self.remote_state),
+        }
+        Ok(())
+    }
+
+    // Serves the peer for up to `timeout` while we have nothing to download from it.
+    pub async fn idle(&mut self, timeout: Duration) -> anyhow::Result<()> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        while let Ok(frame) = tokio::time::timeout_at(deadline, self.recv_frame()).await {
+            let frame = frame?;
+            let msg = Message::from_bytes(&frame)?;
+            match msg.kind {
+                MessageType::Choke => self.remote_state = PeerState::Choked,
+                MessageType::Unchoke => self.remote_state = PeerState::Unchoked,
+                // a block of a piece given up on
+                MessageType::Piece => {}
+                _ => self.handle_remote(msg.kind, msg.payload).await?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn has_piece(&self, index: usize) -> bool {
+        self.pieces_bitfield
+            .get(index / 8)
+            .is_some_and(|b| b & (0x80 >> (index % 8)) != 0)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.stream.is_encrypted()
+    }
+
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+
+    // block bytes received and sent so far
+    pub fn transferred(&self) -> (u64, u64) {
+        (self.downloaded, self.uploaded)
+    }
+
+    // Chokes or unchokes the peer, only peers with a source can be unchoked.
+    pub async fn set_choking(&mut self, choking: bool) -> anyhow::Result<()> {
+        let choking = choking || self.source.is_none();
+        if choking != self.am_choking {
+            let kind = if choking {
+                MessageType::Choke
+            } else {
+                MessageType::Unchoke
+            };
+            self.send(&Message::status(kind)).await?;
+            self.am_choking = choking;
+        }
+        Ok(())
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces_bitfield
+            .iter()
+            .map(|b| b.count_ones() as usize)
+            .sum()
+    }
+
+    fn set_piece(&mut self, index: usize) {
+        if self.pieces_bitfield.len() <= index / 8 {
+            self.pieces_bitfield.resize(index / 8 + 1, 0);
+        }
+        self.pieces_bitfield[index / 8] |= 0x80 >> (index % 8);
+    }
+
+    pub fn supports_v2(&self) -> bool {
+        self.remote_reserved[7] & RESERVED_V2 != 0
+    }
+
+    pub fn supports_extensions(&self) -> bool {
+        self.remote_reserved[5] & RESERVED_EXTENSIONS != 0
+    }
+
+    // Tells the peer which extensions we speak and where we listen, when it speaks the
+    // extension protocol.
+    pub async fn send_extension_handshake(
+        &mut self,
+        listen_port: Option<u16>,
+    ) -> anyhow::Result<()> {
+        if !self.supports_extensions() {
+            return Ok(());
+        }
+        let mut handshake = ExtensionHandshake {
+            p: listen_port.map(i64::from),
+            ..Default::default()
+        };
+        handshake
+            .m
+            .insert("ut_holepunch".to_owned(), UT_HOLEPUNCH as i64);
+        let payload = serde_bencode::to_bytes(&handshake)?;
+        let msg = Message::extended(EXTENSION_HANDSHAKE, &payload);
+        self.send(&msg).await?;
+        Ok(())
+    }
+
+    fn handle_extended(&mut self, id: u8, payload: &[u8]) -> anyhow::Result<()> {
+        match id {
+            EXTENSION_HANDSHAKE => {
+                let handshake = serde_bencode::from_bytes::<ExtensionHandshake>(payload)?;
+                // sent again when the peer changes its extensions
+                if let Some(&id) = handshake.m.get("ut_holepunch") {
+                    self.holepunch_id = u8::try_from(id).ok().filter(|&id| id != 0);
+                }
+                if let Some(&id) = handshake.m.get("ut_metadata") {
+                    self.metadata_id = u8::try_from(id).ok().filter(|&id| id != 0);
+                }
+                if let Some(size) = handshake.metadata_size {
+                    self.metadata_size = usize::try_from(size)
+                        .ok()
+                        .filter(|&s| s > 0 && s <= metadata::MAX_SIZE);
+                }
+                if let Some(port) = handshake.p.and_then(|p| u16::try_from(p).ok()) {
+                    self.listen_port = Some(port).filter(|&p| p != 0);
+                }
+            }
+            UT_HOLEPUNCH => self.holepunch.push(Holepunch::from_bytes(payload)?),
+            // extensions we didn't announce
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn supports_holepunch(&self) -> bool {
+        self.holepunch_id.is_some()
+    }
+
+    // where the peer takes connections, the address it connected from unless it told us
+    pub fn listen_addr(&self) -> SocketAddr {
+        match self.listen_port {
+            Some(port) => SocketAddr::new(self.remote_addr.ip(), port),
+            None => self.remote_addr,
+        }
+    }
+
+    // ut_holepunch messages received since the last call
+    pub fn take_holepunch(&mut self) -> Vec<Holepunch> {
+        std::mem::take(&mut self.holepunch)
+    }
+
+    pub async fn send_holepunch(&mut self, msg: Holepunch) -> anyhow::Result<()> {
+        let id = self
+            .holepunch_id
+            .ok_or_else(|| anyhow!("Peer doesn't support holepunch"))?;
+        let payload = msg.to_bytes();
+        self.send(&Message::extended(id, &payload)).await?;
+        Ok(())
+    }
+
+    // Downloads the info dictionary from the peer, one piece at a time, and checks it against
+    // the info hash.
+    pub async fn fetch_metadata(&mut self) -> anyhow::Result<Vec<u8>> {
+        anyhow::ensure!(
+            self.supports_extensions(),
+            "Peer doesn't speak the extension protocol"
+        );
+        let mut handshake = ExtensionHandshake::default();
+        handshake
+            .m
+            .insert("ut_metadata".to_owned(), UT_METADATA as i64);
+        let payload = serde_bencode::to_bytes(&handshake)?;
+        self.send(&Message::extended(EXTENSION_HANDSHAKE, &payload))
+            .await?;
+
+        let mut info = vec![];
+        let mut requested = false;
+        loop {
+            if let (false, Some(id), Some(size)) = (requested, self.metadata_id, self.metadata_size)
+            {
+                anyhow::ensure!(info.len() < size, "Metadata longer than announced");
+                let payload = Metadata::Request(info.len() / metadata::PIECE_LEN).to_bytes();
+                self.send(&Message::extended(id, &payload)).await?;
+                requested = true;
+            }
+
+            let frame = self.recv_frame().await?;
+            let msg = Message::from_bytes(&frame)?;
+            match (msg.kind, msg.payload) {
+                (
+                    MessageType::Extended,
+                    MessagePayload::Extended {
+                        id: EXTENSION_HANDSHAKE,
+                        payload,
+                    },
+                ) => {
+                    self.handle_extended(EXTENSION_HANDSHAKE, payload)?;
+                    anyhow::ensure!(
+                        self.metadata_id.is_some() && self.metadata_size.is_some(),
+                        "Peer doesn't serve the metadata"
+                    );
+                }
+                (
+                    MessageType::Extended,
+                    MessagePayload::Extended {
+                        id: UT_METADATA,
+                        payload,
+                    },
+                ) => match Metadata::from_bytes(payload)? {
+                    // we don't serve the metadata
+                    Metadata::Request(piece) => {
+                        if let Some(id) = self.metadata_id {
+                            let payload = Metadata::Reject(piece).to_bytes();
+                            self.send(&Message::extended(id, &payload)).await?;
+                        }
+                    }
+                    Metadata::Reject(piece) => anyhow::bail!("Metadata piece {piece} rejected"),
+                    Metadata::Data { piece, data, .. } => {
+                        let size = self.metadata_size.unwrap_or_default();
+                        anyhow::ensure!(
+                            requested && piece == info.len() / metadata::PIECE_LEN,
+                            "Unexpected metadata piece {piece}"
+                        );
+                        info.extend_from_slice(&data);
+                        requested = false;
+                        if info.len() >= size {
+                            anyhow::ensure!(info.len() == size, "Metadata longer than announced");
+                            anyhow::ensure!(
+                                Sha1::digest(&info)[..] == self.info_hash,
+                                "Metadata doesn't match the info hash"
+                            );
+                            return Ok(info);
+                        }
+                        anyhow::ensure!(
+                            data.len() == metadata::PIECE_LEN,
+                            "Short metadata piece {piece}"
+                        );
+                    }
+                },
+                (MessageType::Choke, _) => self.remote_state = PeerState::Choked,
+                (MessageType::Unchoke, _) => self.remote_state = PeerState::Unchoked,
+                (MessageType::Piece, _) => anyhow::bail!("unexpected msg {:?}", msg.kind),
+                (kind, payload) => self.handle_remote(kind, payload).await?,
+            }
+        }
+    }
+
+    // Asks for the leaf hashes of one piece, `None` when the peer rejects the request.
+    pub async fn request_block_hashes(
+        &mut self,
+        pieces_root: merkle::Hash,
+        file_piece: usize,
+        width: usize,
+    ) -> anyhow::Result<Option<Vec<merkle::Hash>>> {
+        let request = HashRequest {
+            pieces_root,
+            base_layer: 0,
+            index: (file_piece * width) as u32,
+            length: width as u32,
+            proof_layers: 0,
+        };
+        let msg = Message::hash_request(MessageType::HashRequest, request);
+        self.send(&msg).await?;
+
+        loop {
+            let frame = self.recv_frame().await?;
+            let msg = Message::from_bytes(&frame)?;
+            match (msg.kind, msg.payload) {
+                (MessageType::Hashes, MessagePayload::Hashes { request: r, hashes })
+                    if r == request =>
+                {
+                    return Ok(Some(
+                        hashes
+                            .chunks_exact(32)
+                            .map(|h| h.try_into().unwrap())
+                            .collect(),
+                    ));
+                }
+                (MessageType::HashReject, MessagePayload::HashRequest(r)) if r == request => {
+                    return Ok(None)
+                }
+                (MessageType::Choke, _) => self.remote_state = PeerState::Choked,
+                (MessageType::Unchoke, _) => self.remote_state = PeerState::Unchoked,
+                (MessageType::Piece, _) => anyhow::bail!("unexpected msg {:?}", msg.kind),
+                (kind, payload) => self.handle_remote(kind, payload).await?,
+            }
+        }
+    }
+
+    // we don't serve hashes
+    async fn reject_hashes(&mut self, request: HashRequest) -> anyhow::Result<()> {
+        let msg = Message::hash_request(MessageType::HashReject, request);
+        self.send(&msg).await?;
+        Ok(())
+    }
+
     fn requests(
         piece_id: usize,
         block_size: usize,
+        start: usize,
         total_len: usize,
     ) -> VecDeque<Message<'static>> {
         (0..(total_len / block_size) + 1)
-            .scan(0, |cur_offset, _| {
+            .scan(start, |cur_offset, _| {
                 if *cur_offset < total_len {
                     let (block_begin, block_length) = (
                         *cur_offset,
@@ -309,22 +999,52 @@ impl Peer {
         &mut self,
         piece_index: usize,
         piece_length: usize,
-        piece_hash: [u8; 20],
+        check: PieceCheck,
     ) -> anyhow::Result<Bytes> {
-        const BLOCK_SIZE: usize = 16 * 1024; // 16 Kb
-        let mut buf = [0; 1024 + BLOCK_SIZE];
-        let mut piece_buf: BytesMut = BytesMut::with_capacity(piece_length);
-        let mut pending_piece_offset = 0;
-        let mut pending_requests = Peer::requests(piece_index, BLOCK_SIZE, piece_length);
+        let mut piece_buf = BytesMut::with_capacity(piece_length);
+        self.continue_piece(piece_index, piece_length, check, &mut piece_buf)
+            .await
+    }
+
+    // Downloads the rest of a piece whose first bytes are already in `piece_buf`.
+    // On error `piece_buf` keeps the blocks received so far.
+    #[instrument(name = "piece", skip_all, fields(index = piece_index, bytes = piece_length))]
+    pub async fn continue_piece(
+        &mut self,
+        piece_index: usize,
+        piece_length: usize,
+        check: PieceCheck,
+        piece_buf: &mut BytesMut,
+    ) -> anyhow::Result<Bytes> {
+        const BLOCK_SIZE: usize = merkle::BLOCK_SIZE; // 16 Kb
+        let mut pending_piece_offset = piece_buf.len();
+        let mut pending_requests =
+            Peer::requests(piece_index, BLOCK_SIZE, pending_piece_offset, piece_length);
         const PIPELINED_REQUESTS: u32 = 5;
 
-        eprintln!("Downloading piece {piece_index} len {piece_length}");
+        debug!(from = pending_piece_offset, "Downloading");
+
+        // v2 peers give us the block hashes so that each block is checked on arrival
+        let mut block_hashes = None;
+        if let PieceCheck::Merkle {
+            pieces_root,
+            file_piece,
+            width,
+            ..
+        } = check
+        {
+            if self.supports_v2() && width > 1 && width <= MAX_HASHES {
+                block_hashes = self
+                    .request_block_hashes(pieces_root, file_piece, width)
+                    .await?
+                    .filter(|hashes| check.verify_block_hashes(hashes));
+            }
+        }
 
         // Send interested message
         if let LocalState::Uninterested = self.local_state {
             let msg = Message::status(MessageType::Interested);
-            eprintln!("Sending {msg:?}");
-            self.stream.write_all(&msg.to_bytes()).await?;
+            self.send(&msg).await?;
 
             self.local_state = LocalState::Interested;
         }
@@ -333,8 +1053,7 @@ impl Peer {
             // Send requests
             for _ in 0..PIPELINED_REQUESTS {
                 if let Some(msg) = pending_requests.pop_front() {
-                    eprintln!("Sending {msg:?}");
-                    self.stream.write_all(&msg.to_bytes()).await?;
+                    self.send(&msg).await?;
                 } else {
                     break;
                 }
@@ -342,7 +1061,8 @@ impl Peer {
         }
 
         while pending_piece_offset < piece_length {
-            let msg = self.recv_message(&mut buf).await?;
+            let frame = self.recv_frame().await?;
+            let msg = Message::from_bytes(&frame)?;
 
             if msg.length == 0 {
                 continue;
@@ -357,8 +1077,7 @@ impl Peer {
                     // Send requests
                     for _ in 0..PIPELINED_REQUESTS {
                         if let Some(msg) = pending_requests.pop_front() {
-                            eprintln!("Sending {msg:?}");
-                            self.stream.write_all(&msg.to_bytes()).await?;
+                            self.send(&msg).await?;
                         } else {
                             break;
                         }
@@ -368,9 +1087,12 @@ impl Peer {
                     self.remote_state = PeerState::Unchoked;
                 }
                 (PeerState::Unchoked, MessageType::Choke, _) => {
-                    // change state to choked
+                    // change state to choked, requests in flight are dropped by the peer
                     self.remote_state = PeerState::Choked;
+                    pending_requests =
+                        Peer::requests(piece_index, BLOCK_SIZE, pending_piece_offset, piece_length);
                 }
+                (PeerState::Unchoked, MessageType::Unchoke, _) => {}
                 (
                     PeerState::Unchoked,
                     MessageType::Piece,
@@ -380,36 +1102,40 @@ impl Peer {
                         piece,
                     },
                 ) => {
-                    eprintln!("Piece {index} offset={begin} len={}", piece.len());
                     anyhow::ensure!(index as usize == piece_index);
                     anyhow::ensure!(begin as usize == pending_piece_offset);
+                    if let Some(hashes) = &block_hashes {
+                        anyhow::ensure!(
+                            hashes.get(begin as usize / BLOCK_SIZE)
+                                == Some(&merkle::hash_block(piece)),
+                            "Block {begin} of piece {index} failed merkle check"
+                        );
+                    }
                     piece_buf.put(piece);
 
                     pending_piece_offset += piece.len();
+                    self.downloaded += piece.len() as u64;
 
                     // Send next request
                     if let Some(msg) = pending_requests.pop_front() {
-                        eprintln!("Sending {msg:?}");
-                        self.stream.write_all(&msg.to_bytes()).await?;
+                        self.send(&msg).await?;
                     }
                 }
-                (_, k, _) => anyhow::bail!("unexpected msg {:?} state {:?}", k, self.remote_state),
+                (PeerState::Choked, MessageType::Piece, _) => {
+                    anyhow::bail!("unexpected msg Piece state {:?}", self.remote_state)
+                }
+                (_, kind, payload) => self.handle_remote(kind, payload).await?,
             }
         }
 
         // check hash
-        let mut hasher = Sha1::new();
-        hasher.update(&piece_buf[..]);
-        let hashed_info = hasher.finalize();
-
-        if &hashed_info[..] != piece_hash {
+        if !check.verify(&piece_buf[..]) {
+            piece_buf.clear();
             Err(anyhow!(
-                "Invalid hash of received piece {:?} {:?}",
-                hashed_info,
-                piece_hash
+                "Invalid hash of received piece {piece_index} {check:?}"
             ))
         } else {
-            Ok(piece_buf.freeze())
+            Ok(piece_buf.split().freeze())
         }
     }
 }
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// wait before retrying an address, doubled for every failure in a row
+const RETRY_BACKOFF: Duration = Duration::from_secs(10);
+// then it is forgotten
+const MAX_FAILURES: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Idle,
+    Dialing,
+    Connected,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    // the swarm it was announced in
+    info_hash: [u8; 20],
+    // found on the local network, dialed before the others
+    local: bool,
+    state: State,
+    failures: u32,
+    retry: Instant,
+}
+
+// Addresses a download may connect to, from every source, each listed once. Addresses that
+// fail or drop wait longer each time before they are dialed again.
+#[derive(Debug, Default)]
+pub struct PeerList {
+    candidates: HashMap<SocketAddr, Candidate>,
+}
+
+impl PeerList {
+    pub fn new() -> Self {
+        PeerList::default()
+    }
+
+    // Returns whether the address is new.
+    pub fn add(&mut self, addr: SocketAddr, info_hash: [u8; 20]) -> bool {
+        self.insert(addr, info_hash, false)
+    }
+
+    // Like `add`, for a peer on the local network. One listed already is dialed first from
+    // now on.
+    pub fn add_local(&mut self, addr: SocketAddr, info_hash: [u8; 20]) -> bool {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.local = true;
+        }
+        self.insert(addr, info_hash, true)
+    }
+
+    fn insert(&mut self, addr: SocketAddr, info_hash: [u8; 20], local: bool) -> bool {
+        if self.candidates.contains_key(&addr) {
+            return false;
+        }
+        self.candidates.insert(
+            addr,
+            Candidate {
+                info_hash,
+                local,
+                state: State::Idle,
+                failures: 0,
+                retry: Instant::now(),
+            },
+        );
+        true
+    }
+
+    // The next address to dial, local ones and then those that failed the least first. It
+    // counts as dialing until `connected` or `failed`.
+    pub fn dial(&mut self) -> Option<(SocketAddr, [u8; 20])> {
+        let now = Instant::now();
+        let (addr, candidate) = self
+            .candidates
+            .iter_mut()
+            .filter(|(_, c)| c.state == State::Idle && c.retry <= now)
+            .min_by_key(|(_, c)| (!c.local, c.failures, c.retry))?;
+        candidate.state = State::Dialing;
+        Some((*addr, candidate.info_hash))
+    }
+
+    // Dials the address again right away, like a new one: a relay told us it connects to us
+    // now.
+    pub fn retry_now(&mut self, addr: SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            if candidate.state == State::Idle {
+                candidate.failures = 0;
+                candidate.retry = Instant::now();
+            }
+        }
+    }
+
+    pub fn connected(&mut self, addr: SocketAddr) {
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.state = State::Connected;
+        }
+    }
+
+    // Dialing failed: the address is retried later, or dropped after too many failures.
+    pub fn failed(&mut self, addr: SocketAddr) {
+        let Some(candidate) = self.candidates.get_mut(&addr) else {
+            return;
+        };
+        candidate.failures += 1;
+        if candidate.failures >= MAX_FAILURES {
+            self.candidates.remove(&addr);
+            return;
+        }
+        candidate.state = State::Idle;
+        candidate.retry = Instant::now() + RETRY_BACKOFF * 2u32.pow(candidate.failures - 1);
+    }
+
+    // The connection dropped. One that transferred data is retried soon, others count as
+    // failures.
+    pub fn disconnected(&mut self, addr: SocketAddr, useful: bool) {
+        if !useful {
+            return self.failed(addr);
+        }
+        if let Some(candidate) = self.candidates.get_mut(&addr) {
+            candidate.failures = 0;
+            candidate.state = State::Idle;
+            candidate.retry = Instant::now() + RETRY_BACKOFF;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_peer_backs_off() {
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let mut list = PeerList::new();
+        assert!(list.add(addr, [1; 20]));
+        assert!(!list.add(addr, [1; 20]));
+
+        for failures in 1..MAX_FAILURES {
+            assert_eq!(list.dial(), Some((addr, [1; 20])));
+            // dialing already
+            assert_eq!(list.dial(), None);
+            let before = Instant::now();
+            list.failed(addr);
+
+            // not again before the backoff, which doubles each time
+            assert_eq!(list.dial(), None);
+            let candidate = list.candidates.get_mut(&addr).unwrap();
+            let backoff = RETRY_BACKOFF * 2u32.pow(failures - 1);
+            assert!(candidate.retry >= before + backoff);
+            assert!(candidate.retry <= Instant::now() + backoff);
+            // as if the backoff were over
+            candidate.retry -= backoff;
+        }
+
+        assert_eq!(list.dial(), Some((addr, [1; 20])));
+        list.failed(addr);
+        assert!(list.is_empty());
+        assert_eq!(list.dial(), None);
+    }
+}
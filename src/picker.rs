@@ -0,0 +1,102 @@
+use bytes::BytesMut;
+
+use std::collections::VecDeque;
+
+use crate::piece::PieceCheck;
+use crate::priority::Priority;
+
+// A piece still missing, with the bytes received so far.
+#[derive(Debug)]
+pub struct PieceJob {
+    pub index: usize,
+    pub length: usize,
+    pub check: PieceCheck,
+    pub buf: BytesMut,
+}
+
+impl PieceJob {
+    pub fn new(index: usize, length: usize, check: PieceCheck) -> Self {
+        PieceJob {
+            index,
+            length,
+            check,
+            buf: BytesMut::new(),
+        }
+    }
+}
+
+// Hands out missing pieces to peers and web seeds, highest priority first and then in
+// piece order. In sequential mode the order starts at the playhead.
+#[derive(Debug, Default)]
+pub struct PiecePicker {
+    jobs: VecDeque<PieceJob>,
+    priorities: Vec<Priority>,
+    sequential: bool,
+    playhead: usize,
+}
+
+impl PiecePicker {
+    pub fn new(priorities: Vec<Priority>) -> Self {
+        PiecePicker {
+            priorities,
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, job: PieceJob) {
+        self.jobs.push_back(job);
+    }
+
+    // skipped pieces are never handed out
+    pub fn pick(&mut self, has: impl Fn(usize) -> bool) -> Option<PieceJob> {
+        let (pos, _) = self
+            .jobs
+            .iter()
+            .enumerate()
+            .filter(|(_, job)| self.priority(job.index) > Priority::Skip && has(job.index))
+            .min_by_key(|(_, job)| {
+                let behind = self.sequential && job.index < self.playhead;
+                (
+                    std::cmp::Reverse(self.priority(job.index)),
+                    behind,
+                    job.index,
+                )
+            })?;
+        self.jobs.remove(pos)
+    }
+
+    pub fn priority(&self, index: usize) -> Priority {
+        self.priorities.get(index).copied().unwrap_or_default()
+    }
+
+    pub fn set_priority(&mut self, index: usize, priority: Priority) {
+        if let Some(p) = self.priorities.get_mut(index) {
+            *p = priority;
+        }
+    }
+
+    pub fn is_wanted(&self, index: usize) -> bool {
+        self.priority(index) > Priority::Skip
+    }
+
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    pub fn set_playhead(&mut self, index: usize) {
+        self.playhead = index;
+    }
+
+    // pieces waiting to be picked, the others are being downloaded or done
+    pub fn queued(&self) -> impl Iterator<Item = usize> + '_ {
+        self.jobs.iter().map(|job| job.index)
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+}
@@ -0,0 +1,112 @@
+use sha1::{Digest, Sha1};
+
+use crate::merkle;
+use crate::storage::FileLayout;
+use crate::torrent::TorrentFile;
+
+// How a downloaded piece is verified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceCheck {
+    // v1 SHA-1 over the piece, zero padded up to `length` when the piece ends in padding
+    Sha1 {
+        hash: [u8; 20],
+        length: usize,
+    },
+    // v2 root of the piece subtree with `width` leaves, piece `file_piece` of the file
+    // with `pieces_root`
+    Merkle {
+        pieces_root: merkle::Hash,
+        file_piece: usize,
+        root: merkle::Hash,
+        width: usize,
+    },
+}
+
+impl PieceCheck {
+    pub fn verify(&self, data: &[u8]) -> bool {
+        match self {
+            PieceCheck::Sha1 { hash, length } => {
+                let mut hasher = Sha1::new();
+                hasher.update(data);
+                hasher.update(vec![0; length.saturating_sub(data.len())]);
+                hasher.finalize()[..] == hash[..]
+            }
+            PieceCheck::Merkle { root, width, .. } => {
+                merkle::root(&merkle::block_hashes(data), *width, [0; 32]) == *root
+            }
+        }
+    }
+
+    // the block hashes of a piece, once checked against the piece root
+    pub fn verify_block_hashes(&self, hashes: &[merkle::Hash]) -> bool {
+        match self {
+            PieceCheck::Sha1 { .. } => false,
+            PieceCheck::Merkle { root, width, .. } => {
+                hashes.len() == *width && merkle::root(hashes, *width, [0; 32]) == *root
+            }
+        }
+    }
+}
+
+pub fn v1_checks(torrent: &TorrentFile, layout: &FileLayout) -> anyhow::Result<Vec<PieceCheck>> {
+    Ok(torrent
+        .info
+        .piece_hashes()?
+        .into_iter()
+        .enumerate()
+        .map(|(index, &hash)| PieceCheck::Sha1 {
+            hash,
+            length: layout.piece_len(index),
+        })
+        .collect())
+}
+
+pub fn v2_checks(torrent: &TorrentFile, layout: &FileLayout) -> anyhow::Result<Vec<PieceCheck>> {
+    let piece_length = torrent.info.piece_length;
+    anyhow::ensure!(
+        piece_length >= merkle::BLOCK_SIZE && piece_length.is_power_of_two(),
+        "Invalid v2 piece length {piece_length}"
+    );
+
+    let mut checks = vec![None; layout.num_pieces()];
+    for (file, slot) in torrent.info.v2_files()?.iter().zip(layout.files.iter()) {
+        let Some(pieces_root) = file.pieces_root else {
+            continue;
+        };
+        let first_piece = (slot.offset / piece_length as u64) as usize;
+
+        if file.length <= piece_length as u64 {
+            checks[first_piece] = Some(PieceCheck::Merkle {
+                pieces_root,
+                file_piece: 0,
+                root: pieces_root,
+                width: merkle::num_leaves(file.length.div_ceil(merkle::BLOCK_SIZE as u64) as usize),
+            });
+            continue;
+        }
+
+        for (file_piece, &root) in torrent.piece_layer(file)?.iter().enumerate() {
+            checks[first_piece + file_piece] = Some(PieceCheck::Merkle {
+                pieces_root,
+                file_piece,
+                root,
+                width: piece_length / merkle::BLOCK_SIZE,
+            });
+        }
+    }
+
+    checks
+        .into_iter()
+        .enumerate()
+        .map(|(index, check)| check.ok_or_else(|| anyhow::anyhow!("No v2 hash for piece {index}")))
+        .collect()
+}
+
+// v2 hashes are preferred for hybrid torrents
+pub fn piece_checks(torrent: &TorrentFile, layout: &FileLayout) -> anyhow::Result<Vec<PieceCheck>> {
+    if torrent.info.is_v2() {
+        v2_checks(torrent, layout)
+    } else {
+        v1_checks(torrent, layout)
+    }
+}
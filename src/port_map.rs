@@ -0,0 +1,216 @@
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::natpmp;
+use crate::net;
+use crate::upnp;
+
+// what leases are asked for, they are renewed halfway through
+const LEASE: Duration = Duration::from_secs(60 * 60);
+// wait before looking for a gateway again
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl Protocol {
+    // the IANA number
+    pub fn number(self) -> u8 {
+        match self {
+            Protocol::Tcp => 6,
+            Protocol::Udp => 17,
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "TCP"),
+            Protocol::Udp => write!(f, "UDP"),
+        }
+    }
+}
+
+// A router that forwards ports.
+#[derive(Debug, Clone)]
+pub enum Gateway {
+    // NAT-PMP or PCP
+    NatPmp(natpmp::Gateway),
+    Upnp(upnp::Gateway),
+}
+
+impl fmt::Display for Gateway {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Gateway::NatPmp(_) => write!(f, "NAT-PMP"),
+            Gateway::Upnp(_) => write!(f, "UPnP"),
+        }
+    }
+}
+
+impl Gateway {
+    // Forwards `port` on the default router when it speaks NAT-PMP or PCP, otherwise on a UPnP
+    // device.
+    pub async fn discover(port: u16) -> anyhow::Result<Mapped> {
+        if let Some(router) = net::default_gateway() {
+            let gateway = natpmp::Gateway::new((router, natpmp::PORT).into());
+            match Gateway::NatPmp(gateway).map(port, None).await {
+                Ok(mapped) => return Ok(mapped),
+                Err(e) => debug!("NAT-PMP on {router}: {e:#}"),
+            }
+        }
+        Gateway::Upnp(upnp::Gateway::discover().await?)
+            .map(port, None)
+            .await
+    }
+
+    // Forwards `port` for TCP and UDP, from the external ports of an earlier mapping when
+    // renewing it.
+    pub async fn map(mut self, port: u16, renew: Option<&Mapped>) -> anyhow::Result<Mapped> {
+        let mut external = vec![];
+        let mut lease = LEASE;
+        for protocol in [Protocol::Tcp, Protocol::Udp] {
+            let wanted = renew
+                .and_then(|m| m.port(protocol))
+                .or(external.first().map(|&(_, port)| port))
+                .unwrap_or(port);
+            let (mapped, granted) = match &mut self {
+                Gateway::NatPmp(gateway) => {
+                    let mapped = gateway.map(protocol, port, wanted, LEASE).await?;
+                    (mapped.external_port, mapped.lifetime)
+                }
+                Gateway::Upnp(gateway) => {
+                    let granted = gateway
+                        .add_port_mapping(protocol, port, wanted, LEASE)
+                        .await?;
+                    (wanted, granted)
+                }
+            };
+            external.push((protocol, mapped));
+            // zero for a permanent one
+            if !granted.is_zero() {
+                lease = lease.min(granted);
+            }
+        }
+        Ok(Mapped {
+            gateway: self,
+            port,
+            external,
+            lease,
+        })
+    }
+}
+
+// Ports a gateway forwards to us.
+#[derive(Debug, Clone)]
+pub struct Mapped {
+    pub gateway: Gateway,
+    // ours
+    pub port: u16,
+    pub external: Vec<(Protocol, u16)>,
+    pub lease: Duration,
+}
+
+impl Mapped {
+    // the external port of `protocol`
+    pub fn port(&self, protocol: Protocol) -> Option<u16> {
+        self.external
+            .iter()
+            .find(|(p, _)| *p == protocol)
+            .map(|&(_, port)| port)
+    }
+
+    pub async fn remove(self) -> anyhow::Result<()> {
+        for (protocol, external) in self.external {
+            match &self.gateway {
+                Gateway::NatPmp(gateway) => {
+                    // a lifetime of zero deletes it, NAT-PMP wants no external port then
+                    gateway
+                        .clone()
+                        .map(protocol, self.port, 0, Duration::ZERO)
+                        .await?;
+                }
+                Gateway::Upnp(gateway) => gateway.delete_port_mapping(protocol, external).await?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// Keeps a port forwarded on the router for as long as it runs, so that peers outside reach us.
+pub struct PortMapping {
+    mapped: Arc<Mutex<Option<Mapped>>>,
+    task: JoinHandle<()>,
+}
+
+impl PortMapping {
+    // Looks for a gateway in the background, and renews the mapping once found.
+    pub fn start(port: u16) -> Self {
+        let mapped: Arc<Mutex<Option<Mapped>>> = Arc::new(Mutex::new(None));
+        let current = mapped.clone();
+        let task = tokio::spawn(async move {
+            loop {
+                let renewing = current.lock().unwrap().clone();
+                let result = match &renewing {
+                    Some(m) => m.gateway.clone().map(port, Some(m)).await,
+                    None => Gateway::discover(port).await,
+                };
+                let wait = match result {
+                    Ok(m) => {
+                        if renewing.is_none() {
+                            info!(
+                                "Port {port} forwarded from {} by {}",
+                                m.port(Protocol::Tcp).unwrap_or(port),
+                                m.gateway
+                            );
+                        }
+                        let wait = (m.lease / 2).max(MIN_RENEW_INTERVAL);
+                        *current.lock().unwrap() = Some(m);
+                        wait
+                    }
+                    Err(e) => {
+                        warn!("Port mapping: {e:#}");
+                        *current.lock().unwrap() = None;
+                        RETRY_INTERVAL
+                    }
+                };
+                tokio::time::sleep(wait).await;
+            }
+        });
+        PortMapping { mapped, task }
+    }
+
+    // where peers outside reach us over TCP, once mapped
+    pub fn external_port(&self) -> Option<u16> {
+        self.mapped
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|m| m.port(Protocol::Tcp))
+    }
+
+    // Stops renewing and deletes the mapping.
+    pub async fn remove(&self) -> anyhow::Result<()> {
+        self.task.abort();
+        let mapped = self.mapped.lock().unwrap().take();
+        match mapped {
+            Some(mapped) => mapped.remove().await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for PortMapping {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
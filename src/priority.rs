@@ -0,0 +1,194 @@
+use anyhow::Context;
+
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::storage::FileLayout;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    // not downloaded
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+// Selects files by index or by a glob matched against the path inside the torrent or the
+// file name.
+#[derive(Debug, Clone)]
+pub enum FileFilter {
+    Index(usize),
+    Pattern(glob::Pattern),
+}
+
+impl FromStr for FileFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.parse() {
+            Ok(index) => Ok(FileFilter::Index(index)),
+            Err(_) => Ok(FileFilter::Pattern(
+                glob::Pattern::new(s).with_context(|| format!("Invalid pattern {s}"))?,
+            )),
+        }
+    }
+}
+
+impl FileFilter {
+    pub fn matches(&self, index: usize, path: &Path) -> bool {
+        match self {
+            FileFilter::Index(i) => *i == index,
+            FileFilter::Pattern(pattern) => {
+                let relative = path
+                    .iter()
+                    .map(|c| c.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                pattern.matches(&relative)
+                    || path
+                        .file_name()
+                        .is_some_and(|name| pattern.matches(&name.to_string_lossy()))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileSelection {
+    // only these files when not empty
+    pub include: Vec<FileFilter>,
+    pub exclude: Vec<FileFilter>,
+    pub high: Vec<FileFilter>,
+    pub low: Vec<FileFilter>,
+}
+
+impl FileSelection {
+    pub fn file_priorities(&self, layout: &FileLayout) -> Vec<Priority> {
+        let any =
+            |filters: &[FileFilter], index, path| filters.iter().any(|f| f.matches(index, path));
+
+        layout
+            .files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| {
+                let path = file.path.as_path();
+                if (!self.include.is_empty() && !any(&self.include, index, path))
+                    || any(&self.exclude, index, path)
+                {
+                    Priority::Skip
+                } else if any(&self.high, index, path) {
+                    Priority::High
+                } else if any(&self.low, index, path) {
+                    Priority::Low
+                } else {
+                    Priority::Normal
+                }
+            })
+            .collect()
+    }
+}
+
+// A piece gets the highest priority of the files it overlaps.
+pub fn piece_priorities(layout: &FileLayout, file_priorities: &[Priority]) -> Vec<Priority> {
+    (0..layout.num_pieces())
+        .map(|index| {
+            layout
+                .piece_spans(index)
+                .iter()
+                .map(|span| file_priorities[span.file_index])
+                .max()
+                .unwrap_or(Priority::Skip)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // dir/a.txt of 5 bytes, dir/sub/b.bin of 6 and c.txt of 9 in pieces of 4
+    fn layout() -> FileLayout {
+        FileLayout::from_files(
+            vec![
+                ("dir/a.txt".into(), 5),
+                ("dir/sub/b.bin".into(), 6),
+                ("c.txt".into(), 9),
+            ],
+            4,
+            true,
+        )
+    }
+
+    fn filters(filters: &[&str]) -> Vec<FileFilter> {
+        filters.iter().map(|f| f.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_filters() {
+        assert!(matches!("2".parse(), Ok(FileFilter::Index(2))));
+        assert!(matches!("*.txt".parse(), Ok(FileFilter::Pattern(_))));
+        assert!("[".parse::<FileFilter>().is_err());
+
+        let b = Path::new("dir/sub/b.bin");
+        assert!(FileFilter::Index(1).matches(1, b));
+        assert!(!FileFilter::Index(0).matches(1, b));
+        // the whole path or just the name
+        assert!("dir/sub/*".parse::<FileFilter>().unwrap().matches(1, b));
+        assert!("b.bin".parse::<FileFilter>().unwrap().matches(1, b));
+        assert!(!"sub".parse::<FileFilter>().unwrap().matches(1, b));
+    }
+
+    #[test]
+    fn file_priorities() {
+        let layout = layout();
+        let selection = FileSelection {
+            exclude: filters(&["*.bin"]),
+            high: filters(&["c.txt"]),
+            ..Default::default()
+        };
+        assert_eq!(
+            selection.file_priorities(&layout),
+            [Priority::Normal, Priority::Skip, Priority::High]
+        );
+
+        // exclude wins over include, and high over low
+        let selection = FileSelection {
+            include: filters(&["*.txt", "1"]),
+            exclude: filters(&["0"]),
+            high: filters(&["1"]),
+            low: filters(&["1", "2"]),
+        };
+        assert_eq!(
+            selection.file_priorities(&layout),
+            [Priority::Skip, Priority::High, Priority::Low]
+        );
+    }
+
+    #[test]
+    fn pieces_take_the_highest_file() {
+        let layout = layout();
+        // bytes 0..5 a, 5..11 b, 11..20 c
+        let priorities =
+            piece_priorities(&layout, &[Priority::Low, Priority::Skip, Priority::High]);
+        assert_eq!(
+            priorities,
+            [
+                Priority::Low,
+                // shared by a and the skipped b
+                Priority::Low,
+                // shared by b and c
+                Priority::High,
+                Priority::High,
+                Priority::High,
+            ]
+        );
+
+        let priorities =
+            piece_priorities(&layout, &[Priority::Skip, Priority::Skip, Priority::Normal]);
+        assert_eq!(priorities[..2], [Priority::Skip, Priority::Skip]);
+        assert_eq!(priorities[2], Priority::Normal);
+    }
+}
@@ -0,0 +1,110 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+// Bytes per second, shared by the limiters built on it so that a change applies to all of
+// them right away. `None` doesn't limit.
+#[derive(Debug, Clone, Default)]
+pub struct Rate(Arc<AtomicU64>);
+
+impl Rate {
+    pub fn new(rate: Option<u64>) -> Self {
+        Rate(Arc::new(AtomicU64::new(rate.unwrap_or_default())))
+    }
+
+    pub fn get(&self) -> Option<u64> {
+        Some(self.0.load(Ordering::Relaxed)).filter(|&r| r > 0)
+    }
+
+    pub fn set(&self, rate: Option<u64>) {
+        self.0.store(rate.unwrap_or_default(), Ordering::Relaxed);
+    }
+}
+
+// Token bucket shared by the connections it throttles. Tokens may go negative: the caller
+// that overdraws waits for the debt to refill, so big blocks still pass.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: Rate,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Self {
+        RateLimiter::with_rate(Rate::new(rate))
+    }
+
+    pub fn with_rate(rate: Rate) -> Self {
+        RateLimiter {
+            bucket: Mutex::new(Bucket {
+                tokens: rate.get().unwrap_or_default() as f64,
+                refilled: Instant::now(),
+            }),
+            rate,
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        RateLimiter::new(None)
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.rate.get()
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        self.rate.set(rate);
+    }
+
+    // Takes `bytes` tokens, waiting while the bucket is in debt. Cancel safe, the tokens are
+    // taken before waiting.
+    pub async fn acquire(&self, bytes: usize) {
+        let Some(rate) = self.rate() else {
+            return;
+        };
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            // at most one second worth of burst
+            bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64);
+            bucket.refilled = now;
+            bucket.tokens -= bytes as f64;
+            (-bucket.tokens).max(0.0) / rate as f64
+        };
+        if wait > 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::unlimited()
+    }
+}
+
+// The limiters one transfer goes through, the peer's own, its torrent's and the session's.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle(Vec<Arc<RateLimiter>>);
+
+impl Throttle {
+    pub fn new(limiters: Vec<Arc<RateLimiter>>) -> Self {
+        Throttle(limiters)
+    }
+
+    // Waits for the slowest limiter: each one counts the time spent waiting for the others.
+    pub async fn acquire(&self, bytes: usize) {
+        for limiter in &self.0 {
+            limiter.acquire(bytes).await;
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use crate::bitfield::Bitfield;
+use crate::piece::PieceCheck;
+use crate::storage::FileLayout;
+use crate::verify;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileState {
+    pub size: u64,
+    pub mtime: u64,
+    pub mtime_nsec: u32,
+}
+
+impl FileState {
+    fn read(path: &Path) -> Option<Self> {
+        let meta = std::fs::metadata(path).ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(FileState {
+            size: meta.len(),
+            mtime: mtime.as_secs(),
+            mtime_nsec: mtime.subsec_nanos(),
+        })
+    }
+
+    // missing files, like skipped ones, are recorded with an empty state
+    fn read_or_missing(path: &Path) -> Self {
+        FileState::read(path).unwrap_or(FileState {
+            size: 0,
+            mtime: 0,
+            mtime_nsec: 0,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PartialPiece {
+    pub index: usize,
+    // bytes from the start of the piece already on disk, not yet verified
+    pub length: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+
+    pub files: Vec<FileState>,
+
+    pub partial: Vec<PartialPiece>,
+}
+
+pub fn resume_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".resume");
+    PathBuf::from(name)
+}
+
+// Fast-resume state of a download, persisted next to its output.
+pub struct ResumeState {
+    path: PathBuf,
+    data_paths: Vec<PathBuf>,
+    info_hash: [u8; 20],
+    pub have: Bitfield,
+    pub partial: Vec<PartialPiece>,
+    // counts the changes, `written` holds the count last saved
+    changes: u64,
+    written: Arc<Mutex<Option<u64>>>,
+}
+
+// The resume state at one point, taken under the download's lock and written outside it.
+pub struct ResumeSnapshot {
+    path: PathBuf,
+    data_paths: Vec<PathBuf>,
+    info_hash: [u8; 20],
+    pieces: Vec<u8>,
+    partial: Vec<PartialPiece>,
+    changes: u64,
+    written: Arc<Mutex<Option<u64>>>,
+}
+
+impl ResumeState {
+    pub fn new(
+        output: &Path,
+        data_paths: Vec<PathBuf>,
+        info_hash: [u8; 20],
+        num_pieces: usize,
+    ) -> Self {
+        ResumeState {
+            path: resume_path(output),
+            data_paths,
+            info_hash,
+            have: Bitfield::new(num_pieces),
+            partial: vec![],
+            changes: 0,
+            written: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    // Loads the resume file if it matches the torrent and the files on disk look unchanged.
+    pub fn load(&mut self) -> anyhow::Result<bool> {
+        let content = match std::fs::read(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e).context("Reading resume file"),
+        };
+
+        let data = match serde_bencode::from_bytes::<ResumeData>(&content) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Ignoring corrupt resume file: {e}");
+                return Ok(false);
+            }
+        };
+
+        if data.info_hash[..] != self.info_hash || data.files.len() != self.data_paths.len() {
+            return Ok(false);
+        }
+
+        let unchanged = self
+            .data_paths
+            .iter()
+            .zip(data.files.iter())
+            .all(|(path, state)| FileState::read_or_missing(path) == *state);
+        if !unchanged {
+            return Ok(false);
+        }
+
+        self.have = Bitfield::from_bytes(&data.pieces, self.have.len());
+        self.partial = data
+            .partial
+            .into_iter()
+            .filter(|p| p.index < self.have.len() && !self.have.has(p.index))
+            .collect();
+
+        Ok(true)
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        self.snapshot().write()
+    }
+
+    pub fn snapshot(&self) -> ResumeSnapshot {
+        ResumeSnapshot {
+            path: self.path.clone(),
+            data_paths: self.data_paths.clone(),
+            info_hash: self.info_hash,
+            pieces: self.have.as_bytes().to_vec(),
+            partial: self.partial.clone(),
+            changes: self.changes,
+            written: self.written.clone(),
+        }
+    }
+
+    // changes since the last save
+    pub fn unsaved(&self) -> u64 {
+        self.changes - self.written.lock().unwrap().unwrap_or(0)
+    }
+
+    pub fn remove(&self) -> anyhow::Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn piece_done(&mut self, index: usize) {
+        self.changes += 1;
+        self.have.set(index);
+        self.partial.retain(|p| p.index != index);
+    }
+
+    pub fn piece_partial(&mut self, index: usize, length: usize) {
+        self.changes += 1;
+        self.partial.retain(|p| p.index != index);
+        if length > 0 {
+            self.partial.push(PartialPiece { index, length });
+        }
+    }
+
+    // Hashes the data already on disk, used when the resume file can't be trusted.
+    pub fn recheck(&mut self, layout: &FileLayout, checks: &[PieceCheck], threads: usize) {
+        self.changes += 1;
+        self.partial.clear();
+        self.have = verify::check_pieces(layout, &self.data_paths, checks, threads);
+    }
+
+    pub fn data_exists(&self) -> bool {
+        self.data_paths
+            .iter()
+            .any(|p| FileState::read(p).is_some_and(|s| s.size > 0))
+    }
+}
+
+impl ResumeSnapshot {
+    // Writes the resume file unless a newer snapshot already has. Snapshots may be written
+    // from several threads, they go one at a time.
+    pub fn write(self) -> anyhow::Result<()> {
+        let mut written = self.written.lock().unwrap();
+        if written.is_some_and(|w| w >= self.changes) {
+            return Ok(());
+        }
+        let data = ResumeData {
+            info_hash: self.info_hash.to_vec(),
+            pieces: self.pieces,
+            files: self
+                .data_paths
+                .iter()
+                .map(|p| FileState::read_or_missing(p))
+                .collect(),
+            partial: self.partial,
+        };
+
+        let tmp = self.path.with_extension("resume.tmp");
+        std::fs::write(&tmp, serde_bencode::to_bytes(&data)?).context("Writing resume file")?;
+        std::fs::rename(&tmp, &self.path).context("Replacing resume file")?;
+        *written = Some(self.changes);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn older_snapshot_does_not_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        let mut state = ResumeState::new(&output, vec![output.clone()], [1; 20], 4);
+        state.piece_done(0);
+        let older = state.snapshot();
+        state.piece_done(2);
+        assert_eq!(state.unsaved(), 2);
+        state.snapshot().write().unwrap();
+        older.write().unwrap();
+        assert_eq!(state.unsaved(), 0);
+
+        let mut loaded = ResumeState::new(&output, vec![output.clone()], [1; 20], 4);
+        assert!(loaded.load().unwrap());
+        assert!(loaded.have.has(0) && loaded.have.has(2));
+    }
+}
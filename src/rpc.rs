@@ -0,0 +1,388 @@
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::warn;
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::download::{DownloadOptions, PeerStatus};
+use crate::http::{self, Request};
+use crate::magnet::Magnet;
+use crate::priority::{FileFilter, FileSelection};
+use crate::session::{Session, TorrentId, TorrentStatus};
+use crate::torrent::TorrentFile;
+use crate::transmission;
+
+// JSON-RPC 2.0 control API of a session, posted to this path of a local HTTP server.
+pub const RPC_PATH: &str = "/rpc";
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        RpcError::new(SERVER_ERROR, format!("{e:#}"))
+    }
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct IdParams {
+    id: TorrentId,
+}
+
+// file filters as given on the command line
+#[derive(Deserialize, Default)]
+struct SelectionParams {
+    #[serde(default)]
+    include: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    high: Vec<String>,
+    #[serde(default)]
+    low: Vec<String>,
+}
+
+impl SelectionParams {
+    fn selection(&self) -> anyhow::Result<FileSelection> {
+        let filters = |filters: &[String]| {
+            filters
+                .iter()
+                .map(|f| f.parse())
+                .collect::<anyhow::Result<Vec<FileFilter>>>()
+        };
+        Ok(FileSelection {
+            include: filters(&self.include)?,
+            exclude: filters(&self.exclude)?,
+            high: filters(&self.high)?,
+            low: filters(&self.low)?,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct AddParams {
+    // hex encoded torrent file
+    metainfo: Option<String>,
+    magnet: Option<String>,
+    output: PathBuf,
+    threads: Option<usize>,
+    #[serde(default)]
+    sequential: bool,
+    #[serde(flatten)]
+    selection: SelectionParams,
+}
+
+#[derive(Deserialize)]
+struct RemoveParams {
+    id: TorrentId,
+    #[serde(default)]
+    delete_data: bool,
+}
+
+#[derive(Deserialize)]
+struct SelectParams {
+    id: TorrentId,
+    #[serde(flatten)]
+    selection: SelectionParams,
+}
+
+// bytes per second, a missing rate doesn't limit
+#[derive(Deserialize)]
+struct RateParams {
+    // the torrent's limits, or every peer's with `peer`, or else the session's
+    id: Option<TorrentId>,
+    #[serde(default)]
+    peer: bool,
+    download: Option<u64>,
+    upload: Option<u64>,
+}
+
+fn params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+pub fn status_json(status: &TorrentStatus) -> Value {
+    json!({
+        "id": status.id,
+        "name": status.name,
+        "info_hash": hex::encode(status.info_hash),
+        "output": status.output,
+        "state": status.state.to_string(),
+        "pieces": status.pieces,
+        "missing": status.missing,
+        "download_limit": status.download_limit,
+        "upload_limit": status.upload_limit,
+    })
+}
+
+fn peer_json(peer: &PeerStatus) -> Value {
+    json!({
+        "addr": peer.addr.to_string(),
+        "id": hex::encode(peer.id),
+        "pieces": peer.pieces,
+        "downloaded": peer.downloaded,
+        "download_rate": peer.download_rate,
+        "upload_rate": peer.upload_rate,
+        "interested": peer.interested,
+        "choked": peer.choked,
+        "encrypted": peer.encrypted,
+    })
+}
+
+async fn dispatch(session: &Session, method: &str, p: Value) -> Result<Value, RpcError> {
+    match method {
+        "add" => {
+            let p: AddParams = params(p)?;
+            let options = DownloadOptions {
+                threads: p.threads.unwrap_or_else(crate::verify::default_threads),
+                selection: p.selection.selection()?,
+                sequential: p.sequential,
+                ..Default::default()
+            };
+            let id = match (&p.metainfo, &p.magnet) {
+                (Some(metainfo), None) => {
+                    let bytes = hex::decode(metainfo).context("Invalid metainfo hex")?;
+                    session.add(TorrentFile::from_bytes(&bytes)?, &p.output, options)?
+                }
+                (None, Some(magnet)) => {
+                    let magnet: Magnet = magnet.parse()?;
+                    if let Some(id) = session.find(&magnet.info_hash) {
+                        return Err(anyhow!("Torrent already added as {id}").into());
+                    }
+                    let torrent = session.fetch_torrent(&magnet).await?;
+                    session.add(torrent, &p.output, options)?
+                }
+                _ => {
+                    return Err(RpcError::new(
+                        INVALID_PARAMS,
+                        "Either metainfo or magnet is needed",
+                    ))
+                }
+            };
+            Ok(json!({ "id": id }))
+        }
+        "list" => Ok(session.list().iter().map(status_json).collect()),
+        "status" => {
+            let p: IdParams = params(p)?;
+            Ok(status_json(&session.status(p.id)?))
+        }
+        "pause" => {
+            let p: IdParams = params(p)?;
+            session.pause(p.id).await?;
+            Ok(Value::Null)
+        }
+        "resume" => {
+            let p: IdParams = params(p)?;
+            session.resume(p.id)?;
+            Ok(Value::Null)
+        }
+        "remove" => {
+            let p: RemoveParams = params(p)?;
+            session.remove(p.id, p.delete_data).await?;
+            Ok(Value::Null)
+        }
+        "set_priorities" => {
+            let p: SelectParams = params(p)?;
+            session
+                .set_selection(p.id, p.selection.selection()?)
+                .await?;
+            Ok(Value::Null)
+        }
+        "rate_limits" => {
+            let (download, upload) = session.rate_limits();
+            let (peer_download, peer_upload) = session.peer_rate_limits();
+            Ok(json!({
+                "download": download,
+                "upload": upload,
+                "peer_download": peer_download,
+                "peer_upload": peer_upload,
+            }))
+        }
+        "set_rate_limits" => {
+            let p: RateParams = params(p)?;
+            match (p.id, p.peer) {
+                (Some(id), _) => session.set_torrent_rate_limits(id, p.download, p.upload)?,
+                (None, true) => session.set_peer_rate_limits(p.download, p.upload),
+                (None, false) => session.set_rate_limits(p.download, p.upload),
+            }
+            Ok(Value::Null)
+        }
+        "peers" => {
+            let p: IdParams = params(p)?;
+            Ok(session.peers(p.id)?.iter().map(peer_json).collect())
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("No method {method}"),
+        )),
+    }
+}
+
+// Answers one JSON-RPC request body.
+pub async fn handle_body(session: &Session, body: &[u8]) -> Value {
+    let request: RpcRequest = match serde_json::from_slice::<Value>(body) {
+        Ok(value) => match serde_json::from_value(value) {
+            Ok(request) => request,
+            Err(e) => {
+                return error_json(Value::Null, RpcError::new(INVALID_REQUEST, e.to_string()))
+            }
+        },
+        Err(e) => return error_json(Value::Null, RpcError::new(PARSE_ERROR, e.to_string())),
+    };
+    match dispatch(session, &request.method, request.params).await {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": request.id, "result": result }),
+        Err(e) => error_json(request.id, e),
+    }
+}
+
+fn error_json(id: Value, error: RpcError) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": error.code, "message": error.message },
+    })
+}
+
+// Serves the control API, and the Transmission compatible one next to it. Both want the
+// session id header, which only stops requests forged by web pages: meant for a loopback
+// address.
+pub async fn serve(session: Arc<Session>, listener: TcpListener) -> anyhow::Result<()> {
+    let session_id: Arc<str> = transmission::session_id().into();
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let session = session.clone();
+        let session_id = session_id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(&session, &session_id, stream).await {
+                warn!("RPC {addr}: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle(session: &Session, session_id: &str, stream: TcpStream) -> anyhow::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = Request::read(&mut stream).await?;
+
+    if request.path() == transmission::RPC_PATH {
+        return transmission::handle(session, session_id, &request, &mut stream).await;
+    }
+    if request.path() != RPC_PATH {
+        return http::write_response(&mut stream, 404, "text/plain", b"Not found\n").await;
+    }
+    if !transmission::check_session_id(session_id, &request, &mut stream).await? {
+        return Ok(());
+    }
+    if request.method != "POST" {
+        return http::write_response(&mut stream, 405, "text/plain", b"Method not allowed\n").await;
+    }
+
+    let response = handle_body(session, &request.body).await;
+    http::write_response(
+        &mut stream,
+        200,
+        "application/json",
+        &serde_json::to_vec(&response)?,
+    )
+    .await
+}
+
+// Calls a method of the control API served at `addr`.
+pub async fn call(addr: SocketAddr, method: &str, params: Value) -> anyhow::Result<Value> {
+    let request = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+    let client = reqwest::Client::new();
+    let post = |session_id: &str| {
+        client
+            .post(format!("http://{addr}{RPC_PATH}"))
+            .header(transmission::SESSION_ID_HEADER, session_id)
+            .json(&request)
+            .send()
+    };
+    let mut response = post("")
+        .await
+        .with_context(|| format!("Connecting to the daemon at {addr}"))?;
+    // the first request learns the session id
+    if response.status() == reqwest::StatusCode::CONFLICT {
+        let session_id = response
+            .headers()
+            .get(transmission::SESSION_ID_HEADER)
+            .and_then(|id| id.to_str().ok())
+            .context("Conflict without a session id")?
+            .to_owned();
+        response = post(&session_id).await?;
+    }
+    let response: Value = response.json().await.context("Decoding RPC response")?;
+
+    if let Some(error) = response.get("error") {
+        anyhow::bail!(
+            "{}",
+            error["message"].as_str().unwrap_or("Invalid RPC error")
+        );
+    }
+    Ok(response["result"].clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionOptions;
+    use std::net::Ipv4Addr;
+
+    #[tokio::test]
+    async fn session_id_required() {
+        let options = SessionOptions {
+            listen: (Ipv4Addr::LOCALHOST, 0).into(),
+            utp: false,
+            lsd: false,
+            port_mapping: false,
+            ..Default::default()
+        };
+        let session = Arc::new(Session::new(options).await.unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(session, listener));
+
+        let request = json!({ "jsonrpc": "2.0", "id": 1, "method": "list" });
+        let response = reqwest::Client::new()
+            .post(format!("http://{addr}{RPC_PATH}"))
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::CONFLICT);
+
+        assert_eq!(call(addr, "list", Value::Null).await.unwrap(), json!([]));
+    }
+}
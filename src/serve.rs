@@ -0,0 +1,206 @@
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::debug;
+
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::download::Download;
+use crate::http::{self, Request};
+
+// Serves the files of a download over HTTP while it runs. Each file is found at
+// `/<index>/<path>` or just `/<path>`, reads drive the piece priorities.
+pub async fn serve(download: Arc<Download>, listener: TcpListener) -> anyhow::Result<()> {
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let download = download.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(&download, stream).await {
+                debug!("Serving {addr}: {e:#}");
+            }
+        });
+    }
+}
+
+fn url_path(path: &Path) -> String {
+    path.iter()
+        .map(|c| c.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    match extension.as_str() {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "txt" | "nfo" => "text/plain; charset=utf-8",
+        "html" | "htm" => "text/html; charset=utf-8",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        _ => "application/octet-stream",
+    }
+}
+
+// file index for a request path
+fn find_file(download: &Download, path: &str) -> Option<usize> {
+    let path = http::percent_decode(path.trim_start_matches('/'));
+    let files = &download.layout().files;
+
+    let first = path.split('/').next().unwrap_or_default();
+    if let Ok(index) = first.parse::<usize>() {
+        return (index < files.len()).then_some(index);
+    }
+    files.iter().position(|f| url_path(&f.path) == path)
+}
+
+async fn handle(download: &Download, stream: TcpStream) -> anyhow::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = Request::read(&mut stream).await?;
+
+    if request.method != "GET" && request.method != "HEAD" {
+        return http::write_response(&mut stream, 405, "text/plain", b"Method not allowed\n").await;
+    }
+
+    if request.path() == "/" {
+        let mut index = String::from("<html><body><ul>\n");
+        for (i, file) in download.layout().files.iter().enumerate() {
+            let path = url_path(&file.path);
+            index.push_str(&format!(
+                "<li><a href=\"/{i}/{path}\">{path}</a> ({} bytes)</li>\n",
+                file.length
+            ));
+        }
+        index.push_str("</ul></body></html>\n");
+        return http::write_response(
+            &mut stream,
+            200,
+            "text/html; charset=utf-8",
+            index.as_bytes(),
+        )
+        .await;
+    }
+
+    let Some(file_index) = find_file(download, request.path()) else {
+        return http::write_response(&mut stream, 404, "text/plain", b"Not found\n").await;
+    };
+    let mut reader = download.reader(file_index)?;
+    let length = reader.len();
+    let content_type = content_type(&download.layout().files[file_index].path);
+
+    let (status, start, end) = match request.header("range") {
+        Some(range) => match http::byte_range(range, length) {
+            Some((start, end)) => (206, start, end + 1),
+            None => {
+                http::write_head(
+                    &mut stream,
+                    416,
+                    &[
+                        ("Content-Range", format!("bytes */{length}")),
+                        ("Content-Length", "0".to_owned()),
+                    ],
+                )
+                .await?;
+                return Ok(());
+            }
+        },
+        None => (200, 0, length),
+    };
+
+    let mut headers = vec![
+        ("Content-Type", content_type.to_owned()),
+        ("Content-Length", (end - start).to_string()),
+        ("Accept-Ranges", "bytes".to_owned()),
+    ];
+    if status == 206 {
+        headers.push((
+            "Content-Range",
+            format!("bytes {start}-{}/{length}", end - 1),
+        ));
+    }
+    http::write_head(&mut stream, status, &headers).await?;
+
+    if request.method == "GET" {
+        reader.seek(SeekFrom::Start(start)).await?;
+        tokio::io::copy(&mut reader.take(end - start), &mut stream).await?;
+    }
+    stream.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::{CONTENT_RANGE, RANGE};
+    use reqwest::StatusCode;
+
+    use crate::create::{self, CreateOptions};
+    use crate::download::DownloadOptions;
+
+    #[tokio::test]
+    async fn ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..40000).map(|i| i as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let options = CreateOptions {
+            threads: 1,
+            ..Default::default()
+        };
+        let torrent = create::create_torrent(&path, &options).unwrap();
+        // the data is all there already
+        let options = DownloadOptions {
+            threads: 1,
+            ..Default::default()
+        };
+        let download = Arc::new(Download::new(torrent, &path, &options).unwrap());
+        assert_eq!(download.missing(), 0);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/0/data.bin", listener.local_addr().unwrap());
+        tokio::spawn(serve(download, listener));
+        let client = reqwest::Client::new();
+
+        let response = client.get(&url).send().await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.bytes().await.unwrap(), data);
+
+        // across the first piece boundary
+        let response = client
+            .get(&url)
+            .header(RANGE, "bytes=16380-16399")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 16380-16399/40000");
+        assert_eq!(response.bytes().await.unwrap(), data[16380..16400]);
+
+        let response = client
+            .get(&url)
+            .header(RANGE, "bytes=-10")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes 39990-39999/40000");
+        assert_eq!(response.bytes().await.unwrap(), data[39990..]);
+
+        let response = client
+            .get(&url)
+            .header(RANGE, "bytes=40000-")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(response.headers()[CONTENT_RANGE], "bytes */40000");
+    }
+}
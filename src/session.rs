@@ -0,0 +1,913 @@
+use anyhow::{anyhow, Context};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, error, warn};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::download::{Download, DownloadOptions, Limits, PeerStatus, Progress};
+use crate::lsd::{self, Lsd};
+use crate::magnet::Magnet;
+use crate::mse::{ByteStream, Encryption};
+use crate::net;
+use crate::peer::Peer;
+use crate::port_map::PortMapping;
+use crate::priority::FileSelection;
+use crate::resume;
+use crate::storage::{self, FileLayout};
+use crate::torrent::TorrentFile;
+use crate::utp::{Transport, UtpSocket};
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// how soon a torrent that started is announced to the local network
+const LSD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+pub type TorrentId = usize;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    // loading the resume file or rechecking the data on disk
+    Checking,
+    Downloading,
+    // everything wanted is there, uploading only
+    Seeding,
+    Paused,
+    Error(String),
+}
+
+impl fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TorrentState::Checking => write!(f, "checking"),
+            TorrentState::Downloading => write!(f, "downloading"),
+            TorrentState::Seeding => write!(f, "seeding"),
+            TorrentState::Paused => write!(f, "paused"),
+            TorrentState::Error(e) => write!(f, "error: {e}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    // where peers connect to us, for every torrent. The unspecified IPv4 address takes IPv6
+    // peers too.
+    pub listen: SocketAddr,
+    pub max_connections: usize,
+    // bytes per second, `None` for no limit
+    pub download_rate: Option<u64>,
+    pub upload_rate: Option<u64>,
+    // what every peer gets on its own
+    pub peer_download_rate: Option<u64>,
+    pub peer_upload_rate: Option<u64>,
+    // peers each torrent uploads to at once, unless its own options say
+    pub upload_slots: Option<usize>,
+    // peers each torrent connects to at once, unless its own options say
+    pub max_peers: Option<usize>,
+    // for incoming connections and those of every torrent
+    pub encryption: Encryption,
+    // also take and open uTP connections, on the UDP port of `listen`
+    pub utp: bool,
+    // find peers on the local network, for torrents that aren't private
+    pub lsd: bool,
+    // have the router forward the listen port, and announce the external one
+    pub port_mapping: bool,
+    // where torrents go when added without an output path
+    pub download_dir: PathBuf,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        SessionOptions {
+            listen: (Ipv4Addr::UNSPECIFIED, 6881).into(),
+            max_connections: 200,
+            download_rate: None,
+            upload_rate: None,
+            peer_download_rate: None,
+            peer_upload_rate: None,
+            upload_slots: None,
+            max_peers: None,
+            encryption: Encryption::default(),
+            utp: true,
+            lsd: true,
+            port_mapping: true,
+            download_dir: PathBuf::from("."),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentStatus {
+    pub id: TorrentId,
+    pub name: String,
+    pub info_hash: [u8; 20],
+    pub output: PathBuf,
+    pub state: TorrentState,
+    pub size: u64,
+    pub piece_length: u64,
+    pub pieces: usize,
+    // pieces of the selected files, and those not downloaded yet, `None` until the data is
+    // checked
+    pub wanted: Option<usize>,
+    pub missing: Option<usize>,
+    // bytes per second
+    pub download_limit: Option<u64>,
+    pub upload_limit: Option<u64>,
+    // block bytes received and sent since the torrent was last started
+    pub downloaded: u64,
+    pub uploaded: u64,
+    // bytes per second over the last seconds, zero unless running
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    // time left at that rate, `None` while nothing comes in
+    pub eta: Option<Duration>,
+}
+
+// A torrent of the session, and the task running it.
+struct Entry {
+    id: TorrentId,
+    torrent: TorrentFile,
+    output: PathBuf,
+    options: Mutex<DownloadOptions>,
+    // every info hash peers may know the torrent by
+    info_hashes: Vec<[u8; 20]>,
+    layout: FileLayout,
+    run: Mutex<Run>,
+    // held while the data is checked. Stopping can't cancel a check that runs, the next
+    // start waits for it to end.
+    checking: Arc<tokio::sync::Mutex<()>>,
+}
+
+struct Run {
+    state: TorrentState,
+    download: Option<Arc<Download>>,
+    // peers that connected to us, only while running
+    incoming: Option<mpsc::Sender<Peer>>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Entry {
+    fn set_state(&self, state: TorrentState) {
+        self.run.lock().unwrap().state = state;
+    }
+
+    fn state(&self) -> TorrentState {
+        self.run.lock().unwrap().state.clone()
+    }
+
+    fn status(&self) -> TorrentStatus {
+        let (download_limit, upload_limit) = {
+            let options = self.options.lock().unwrap();
+            (options.download_rate, options.upload_rate)
+        };
+        let run = self.run.lock().unwrap();
+        let (downloaded, uploaded) = run
+            .download
+            .as_ref()
+            .map(|d| d.transferred())
+            .unwrap_or_default();
+        let progress = match &run.download {
+            Some(download) if run.incoming.is_some() => download.progress().borrow().clone(),
+            _ => Progress::default(),
+        };
+        TorrentStatus {
+            id: self.id,
+            name: self.torrent.info.name.clone(),
+            info_hash: self.info_hashes[0],
+            output: self.output.clone(),
+            state: run.state.clone(),
+            size: self.layout.total_length,
+            piece_length: self.layout.piece_length,
+            pieces: self.layout.num_pieces(),
+            wanted: run.download.as_ref().map(|d| d.wanted()),
+            missing: run.download.as_ref().map(|d| d.missing()),
+            download_limit,
+            upload_limit,
+            downloaded,
+            uploaded,
+            download_rate: progress.download_rate,
+            upload_rate: progress.upload_rate,
+            eta: progress.eta,
+        }
+    }
+
+    // Checks the data and then downloads and seeds, in a task of its own.
+    fn start(self: &Arc<Self>) {
+        let (incoming_tx, incoming) = mpsc::channel(8);
+        let entry = self.clone();
+        let task = tokio::spawn(async move {
+            let result = async {
+                let (torrent, output, options) = (
+                    entry.torrent.clone(),
+                    entry.output.clone(),
+                    entry.options.lock().unwrap().clone(),
+                );
+                let checking = entry.checking.clone().lock_owned().await;
+                // the result is dropped with this task when it stops meanwhile
+                let download = tokio::task::spawn_blocking(move || {
+                    let _checking = checking;
+                    Download::new(torrent, &output, &options).map(Arc::new)
+                })
+                .await??;
+                {
+                    let mut run = entry.run.lock().unwrap();
+                    run.download = Some(download.clone());
+                    run.state = TorrentState::Downloading;
+                }
+                download
+                    .seed(incoming, || entry.set_state(TorrentState::Seeding))
+                    .await
+            }
+            .await;
+
+            if let Err(e) = result {
+                error!("Torrent {} stopped: {e:#}", entry.id);
+                entry.set_state(TorrentState::Error(format!("{e:#}")));
+            }
+        });
+
+        let mut run = self.run.lock().unwrap();
+        run.state = TorrentState::Checking;
+        run.incoming = Some(incoming_tx);
+        run.task = Some(task);
+    }
+
+    // Stops the task and writes out what was downloaded.
+    async fn stop(&self) -> anyhow::Result<()> {
+        let (task, download) = {
+            let mut run = self.run.lock().unwrap();
+            run.incoming = None;
+            (run.task.take(), run.download.clone())
+        };
+        if let Some(task) = task {
+            task.abort();
+            let _ = task.await;
+        }
+        match download {
+            Some(download) => download.save(),
+            None => Ok(()),
+        }
+    }
+
+    fn incoming(&self) -> Option<mpsc::Sender<Peer>> {
+        self.run.lock().unwrap().incoming.clone()
+    }
+
+    // once checked, while running
+    fn running(&self) -> Option<Arc<Download>> {
+        let run = self.run.lock().unwrap();
+        run.incoming.as_ref().and(run.download.clone())
+    }
+
+    // Deletes the downloaded files, with the resume and part files, and the directories
+    // left empty.
+    fn delete_data(&self) -> anyhow::Result<()> {
+        let layout = &self.layout;
+        let mut paths = layout.paths(&self.output);
+        paths.push(resume::resume_path(&self.output));
+        paths.push(storage::part_path(&self.output));
+        for path in &paths {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(e).with_context(|| format!("Deleting {}", path.display()));
+                }
+                _ => {}
+            }
+        }
+
+        if layout.is_multi_file() {
+            let mut dirs: Vec<_> = paths
+                .iter()
+                .flat_map(|p| p.ancestors().skip(1))
+                .filter(|d| d.starts_with(&self.output))
+                .collect();
+            dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+            dirs.dedup();
+            for dir in dirs {
+                // only empty ones go
+                let _ = fs::remove_dir(dir);
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Inner {
+    torrents: Mutex<BTreeMap<TorrentId, Arc<Entry>>>,
+    limits: Limits,
+    listen_port: u16,
+    listen_ipv6: bool,
+    port_mapping: Option<PortMapping>,
+    upload_slots: Option<usize>,
+    max_peers: Option<usize>,
+    encryption: Encryption,
+    transport: Transport,
+    download_dir: PathBuf,
+}
+
+impl Inner {
+    fn get(&self, id: TorrentId) -> anyhow::Result<Arc<Entry>> {
+        self.torrents
+            .lock()
+            .unwrap()
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| anyhow!("No torrent {id}"))
+    }
+
+    fn find(&self, info_hash: &[u8; 20]) -> Option<Arc<Entry>> {
+        self.torrents
+            .lock()
+            .unwrap()
+            .values()
+            .find(|e| e.info_hashes.contains(info_hash))
+            .cloned()
+    }
+
+    // of the running torrents
+    fn info_hashes(&self) -> Vec<[u8; 20]> {
+        self.torrents
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|e| e.incoming().is_some())
+            .flat_map(|e| e.info_hashes.clone())
+            .collect()
+    }
+
+    // of the checked running torrents that may be announced to the local network
+    fn lsd_hashes(&self) -> Vec<[u8; 20]> {
+        self.torrents
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|e| !e.torrent.is_private() && e.running().is_some())
+            .flat_map(|e| e.info_hashes.clone())
+            .collect()
+    }
+
+    // Announces the running torrents to the local network, and lists the peers found there
+    // with their torrents.
+    async fn discover(&self, lsd: Lsd) {
+        let mut check = tokio::time::interval(LSD_CHECK_INTERVAL);
+        let mut announced: HashMap<[u8; 20], Instant> = HashMap::new();
+        // those running at the last check, started ones are announced again sooner
+        let mut running = HashSet::new();
+        loop {
+            tokio::select! {
+                _ = check.tick() => {
+                    let hashes = self.lsd_hashes();
+                    let due: Vec<_> = hashes
+                        .iter()
+                        .filter(|h| match announced.get(*h) {
+                            Some(at) if running.contains(*h) => {
+                                at.elapsed() >= lsd::ANNOUNCE_INTERVAL
+                            }
+                            Some(at) => at.elapsed() >= lsd::MIN_ANNOUNCE_INTERVAL,
+                            None => true,
+                        })
+                        .copied()
+                        .collect();
+                    running = hashes.into_iter().collect();
+                    if due.is_empty() {
+                        continue;
+                    }
+                    if let Err(e) = lsd.announce(&due).await {
+                        warn!("Local peer discovery: {e:#}");
+                    }
+                    for info_hash in due {
+                        announced.insert(info_hash, Instant::now());
+                    }
+                }
+                announce = lsd.recv() => match announce {
+                    Ok(announce) => {
+                        for info_hash in announce.info_hashes {
+                            let Some(entry) = self.find(&info_hash) else {
+                                continue;
+                            };
+                            if entry.torrent.is_private() {
+                                continue;
+                            }
+                            if let Some(download) = entry.running() {
+                                download.add_local_peer(announce.addr, info_hash);
+                            }
+                        }
+                    }
+                    Err(e) => warn!("Local peer discovery: {e:#}"),
+                },
+            }
+        }
+    }
+
+    // Hands an incoming connection to the running torrent it asks for.
+    async fn accept(&self, stream: Box<dyn ByteStream>, addr: SocketAddr) -> anyhow::Result<()> {
+        let peer = tokio::time::timeout(
+            HANDSHAKE_TIMEOUT,
+            Peer::accept(
+                stream,
+                addr,
+                self.encryption,
+                &self.info_hashes(),
+                |info_hash| {
+                    self.find(info_hash)
+                        .filter(|e| e.incoming().is_some())
+                        .map(|e| e.torrent.info.is_v2())
+                },
+            ),
+        )
+        .await??;
+        let incoming = self
+            .find(&peer.info_hash)
+            .and_then(|e| e.incoming())
+            .ok_or_else(|| anyhow!("Torrent stopped"))?;
+        incoming
+            .send(peer)
+            .await
+            .map_err(|_| anyhow!("Torrent stopped"))
+    }
+}
+
+// Runs many torrents at once, sharing one listening socket and the connection and bandwidth
+// limits. Torrents are stopped without saving when the session is dropped, see `shutdown`.
+pub struct Session {
+    inner: Arc<Inner>,
+    local_addr: SocketAddr,
+    next_id: Mutex<TorrentId>,
+    listeners: Vec<JoinHandle<()>>,
+    utp_listener: Option<JoinHandle<()>>,
+    lsd: Option<JoinHandle<()>>,
+}
+
+impl Session {
+    pub async fn new(options: SessionOptions) -> anyhow::Result<Self> {
+        let (listeners, listen_ipv6) = net::bind_dual_stack(options.listen).await?;
+        let local_addr = listeners[0].local_addr()?;
+        // same port for both, peers learn only one from trackers. Where the families need
+        // sockets of their own uTP stays on the one asked for.
+        let utp = if options.utp {
+            let utp_addr = match listeners.len() {
+                1 => local_addr,
+                _ => SocketAddr::new(options.listen.ip(), local_addr.port()),
+            };
+            Some(Arc::new(UtpSocket::bind(utp_addr).await?))
+        } else {
+            None
+        };
+        let limits = Limits::new(
+            options.max_connections,
+            options.download_rate,
+            options.upload_rate,
+        );
+        limits.peer_download.set(options.peer_download_rate);
+        limits.peer_upload.set(options.peer_upload_rate);
+        let inner = Arc::new(Inner {
+            torrents: Mutex::new(BTreeMap::new()),
+            limits,
+            listen_port: local_addr.port(),
+            listen_ipv6,
+            port_mapping: options
+                .port_mapping
+                .then(|| PortMapping::start(local_addr.port())),
+            upload_slots: options.upload_slots,
+            max_peers: options.max_peers,
+            encryption: options.encryption,
+            transport: match &utp {
+                Some(socket) => Transport::Utp(socket.clone()),
+                None => Transport::Tcp,
+            },
+            download_dir: options.download_dir,
+        });
+
+        let listeners = listeners
+            .into_iter()
+            .map(|listener| {
+                let accepting = inner.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let (stream, addr) = match listener.accept().await {
+                            Ok((stream, addr)) => (stream, net::canonical(addr)),
+                            Err(e) => {
+                                warn!("Accept: {e:#}");
+                                continue;
+                            }
+                        };
+                        let inner = accepting.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = inner.accept(Box::new(stream), addr).await {
+                                debug!("Incoming peer {addr}: {e:#}");
+                            }
+                        });
+                    }
+                })
+            })
+            .collect();
+        let utp_listener = utp.map(|socket| {
+            let accepting = inner.clone();
+            tokio::spawn(async move {
+                while let Ok(stream) = socket.accept().await {
+                    let inner = accepting.clone();
+                    let addr = stream.peer_addr();
+                    tokio::spawn(async move {
+                        if let Err(e) = inner.accept(Box::new(stream), addr).await {
+                            debug!("Incoming uTP peer {addr}: {e:#}");
+                        }
+                    });
+                }
+            })
+        });
+
+        let lsd = if options.lsd {
+            let lsd = Lsd::bind(local_addr.port()).await?;
+            let inner = inner.clone();
+            Some(tokio::spawn(async move { inner.discover(lsd).await }))
+        } else {
+            None
+        };
+
+        Ok(Session {
+            inner,
+            local_addr,
+            next_id: Mutex::new(0),
+            listeners,
+            utp_listener,
+            lsd,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn download_dir(&self) -> &Path {
+        &self.inner.download_dir
+    }
+
+    pub fn find(&self, info_hash: &[u8; 20]) -> Option<TorrentId> {
+        self.inner.find(info_hash).map(|e| e.id)
+    }
+
+    // Adds a torrent and starts it. The session's listen port, the one forwarded by the router
+    // once mapped, and limits replace the ones in `options`.
+    pub fn add(
+        &self,
+        torrent: TorrentFile,
+        output: &Path,
+        mut options: DownloadOptions,
+    ) -> anyhow::Result<TorrentId> {
+        let info_hashes = torrent.info.swarm_hashes()?;
+        let layout = FileLayout::new(&torrent.info)?;
+        let port_mapping = self.inner.port_mapping.as_ref();
+        options.listen_port = port_mapping
+            .and_then(|m| m.external_port())
+            .or(Some(self.inner.listen_port));
+        options.listen_ipv6 = self.inner.listen_ipv6;
+        options.limits = self.inner.limits.clone();
+        options.upload_slots = options.upload_slots.or(self.inner.upload_slots);
+        options.max_peers = options.max_peers.or(self.inner.max_peers);
+        options.encryption = self.inner.encryption;
+        options.transport = self.inner.transport.clone();
+
+        // checked and added under one lock, so that the same torrent isn't added twice at once
+        let mut torrents = self.inner.torrents.lock().unwrap();
+        if let Some(entry) = torrents
+            .values()
+            .find(|e| e.info_hashes.iter().any(|h| info_hashes.contains(h)))
+        {
+            anyhow::bail!("Torrent already added as {}", entry.id);
+        }
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id
+        };
+        let entry = Arc::new(Entry {
+            id,
+            torrent,
+            output: output.to_owned(),
+            options: Mutex::new(options),
+            info_hashes,
+            layout,
+            run: Mutex::new(Run {
+                state: TorrentState::Checking,
+                download: None,
+                incoming: None,
+                task: None,
+            }),
+            checking: Arc::new(tokio::sync::Mutex::new(())),
+        });
+        entry.start();
+        torrents.insert(id, entry);
+        Ok(id)
+    }
+
+    // The torrent of a magnet link, its metadata fetched from the swarm.
+    pub async fn fetch_torrent(&self, magnet: &Magnet) -> anyhow::Result<TorrentFile> {
+        magnet
+            .fetch_torrent(
+                self.inner.listen_port,
+                &self.inner.transport,
+                self.inner.encryption,
+            )
+            .await
+    }
+
+    // Stops the torrent and forgets it, deleting its data too when asked.
+    pub async fn remove(&self, id: TorrentId, delete_data: bool) -> anyhow::Result<()> {
+        let entry = self.inner.get(id)?;
+        self.inner.torrents.lock().unwrap().remove(&id);
+        entry.stop().await?;
+        if delete_data {
+            entry.delete_data()?;
+        }
+        Ok(())
+    }
+
+    pub async fn pause(&self, id: TorrentId) -> anyhow::Result<()> {
+        let entry = self.inner.get(id)?;
+        let result = entry.stop().await;
+        entry.set_state(TorrentState::Paused);
+        result
+    }
+
+    // Restarts a paused or failed torrent, from its resume file.
+    pub fn resume(&self, id: TorrentId) -> anyhow::Result<()> {
+        let entry = self.inner.get(id)?;
+        match entry.state() {
+            TorrentState::Paused | TorrentState::Error(_) => entry.start(),
+            _ => anyhow::bail!("Torrent {id} is running"),
+        }
+        Ok(())
+    }
+
+    // Changes which files are downloaded and their priorities. A running torrent restarts, so
+    // that skipped files move in or out of the part file.
+    pub async fn set_selection(
+        &self,
+        id: TorrentId,
+        selection: FileSelection,
+    ) -> anyhow::Result<()> {
+        let entry = self.inner.get(id)?;
+        entry.options.lock().unwrap().selection = selection;
+        match entry.state() {
+            TorrentState::Paused | TorrentState::Error(_) => Ok(()),
+            _ => {
+                entry.stop().await?;
+                entry.start();
+                Ok(())
+            }
+        }
+    }
+
+    // Session wide rates in bytes per second, `None` for no limit. They apply right away.
+    pub fn rate_limits(&self) -> (Option<u64>, Option<u64>) {
+        let limits = &self.inner.limits;
+        (limits.download.rate(), limits.upload.rate())
+    }
+
+    pub fn set_rate_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.inner.limits.download.set_rate(download);
+        self.inner.limits.upload.set_rate(upload);
+    }
+
+    pub fn peer_rate_limits(&self) -> (Option<u64>, Option<u64>) {
+        let limits = &self.inner.limits;
+        (limits.peer_download.get(), limits.peer_upload.get())
+    }
+
+    pub fn set_peer_rate_limits(&self, download: Option<u64>, upload: Option<u64>) {
+        self.inner.limits.peer_download.set(download);
+        self.inner.limits.peer_upload.set(upload);
+    }
+
+    pub fn set_torrent_rate_limits(
+        &self,
+        id: TorrentId,
+        download: Option<u64>,
+        upload: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let entry = self.inner.get(id)?;
+        {
+            let mut options = entry.options.lock().unwrap();
+            options.download_rate = download;
+            options.upload_rate = upload;
+        }
+        if let Some(running) = &entry.run.lock().unwrap().download {
+            running.set_rate_limits(download, upload);
+        }
+        Ok(())
+    }
+
+    pub fn status(&self, id: TorrentId) -> anyhow::Result<TorrentStatus> {
+        Ok(self.inner.get(id)?.status())
+    }
+
+    pub fn list(&self) -> Vec<TorrentStatus> {
+        let torrents = self.inner.torrents.lock().unwrap();
+        torrents.values().map(|e| e.status()).collect()
+    }
+
+    pub fn peers(&self, id: TorrentId) -> anyhow::Result<Vec<PeerStatus>> {
+        let entry = self.inner.get(id)?;
+        let run = entry.run.lock().unwrap();
+        Ok(match &run.download {
+            Some(download) if run.incoming.is_some() => download.peers(),
+            _ => vec![],
+        })
+    }
+
+    // The download of a torrent once checked, for readers.
+    pub fn download(&self, id: TorrentId) -> anyhow::Result<Arc<Download>> {
+        self.inner
+            .get(id)?
+            .run
+            .lock()
+            .unwrap()
+            .download
+            .clone()
+            .ok_or_else(|| anyhow!("Torrent {id} is not checked yet"))
+    }
+
+    // Stops accepting peers and pauses every torrent, saving their state. The router stops
+    // forwarding the port.
+    pub async fn shutdown(&self) -> anyhow::Result<()> {
+        for listener in &self.listeners {
+            listener.abort();
+        }
+        if let Some(utp_listener) = &self.utp_listener {
+            utp_listener.abort();
+        }
+        if let Some(lsd) = &self.lsd {
+            lsd.abort();
+        }
+        let entries: Vec<_> = self
+            .inner
+            .torrents
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        for entry in entries {
+            entry.stop().await?;
+            entry.set_state(TorrentState::Paused);
+        }
+        if let Some(port_mapping) = &self.inner.port_mapping {
+            port_mapping.remove().await?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for listener in &self.listeners {
+            listener.abort();
+        }
+        if let Some(utp_listener) = &self.utp_listener {
+            utp_listener.abort();
+        }
+        if let Some(lsd) = &self.lsd {
+            lsd.abort();
+        }
+        for entry in self.inner.torrents.lock().unwrap().values() {
+            if let Some(task) = entry.run.lock().unwrap().task.take() {
+                task.abort();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::create::{self, CreateOptions};
+
+    async fn session() -> Arc<Session> {
+        let options = SessionOptions {
+            listen: (Ipv4Addr::LOCALHOST, 0).into(),
+            utp: false,
+            lsd: false,
+            port_mapping: false,
+            ..Default::default()
+        };
+        Arc::new(Session::new(options).await.unwrap())
+    }
+
+    // a torrent of a file that is all there
+    fn torrent(dir: &Path) -> (TorrentFile, PathBuf) {
+        create(dir, "data.bin", false)
+    }
+
+    fn create(dir: &Path, name: &str, private: bool) -> (TorrentFile, PathBuf) {
+        let path = dir.join(name);
+        std::fs::write(&path, name.repeat(10_000)).unwrap();
+        let options = CreateOptions {
+            threads: 1,
+            private,
+            ..Default::default()
+        };
+        (create::create_torrent(&path, &options).unwrap(), path)
+    }
+
+    async fn wait_for(mut done: impl FnMut() -> bool) {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    fn options() -> DownloadOptions {
+        DownloadOptions {
+            threads: 1,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn add_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, path) = torrent(dir.path());
+        let session = session().await;
+
+        let adds: Vec<_> = (0..8)
+            .map(|_| {
+                let (session, torrent, path) = (session.clone(), torrent.clone(), path.clone());
+                tokio::task::spawn_blocking(move || session.add(torrent, &path, options()))
+            })
+            .collect();
+        let mut added = 0;
+        for add in adds {
+            added += add.await.unwrap().is_ok() as usize;
+        }
+        assert_eq!(added, 1);
+        assert_eq!(session.list().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pause_while_checking() {
+        let dir = tempfile::tempdir().unwrap();
+        let (torrent, path) = torrent(dir.path());
+        let session = session().await;
+
+        let id = session.add(torrent, &path, options()).unwrap();
+        session.pause(id).await.unwrap();
+        assert_eq!(session.status(id).unwrap().state, TorrentState::Paused);
+        session.resume(id).unwrap();
+        wait_for(|| session.status(id).unwrap().state == TorrentState::Seeding).await;
+        assert_eq!(session.status(id).unwrap().missing, Some(0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn local_peers() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = session().await;
+        let mut ids = vec![];
+        for (name, private) in [("public", false), ("private", true)] {
+            let (torrent, path) = create(dir.path(), name, private);
+            ids.push(session.add(torrent, &path, options()).unwrap());
+        }
+        let entries: Vec<_> = ids
+            .iter()
+            .map(|&id| session.inner.get(id).unwrap())
+            .collect();
+        wait_for(|| entries.iter().all(|e| e.running().is_some())).await;
+        let (public, private) = (&entries[0], &entries[1]);
+
+        // only the public torrent is announced
+        assert_eq!(session.inner.lsd_hashes(), public.info_hashes);
+
+        let lsd_port = tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let lsd = Lsd::bind_at(session.local_addr.port(), lsd_port)
+            .await
+            .unwrap();
+        let inner = session.inner.clone();
+        tokio::spawn(async move { inner.discover(lsd).await });
+
+        // another client, which can only announce with the port taken
+        let other = Lsd::bind_at(7000, lsd_port).await.unwrap();
+        let hashes = [public.info_hashes[0], private.info_hashes[0]];
+        other.announce(&hashes).await.unwrap();
+
+        let known_peers = |entry: &Entry| entry.running().unwrap().progress().borrow().known_peers;
+        // from the IPv4 group, and the IPv6 one where there is one
+        wait_for(|| known_peers(public) > 0).await;
+        tokio::time::sleep(2 * crate::download::PROGRESS_INTERVAL).await;
+        assert_eq!(known_peers(private), 0);
+    }
+}
@@ -0,0 +1,627 @@
+use anyhow::{anyhow, Context};
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use crate::torrent::TorrentInfo;
+
+// Backend for torrent data, addressed by file index and byte offset within the file.
+pub trait Storage: Send {
+    fn num_files(&self) -> usize;
+
+    fn file_size(&mut self, file_index: usize) -> anyhow::Result<u64>;
+
+    fn set_file_size(&mut self, file_index: usize, size: u64) -> anyhow::Result<()>;
+
+    fn read_block(&mut self, file_index: usize, offset: u64, buf: &mut [u8]) -> anyhow::Result<()>;
+
+    fn write_block(&mut self, file_index: usize, offset: u64, data: &[u8]) -> anyhow::Result<()>;
+
+    fn flush(&mut self) -> anyhow::Result<()>;
+}
+
+pub struct FsStorage {
+    paths: Vec<PathBuf>,
+    files: Vec<Option<File>>,
+    read_only: bool,
+}
+
+impl FsStorage {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        let files = paths.iter().map(|_| None).collect();
+        FsStorage {
+            paths,
+            files,
+            read_only: false,
+        }
+    }
+
+    // never creates or modifies files, used to check existing data
+    pub fn read_only(paths: Vec<PathBuf>) -> Self {
+        FsStorage {
+            read_only: true,
+            ..FsStorage::new(paths)
+        }
+    }
+
+    // single file torrents are written to `output`, multi file torrents below the `output`
+    // directory
+    pub fn for_torrent(info: &TorrentInfo, output: &Path) -> anyhow::Result<Self> {
+        Ok(FsStorage::new(FileLayout::new(info)?.paths(output)))
+    }
+
+    fn file(&mut self, file_index: usize) -> anyhow::Result<&File> {
+        let path = self
+            .paths
+            .get(file_index)
+            .ok_or_else(|| anyhow!("Invalid file index {file_index}"))?;
+
+        if self.files[file_index].is_none() {
+            if self.read_only {
+                let file =
+                    File::open(path).with_context(|| format!("Opening {}", path.display()))?;
+                self.files[file_index] = Some(file);
+                return Ok(self.files[file_index].as_ref().unwrap());
+            }
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("Creating directory {}", parent.display()))?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .with_context(|| format!("Opening {}", path.display()))?;
+            self.files[file_index] = Some(file);
+        }
+
+        Ok(self.files[file_index].as_ref().unwrap())
+    }
+}
+
+impl Storage for FsStorage {
+    fn num_files(&self) -> usize {
+        self.paths.len()
+    }
+
+    fn file_size(&mut self, file_index: usize) -> anyhow::Result<u64> {
+        match std::fs::metadata(&self.paths[file_index]) {
+            Ok(meta) => Ok(meta.len()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set_file_size(&mut self, file_index: usize, size: u64) -> anyhow::Result<()> {
+        self.file(file_index)?.set_len(size)?;
+        Ok(())
+    }
+
+    fn read_block(&mut self, file_index: usize, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        self.file(file_index)?
+            .read_exact_at(buf, offset)
+            .with_context(|| format!("Reading file {file_index} at {offset}"))
+    }
+
+    fn write_block(&mut self, file_index: usize, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        self.file(file_index)?
+            .write_all_at(data, offset)
+            .with_context(|| format!("Writing file {file_index} at {offset}"))
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        for file in self.files.iter().flatten() {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MemStorage {
+    files: Vec<Vec<u8>>,
+}
+
+impl MemStorage {
+    pub fn new(num_files: usize) -> Self {
+        MemStorage {
+            files: vec![Vec::new(); num_files],
+        }
+    }
+
+    pub fn into_inner(self) -> Vec<Vec<u8>> {
+        self.files
+    }
+}
+
+impl Storage for MemStorage {
+    fn num_files(&self) -> usize {
+        self.files.len()
+    }
+
+    fn file_size(&mut self, file_index: usize) -> anyhow::Result<u64> {
+        Ok(self.files[file_index].len() as u64)
+    }
+
+    fn set_file_size(&mut self, file_index: usize, size: u64) -> anyhow::Result<()> {
+        self.files[file_index].resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn read_block(&mut self, file_index: usize, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        let file = &self.files[file_index];
+        let start = offset as usize;
+        anyhow::ensure!(start + buf.len() <= file.len(), "Read past end of file {file_index}");
+        buf.copy_from_slice(&file[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_block(&mut self, file_index: usize, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        let file = &mut self.files[file_index];
+        let start = offset as usize;
+        if file.len() < start + data.len() {
+            file.resize(start + data.len(), 0);
+        }
+        file[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn part_path(output: &Path) -> PathBuf {
+    let mut name = output.as_os_str().to_owned();
+    name.push(".parts");
+    PathBuf::from(name)
+}
+
+// Keeps the data of skipped files out of the download. Pieces shared with wanted files
+// still need it to be verified, so it goes to a sparse part file at the offset the file
+// has in the torrent data.
+pub struct PartStorage {
+    inner: Box<dyn Storage>,
+    skipped: Vec<bool>,
+    offsets: Vec<u64>,
+    path: PathBuf,
+    part: Option<File>,
+}
+
+impl PartStorage {
+    pub fn new(
+        inner: Box<dyn Storage>,
+        layout: &FileLayout,
+        skipped: Vec<bool>,
+        path: PathBuf,
+    ) -> Self {
+        PartStorage {
+            inner,
+            skipped,
+            offsets: layout.files.iter().map(|f| f.offset).collect(),
+            path,
+            part: None,
+        }
+    }
+
+    fn part(&mut self) -> anyhow::Result<&File> {
+        if self.part.is_none() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&self.path)
+                .with_context(|| format!("Opening {}", self.path.display()))?;
+            self.part = Some(file);
+        }
+        Ok(self.part.as_ref().unwrap())
+    }
+
+    // Moves what the part file has of files that are wanted now but were skipped before.
+    pub fn restore(&mut self, layout: &FileLayout) -> anyhow::Result<()> {
+        if !self.path.exists() {
+            return Ok(());
+        }
+        for (file_index, file) in layout.files.iter().enumerate() {
+            if self.skipped[file_index] || file.length == 0 || self.inner.file_size(file_index)? > 0
+            {
+                continue;
+            }
+            let mut buf = vec![0; file.length as usize];
+            let part_len = self.part()?.metadata()?.len();
+            let available = part_len.saturating_sub(file.offset).min(file.length) as usize;
+            self.part()?
+                .read_exact_at(&mut buf[..available], file.offset)?;
+            self.inner.write_block(file_index, 0, &buf)?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for PartStorage {
+    fn num_files(&self) -> usize {
+        self.inner.num_files()
+    }
+
+    fn file_size(&mut self, file_index: usize) -> anyhow::Result<u64> {
+        self.inner.file_size(file_index)
+    }
+
+    fn set_file_size(&mut self, file_index: usize, size: u64) -> anyhow::Result<()> {
+        if self.skipped[file_index] {
+            return Ok(());
+        }
+        self.inner.set_file_size(file_index, size)
+    }
+
+    fn read_block(&mut self, file_index: usize, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        if !self.skipped[file_index] {
+            return self.inner.read_block(file_index, offset, buf);
+        }
+        if !self.path.exists() {
+            buf.fill(0);
+            return Ok(());
+        }
+        let start = self.offsets[file_index] + offset;
+        let part = self.part()?;
+        // holes past the end of the part file read as zeros
+        let available = part
+            .metadata()?
+            .len()
+            .saturating_sub(start)
+            .min(buf.len() as u64);
+        buf[available as usize..].fill(0);
+        part.read_exact_at(&mut buf[..available as usize], start)
+            .with_context(|| format!("Reading part file at {start}"))
+    }
+
+    fn write_block(&mut self, file_index: usize, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        if !self.skipped[file_index] {
+            return self.inner.write_block(file_index, offset, data);
+        }
+        let start = self.offsets[file_index] + offset;
+        self.part()?
+            .write_all_at(data, start)
+            .with_context(|| format!("Writing part file at {start}"))
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if let Some(part) = &self.part {
+            part.sync_data()?;
+        }
+        self.inner.flush()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileSlot {
+    pub path: PathBuf,
+    pub length: u64,
+    // offset of the file inside the concatenated torrent data
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    // offset in the torrent data
+    pub offset: u64,
+    pub file_index: usize,
+    pub file_offset: u64,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+    pub files: Vec<FileSlot>,
+    pub piece_length: u64,
+    pub total_length: u64,
+    multi_file: bool,
+}
+
+impl FileLayout {
+    pub fn new(info: &TorrentInfo) -> anyhow::Result<Self> {
+        anyhow::ensure!(info.piece_length > 0, "Invalid piece length 0");
+        let files = info
+            .file_slots()?
+            .into_iter()
+            .map(|(path, length, offset)| FileSlot {
+                path,
+                length: length as u64,
+                offset,
+            })
+            .collect();
+
+        Ok(FileLayout {
+            files,
+            piece_length: info.piece_length as u64,
+            total_length: info.piece_space_length()? as u64,
+            multi_file: info.is_multi_file(),
+        })
+    }
+
+    pub fn from_files(files: Vec<(PathBuf, u64)>, piece_length: u64, multi_file: bool) -> Self {
+        let mut offset = 0;
+        let files = files
+            .into_iter()
+            .map(|(path, length)| {
+                let slot = FileSlot {
+                    path,
+                    length,
+                    offset,
+                };
+                offset += length;
+                slot
+            })
+            .collect();
+
+        FileLayout {
+            files,
+            piece_length,
+            total_length: offset,
+            multi_file,
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.total_length.div_ceil(self.piece_length) as usize
+    }
+
+    pub fn is_multi_file(&self) -> bool {
+        self.multi_file
+    }
+
+    pub fn paths(&self, output: &Path) -> Vec<PathBuf> {
+        if self.multi_file {
+            self.files.iter().map(|f| output.join(&f.path)).collect()
+        } else {
+            vec![output.to_owned()]
+        }
+    }
+
+    pub fn piece_offset(&self, piece_index: usize) -> u64 {
+        piece_index as u64 * self.piece_length
+    }
+
+    pub fn piece_len(&self, piece_index: usize) -> usize {
+        let start = self.piece_offset(piece_index);
+        std::cmp::min(self.piece_length, self.total_length.saturating_sub(start)) as usize
+    }
+
+    // bytes of file data in a piece, without trailing padding
+    pub fn piece_data_len(&self, piece_index: usize) -> usize {
+        let start = self.piece_offset(piece_index);
+        self.piece_spans(piece_index)
+            .last()
+            .map(|span| (span.offset + span.len as u64 - start) as usize)
+            .unwrap_or(0)
+    }
+
+    // file regions covered by `len` bytes starting at `offset` in the torrent data
+    pub fn spans(&self, offset: u64, len: usize) -> Vec<Span> {
+        let end = offset + len as u64;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| f.length > 0 && f.offset < end && f.offset + f.length > offset)
+            .map(|(file_index, f)| {
+                let start = std::cmp::max(offset, f.offset);
+                let stop = std::cmp::min(end, f.offset + f.length);
+                Span {
+                    offset: start,
+                    file_index,
+                    file_offset: start - f.offset,
+                    len: (stop - start) as usize,
+                }
+            })
+            .collect()
+    }
+
+    pub fn piece_spans(&self, piece_index: usize) -> Vec<Span> {
+        self.spans(self.piece_offset(piece_index), self.piece_len(piece_index))
+    }
+}
+
+// Maps pieces onto the files of a torrent and forwards the IO to a `Storage`.
+pub struct PieceStore {
+    pub layout: FileLayout,
+    storage: Box<dyn Storage>,
+}
+
+impl PieceStore {
+    pub fn new(layout: FileLayout, storage: Box<dyn Storage>) -> Self {
+        PieceStore { layout, storage }
+    }
+
+    pub fn allocate(&mut self) -> anyhow::Result<()> {
+        for (file_index, file) in self.layout.files.iter().enumerate() {
+            // empty files are set too, so that they get created
+            if file.length == 0 || self.storage.file_size(file_index)? != file.length {
+                self.storage.set_file_size(file_index, file.length)?;
+            }
+        }
+        Ok(())
+    }
+
+    // gaps between files read as zeros
+    pub fn read(&mut self, offset: u64, buf: &mut [u8]) -> anyhow::Result<()> {
+        buf.fill(0);
+        for span in self.layout.spans(offset, buf.len()) {
+            let pos = (span.offset - offset) as usize;
+            self.storage
+                .read_block(span.file_index, span.file_offset, &mut buf[pos..pos + span.len])?;
+        }
+        Ok(())
+    }
+
+    // writes to gaps between files are dropped
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+        for span in self.layout.spans(offset, data.len()) {
+            let pos = (span.offset - offset) as usize;
+            self.storage
+                .write_block(span.file_index, span.file_offset, &data[pos..pos + span.len])?;
+        }
+        Ok(())
+    }
+
+    pub fn read_piece(&mut self, piece_index: usize) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0; self.layout.piece_data_len(piece_index)];
+        self.read(self.layout.piece_offset(piece_index), &mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn write_piece(&mut self, piece_index: usize, data: &[u8]) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            data.len() == self.layout.piece_data_len(piece_index),
+            "Invalid length {} for piece {piece_index}",
+            data.len()
+        );
+        self.write(self.layout.piece_offset(piece_index), data)
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.storage.flush()
+    }
+
+    pub fn storage(&mut self) -> &mut dyn Storage {
+        self.storage.as_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // files of 5, 0 and 12 bytes in pieces of 4: the last piece is short
+    fn layout() -> FileLayout {
+        FileLayout::from_files(
+            vec![("a".into(), 5), ("b".into(), 0), ("c".into(), 12)],
+            4,
+            true,
+        )
+    }
+
+    fn store() -> PieceStore {
+        let mut store = PieceStore::new(layout(), Box::new(MemStorage::new(3)));
+        store.allocate().unwrap();
+        store
+    }
+
+    fn file(store: &mut PieceStore, file_index: usize) -> Vec<u8> {
+        let len = store.storage().file_size(file_index).unwrap() as usize;
+        let mut buf = vec![0; len];
+        store.storage().read_block(file_index, 0, &mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn pieces() {
+        let layout = layout();
+        assert_eq!(layout.total_length, 17);
+        assert_eq!(layout.num_pieces(), 5);
+        assert_eq!(layout.piece_len(0), 4);
+        assert_eq!(layout.piece_len(4), 1);
+        assert_eq!(layout.piece_data_len(4), 1);
+    }
+
+    #[test]
+    fn piece_across_files() {
+        let layout = layout();
+        // bytes 4..8: the last of a, nothing of the empty b, three of c
+        assert_eq!(
+            layout.piece_spans(1),
+            vec![
+                Span {
+                    offset: 4,
+                    file_index: 0,
+                    file_offset: 4,
+                    len: 1,
+                },
+                Span {
+                    offset: 5,
+                    file_index: 2,
+                    file_offset: 0,
+                    len: 3,
+                },
+            ]
+        );
+        assert_eq!(
+            layout.piece_spans(4),
+            vec![Span {
+                offset: 16,
+                file_index: 2,
+                file_offset: 11,
+                len: 1,
+            }]
+        );
+    }
+
+    #[test]
+    fn write_and_read_pieces() {
+        let mut store = store();
+        let data: Vec<u8> = (0..17).collect();
+        for piece_index in 0..store.layout.num_pieces() {
+            let offset = store.layout.piece_offset(piece_index) as usize;
+            let len = store.layout.piece_data_len(piece_index);
+            store
+                .write_piece(piece_index, &data[offset..offset + len])
+                .unwrap();
+        }
+
+        assert_eq!(file(&mut store, 0), data[..5]);
+        assert!(file(&mut store, 1).is_empty());
+        assert_eq!(file(&mut store, 2), data[5..]);
+        assert_eq!(store.read_piece(1).unwrap(), data[4..8]);
+        assert_eq!(store.read_piece(4).unwrap(), data[16..]);
+    }
+
+    #[test]
+    fn wrong_piece_length() {
+        let mut store = store();
+        assert!(store.write_piece(4, &[0; 4]).is_err());
+    }
+
+    #[test]
+    fn skipped_file_goes_to_part_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let part = dir.path().join("out.parts");
+        let data: Vec<u8> = (0..17).collect();
+
+        // a is skipped, piece 1 still needs its last byte
+        let storage = PartStorage::new(
+            Box::new(MemStorage::new(3)),
+            &layout(),
+            vec![true, false, false],
+            part.clone(),
+        );
+        let mut store = PieceStore::new(layout(), Box::new(storage));
+        store.allocate().unwrap();
+        for piece_index in 0..store.layout.num_pieces() {
+            let offset = store.layout.piece_offset(piece_index) as usize;
+            let len = store.layout.piece_data_len(piece_index);
+            store
+                .write_piece(piece_index, &data[offset..offset + len])
+                .unwrap();
+        }
+
+        assert_eq!(store.storage().file_size(0).unwrap(), 0);
+        assert_eq!(file(&mut store, 2), data[5..]);
+        assert_eq!(store.read_piece(1).unwrap(), data[4..8]);
+        // at the offset a has in the torrent data
+        assert_eq!(std::fs::read(&part).unwrap(), data[..5]);
+
+        // wanted again: a comes back from the part file
+        let mut storage = PartStorage::new(
+            Box::new(MemStorage::new(3)),
+            &layout(),
+            vec![false, false, false],
+            part,
+        );
+        storage.restore(&layout()).unwrap();
+        let mut store = PieceStore::new(layout(), Box::new(storage));
+        assert_eq!(file(&mut store, 0), data[..5]);
+    }
+}
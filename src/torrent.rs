@@ -1,26 +1,84 @@
 use serde::{Deserialize, Serialize};
 use anyhow::{anyhow, Context};
+use serde_bencode::value::Value;
+use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::merkle;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileEntry {
+    pub length: usize,
+    pub path: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<String>,
+
+    // BEP 47 attributes, `p` marks padding files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attr: Option<String>,
+}
+
+impl FileEntry {
+    pub fn is_padding(&self) -> bool {
+        self.attr.as_ref().is_some_and(|a| a.contains('p'))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct TorrentInfo {
     pub name: String,
 
     #[serde(rename = "piece length")]
     pub piece_length: usize,
 
-    #[serde(with = "serde_bytes")]
+    // v1 SHA-1 piece hashes, absent in v2 only torrents
+    #[serde(default, with = "serde_bytes", skip_serializing_if = "Vec::is_empty")]
     pub pieces: Vec<u8>,
 
-    pub length: usize,
+    // single file mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length: Option<usize>,
+
+    // multi file mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub files: Option<Vec<FileEntry>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub md5sum: Option<String>,
+
+    #[serde(
+        default,
+        rename = "meta version",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub meta_version: Option<u8>,
+
+    // BEP 52 nested directories, files are dicts under an empty key
+    #[serde(default, rename = "file tree", skip_serializing_if = "Option::is_none")]
+    pub file_tree: Option<Value>,
+
+    // bencoded info dict as found in the torrent file, keys we don't model are kept in the hash
+    #[serde(skip)]
+    pub(crate) raw: Option<Vec<u8>>,
 }
 
 impl TorrentInfo {
     pub fn piece_hashes(&self) -> anyhow::Result<Vec<&[u8; 20]>> {
-        if self.pieces.len() % 20 == 0 {
+        if self.pieces.len().is_multiple_of(20) {
             self.pieces
                 .chunks_exact(20)
-                .map(|c| <&[u8; 20]>::try_from(c))
+                .map(<&[u8; 20]>::try_from)
                 .collect::<Result<Vec<_>, _>>()
                 .map_err(|e| anyhow!(e))
                 .context("Extracting hashes")
@@ -30,17 +88,485 @@ impl TorrentInfo {
     }
 
     pub fn hash(&self) -> anyhow::Result<[u8; 20]> {
-        let info = serde_bencode::to_bytes(&self)?;
+        let info = match &self.raw {
+            Some(raw) => raw.clone(),
+            None => serde_bencode::to_bytes(&self)?,
+        };
         let mut hasher = Sha1::new();
         hasher.update(&info);
         let hashed_info = hasher.finalize();
 
         hashed_info[..].try_into().map_err(|e| anyhow!("{}", e))
     }
+
+    pub fn hash_v2(&self) -> anyhow::Result<[u8; 32]> {
+        let info = match &self.raw {
+            Some(raw) => raw.clone(),
+            None => serde_bencode::to_bytes(&self)?,
+        };
+        Ok(Sha256::digest(&info).into())
+    }
+
+    // v2 info hash truncated to 20 bytes, as used by trackers and in handshakes
+    pub fn hash_v2_truncated(&self) -> anyhow::Result<[u8; 20]> {
+        Ok(self.hash_v2()?[..20].try_into()?)
+    }
+
+    // the info hash peers and trackers know this torrent by
+    pub fn wire_hash(&self) -> anyhow::Result<[u8; 20]> {
+        if self.is_v1() {
+            self.hash()
+        } else {
+            self.hash_v2_truncated()
+        }
+    }
+
+    // hybrid torrents live in two swarms, the v1 one and the truncated v2 one
+    pub fn swarm_hashes(&self) -> anyhow::Result<Vec<[u8; 20]>> {
+        let mut hashes = vec![self.wire_hash()?];
+        if self.is_hybrid() {
+            hashes.push(self.hash_v2_truncated()?);
+        }
+        Ok(hashes)
+    }
+
+    pub fn is_v1(&self) -> bool {
+        !self.pieces.is_empty()
+    }
+
+    pub fn is_v2(&self) -> bool {
+        self.meta_version == Some(2) && self.file_tree.is_some()
+    }
+
+    pub fn is_hybrid(&self) -> bool {
+        self.is_v1() && self.is_v2()
+    }
+
+    // files of the v2 file tree, in tree order
+    pub fn v2_files(&self) -> anyhow::Result<Vec<V2File>> {
+        let mut files = vec![];
+        if let Some(tree) = &self.file_tree {
+            collect_v2_files(tree, &mut vec![], &mut files)?;
+        }
+        Ok(files)
+    }
+
+    // length of the data, without padding
+    pub fn total_length(&self) -> usize {
+        match (&self.length, &self.files) {
+            (Some(length), _) => *length,
+            (None, Some(files)) => files
+                .iter()
+                .filter(|f| !f.is_padding())
+                .map(|f| f.length)
+                .sum(),
+            (None, None) => self
+                .v2_files()
+                .map(|files| files.iter().map(|f| f.length as usize).sum())
+                .unwrap_or(0),
+        }
+    }
+
+    // length of the space pieces are cut from, padding included
+    pub fn piece_space_length(&self) -> anyhow::Result<usize> {
+        Ok(match (&self.length, &self.files) {
+            (Some(length), _) => *length,
+            (None, Some(files)) => files.iter().map(|f| f.length).sum(),
+            (None, None) => self
+                .file_slots()?
+                .last()
+                .map(|(_, length, offset)| *offset as usize + length)
+                .unwrap_or(0),
+        })
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        if self.is_v1() {
+            self.pieces.len() / 20
+        } else {
+            self.piece_space_length()
+                .unwrap_or(0)
+                .div_ceil(self.piece_length.max(1))
+        }
+    }
+
+    pub fn piece_len(&self, piece_index: usize) -> usize {
+        let start = piece_index * self.piece_length;
+        let end = self.piece_space_length().unwrap_or(0);
+        std::cmp::min(self.piece_length, end.saturating_sub(start))
+    }
+
+    // private torrents only get peers from their trackers: no DHT, PEX or LSD
+    pub fn is_private(&self) -> bool {
+        self.private == Some(1)
+    }
+
+    pub fn is_multi_file(&self) -> bool {
+        if self.files.is_some() {
+            return true;
+        }
+        if self.length.is_some() {
+            return false;
+        }
+        // a single file v2 torrent has one file named after the torrent
+        !matches!(self.v2_files().as_deref(), Ok([f]) if f.path == [self.name.clone()])
+    }
+
+    // relative paths and lengths of the files, in torrent order
+    pub fn file_list(&self) -> anyhow::Result<Vec<(PathBuf, usize)>> {
+        Ok(self
+            .file_slots()?
+            .into_iter()
+            .map(|(path, length, _)| (path, length))
+            .collect())
+    }
+
+    // Files with their offset in the piece space. Padding files are skipped and, like the
+    // file alignment of v2 only torrents, leave gaps between files.
+    pub fn file_slots(&self) -> anyhow::Result<Vec<(PathBuf, usize, u64)>> {
+        match (&self.length, &self.files) {
+            (Some(length), _) => Ok(vec![(PathBuf::from(&self.name), *length, 0)]),
+            (None, Some(files)) => {
+                let mut offset = 0;
+                let mut slots = vec![];
+                for f in files {
+                    if !f.is_padding() {
+                        slots.push((checked_path(&f.path)?, f.length, offset));
+                    }
+                    offset += f.length as u64;
+                }
+                Ok(slots)
+            }
+            (None, None) => {
+                let piece_length = self.piece_length.max(1) as u64;
+                let mut offset = 0;
+                let mut slots = vec![];
+                for f in self.v2_files()? {
+                    slots.push((checked_path(&f.path)?, f.length as usize, offset));
+                    offset = (offset + f.length).div_ceil(piece_length) * piece_length;
+                }
+                Ok(slots)
+            }
+        }
+    }
+}
+
+fn checked_path(components: &[String]) -> anyhow::Result<PathBuf> {
+    anyhow::ensure!(!components.is_empty(), "Empty file path");
+    let mut path = PathBuf::new();
+    for component in components {
+        anyhow::ensure!(
+            !component.is_empty()
+                && component != "."
+                && component != ".."
+                && !component.contains(['/', '\\']),
+            "Invalid path component {component:?}"
+        );
+        path.push(component);
+    }
+    Ok(path)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V2File {
+    pub path: Vec<String>,
+    pub length: u64,
+    // merkle root of the file, absent for empty files
+    pub pieces_root: Option<merkle::Hash>,
+}
+
+fn collect_v2_files(
+    node: &Value,
+    path: &mut Vec<String>,
+    files: &mut Vec<V2File>,
+) -> anyhow::Result<()> {
+    let Value::Dict(entries) = node else {
+        anyhow::bail!("Invalid file tree node");
+    };
+
+    if let Some(Value::Dict(file)) = entries.get(&b""[..]) {
+        let length = match file.get(&b"length"[..]) {
+            Some(Value::Int(length)) if *length >= 0 => *length as u64,
+            _ => anyhow::bail!("Invalid length for {}", path.join("/")),
+        };
+        let pieces_root = match file.get(&b"pieces root"[..]) {
+            Some(Value::Bytes(root)) => Some(
+                root[..]
+                    .try_into()
+                    .map_err(|_| anyhow!("Invalid pieces root for {}", path.join("/")))?,
+            ),
+            _ => None,
+        };
+        anyhow::ensure!(
+            length == 0 || pieces_root.is_some(),
+            "Missing pieces root for {}",
+            path.join("/")
+        );
+        files.push(V2File {
+            path: path.clone(),
+            length,
+            pieces_root,
+        });
+        return Ok(());
+    }
+
+    let mut names: Vec<_> = entries.keys().collect();
+    names.sort();
+    for name in names {
+        path.push(String::from_utf8(name.clone()).context("Non UTF-8 file name")?);
+        collect_v2_files(&entries[name], path, files)?;
+        path.pop();
+    }
+    Ok(())
+}
+
+// `url-list` is either a single url or a list of them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl UrlList {
+    pub fn urls(&self) -> Vec<&str> {
+        match self {
+            UrlList::Single(url) => vec![url.as_str()],
+            UrlList::Multiple(urls) => urls.iter().map(|u| u.as_str()).collect(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TorrentFile {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub announce: String,
+
+    #[serde(
+        default,
+        rename = "announce-list",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub announce_list: Option<Vec<Vec<String>>>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+
+    #[serde(
+        default,
+        rename = "created by",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub created_by: Option<String>,
+
+    #[serde(
+        default,
+        rename = "creation date",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub creation_date: Option<i64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<String>,
+
+    #[serde(default, rename = "url-list", skip_serializing_if = "Option::is_none")]
+    pub url_list: Option<UrlList>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub httpseeds: Option<Vec<String>>,
+
+    // DHT bootstrap nodes as (host, port)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Vec<(String, u16)>>,
+
+    // v2 piece hashes of each file larger than a piece, keyed by its pieces root
+    #[serde(
+        default,
+        rename = "piece layers",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub piece_layers: Option<BTreeMap<ByteBuf, ByteBuf>>,
+
     pub info: TorrentInfo,
 }
+
+impl TorrentFile {
+    pub fn from_bytes(content: &[u8]) -> anyhow::Result<Self> {
+        let mut torrent =
+            serde_bencode::from_bytes::<TorrentFile>(content).context("parse torrent file")?;
+        torrent.info.raw = Some(raw_info(content)?.to_vec());
+        let piece_length = torrent.info.piece_length;
+        anyhow::ensure!(piece_length > 0, "Invalid piece length 0");
+        // BEP 52: whole merkle subtrees of 16 KiB blocks
+        anyhow::ensure!(
+            !torrent.info.is_v2()
+                || (piece_length >= merkle::BLOCK_SIZE && piece_length.is_power_of_two()),
+            "Invalid v2 piece length {piece_length}"
+        );
+        Ok(torrent)
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.info.is_private()
+    }
+
+    // v2 piece hashes of a file, empty for files that fit in one piece
+    pub fn piece_layer(&self, file: &V2File) -> anyhow::Result<Vec<merkle::Hash>> {
+        let Some(root) = file.pieces_root else {
+            return Ok(vec![]);
+        };
+        if file.length <= self.info.piece_length as u64 {
+            return Ok(vec![]);
+        }
+
+        let layer = self
+            .piece_layers
+            .as_ref()
+            .and_then(|layers| layers.get(serde_bytes::Bytes::new(&root)))
+            .ok_or_else(|| anyhow!("Missing piece layer for {}", file.path.join("/")))?;
+        anyhow::ensure!(
+            layer.len().is_multiple_of(32)
+                && layer.len() / 32 == file.length.div_ceil(self.info.piece_length as u64) as usize,
+            "Invalid piece layer for {}",
+            file.path.join("/")
+        );
+        let hashes: Vec<merkle::Hash> = layer
+            .chunks_exact(32)
+            .map(|h| h.try_into().unwrap())
+            .collect();
+        anyhow::ensure!(
+            merkle::piece_layer_root(&hashes, self.info.piece_length) == root,
+            "Piece layer doesn't match pieces root for {}",
+            file.path.join("/")
+        );
+        Ok(hashes)
+    }
+
+    pub fn web_seeds(&self) -> Vec<&str> {
+        self.url_list.as_ref().map(|u| u.urls()).unwrap_or_default()
+    }
+}
+
+// position after the bencoded value starting at `pos`
+pub fn bencode_end(buf: &[u8], pos: usize) -> anyhow::Result<usize> {
+    match buf.get(pos) {
+        Some(b'i') => buf[pos..]
+            .iter()
+            .position(|&b| b == b'e')
+            .map(|end| pos + end + 1)
+            .ok_or_else(|| anyhow!("Unterminated integer at {pos}")),
+        Some(b'l') | Some(b'd') => {
+            let mut pos = pos + 1;
+            while buf.get(pos) != Some(&b'e') {
+                anyhow::ensure!(pos < buf.len(), "Unterminated container");
+                pos = bencode_end(buf, pos)?;
+            }
+            Ok(pos + 1)
+        }
+        Some(b'0'..=b'9') => {
+            let colon = buf[pos..]
+                .iter()
+                .position(|&b| b == b':')
+                .map(|c| pos + c)
+                .ok_or_else(|| anyhow!("Invalid string at {pos}"))?;
+            let len: usize = std::str::from_utf8(&buf[pos..colon])?.parse()?;
+            anyhow::ensure!(colon + 1 + len <= buf.len(), "String past end at {pos}");
+            Ok(colon + 1 + len)
+        }
+        _ => Err(anyhow!("Invalid bencode at {pos}")),
+    }
+}
+
+// the raw bytes of the top level `info` value
+fn raw_info(content: &[u8]) -> anyhow::Result<&[u8]> {
+    anyhow::ensure!(
+        content.first() == Some(&b'd'),
+        "Torrent is not a dictionary"
+    );
+    let mut pos = 1;
+    while content.get(pos) != Some(&b'e') {
+        let key_end = bencode_end(content, pos)?;
+        let value_end = bencode_end(content, key_end)?;
+        if &content[pos..key_end] == b"4:info" {
+            return Ok(&content[key_end..value_end]);
+        }
+        pos = value_end;
+    }
+    Err(anyhow!("Missing info dictionary"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v1(piece_length: usize) -> Vec<u8> {
+        let mut content =
+            format!("d4:infod6:lengthi5e4:name1:a12:piece lengthi{piece_length}e6:pieces20:")
+                .into_bytes();
+        content.extend([0; 20]);
+        content.extend(b"ee");
+        content
+    }
+
+    #[test]
+    fn zero_piece_length() {
+        assert!(TorrentFile::from_bytes(&v1(16384)).is_ok());
+        assert!(TorrentFile::from_bytes(&v1(0)).is_err());
+    }
+
+    #[test]
+    fn v2_piece_length() {
+        let v2 = |piece_length: usize| {
+            format!(
+                "d4:infod9:file treed1:ad0:d6:lengthi5eeee12:meta versioni2e4:name1:a\
+                 12:piece lengthi{piece_length}eee"
+            )
+        };
+        assert!(TorrentFile::from_bytes(v2(16384).as_bytes()).is_ok());
+        assert!(TorrentFile::from_bytes(v2(0).as_bytes()).is_err());
+        assert!(TorrentFile::from_bytes(v2(8192).as_bytes()).is_err());
+        assert!(TorrentFile::from_bytes(v2(3 * 16384).as_bytes()).is_err());
+        // v1 allows any length
+        assert!(TorrentFile::from_bytes(&v1(3 * 16384)).is_ok());
+    }
+
+    // a v2 torrent of one file with its piece layer as given
+    fn v2_with_layer(length: u64, root: &merkle::Hash, layer: &[u8]) -> TorrentFile {
+        let mut content =
+            format!("d4:infod9:file treed1:ad0:d6:lengthi{length}e11:pieces root32:").into_bytes();
+        content.extend(root);
+        content.extend(b"eee12:meta versioni2e4:name1:a12:piece lengthi32768ee12:piece layersd32:");
+        content.extend(root);
+        content.extend(format!("{}:", layer.len()).into_bytes());
+        content.extend(layer);
+        content.extend(b"ee");
+        TorrentFile::from_bytes(&content).unwrap()
+    }
+
+    #[test]
+    fn piece_layer() {
+        let data: Vec<u8> = (0..80920).map(|i| (i % 251) as u8).collect();
+        let hashes: Vec<_> = data
+            .chunks(32768)
+            .map(|piece| merkle::piece_hash(piece, 32768))
+            .collect();
+        let root = merkle::piece_layer_root(&hashes, 32768);
+        let layer = hashes.concat();
+
+        let torrent = v2_with_layer(80920, &root, &layer);
+        let file = &torrent.info.v2_files().unwrap()[0];
+        assert_eq!(torrent.piece_layer(file).unwrap(), hashes);
+
+        let mut tampered = layer.clone();
+        tampered[40] ^= 1;
+        let torrent = v2_with_layer(80920, &root, &tampered);
+        let file = &torrent.info.v2_files().unwrap()[0];
+        let err = torrent.piece_layer(file).unwrap_err().to_string();
+        assert!(err.contains("doesn't match pieces root"), "{err}");
+
+        // one hash short for the file length
+        let torrent = v2_with_layer(80920, &root, &layer[..64]);
+        let file = &torrent.info.v2_files().unwrap()[0];
+        assert!(torrent.piece_layer(file).is_err());
+    }
+}
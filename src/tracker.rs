@@ -1,9 +1,12 @@
 use serde::{Deserialize, Serialize};
 use anyhow::Context;
+use tracing::debug;
 
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
 use std::net::SocketAddr;
+use std::time::Duration;
 use reqwest::Client;
 
 use crate::torrent::TorrentFile;
@@ -12,65 +15,139 @@ use crate::torrent::TorrentFile;
 struct TrackerRequest {
     peer_id: String,
     port: u16,
-    uploaded: usize,
-    downloaded: usize,
-    left: usize,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
     compact: u8,
+    // where IPv6 peers reach us, BEP 7
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ipv6: Option<Ipv6Addr>,
 }
 
 fn hash_encode(t: &[u8; 20]) -> String {
-    let encoded: String = t.iter().map(|b| format!("%{:02x}", b)).collect();
-    //eprintln!("{encoded}");
-    encoded
+    t.iter().map(|b| format!("%{:02x}", b)).collect()
 }
 
 #[derive(Debug, Clone, Deserialize)]
 struct TrackerResponse {
     interval: u32,
 
-    #[serde(with = "serde_bytes")]
+    #[serde(with = "serde_bytes", default)]
     peers: Vec<u8>,
+    #[serde(with = "serde_bytes", default)]
+    peers6: Vec<u8>,
 }
 
+// Bytes transferred so far, and those still wanted, as told to the tracker.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Transfer {
+    pub uploaded: u64,
+    pub downloaded: u64,
+    pub left: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Announced {
+    pub peers: Vec<SocketAddr>,
+    // until the next announce
+    pub interval: Duration,
+}
+
+#[derive(Debug, Clone)]
 pub struct Tracker {
     peer_id: String,
     port: u16,
+    ipv6: Option<Ipv6Addr>,
+}
+
+impl Default for Tracker {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Tracker {
     pub fn new() -> Self {
-        Tracker { peer_id: "00112233445566778899".to_owned(), port: 6881 }
+        Tracker {
+            peer_id: "00112233445566778899".to_owned(),
+            port: 6881,
+            ipv6: None,
+        }
+    }
+
+    // `port` is where we accept peer connections
+    pub fn with_port(port: u16) -> Self {
+        Tracker {
+            port,
+            ..Tracker::new()
+        }
+    }
+
+    // also announces that peers reach us at `ip`
+    pub fn with_ipv6(self, ip: Ipv6Addr) -> Self {
+        Tracker {
+            ipv6: Some(ip),
+            ..self
+        }
     }
 
     pub async fn req_peers(&self, torrent: &TorrentFile) -> anyhow::Result<Vec<SocketAddr>> {
-        
+        self.req_peers_with(torrent, torrent.info.wire_hash()?)
+            .await
+    }
+
+    // Peers of the swarm with `info_hash`, as a client that has nothing yet.
+    pub async fn req_peers_with(
+        &self,
+        torrent: &TorrentFile,
+        info_hash: [u8; 20],
+    ) -> anyhow::Result<Vec<SocketAddr>> {
+        let transfer = Transfer {
+            left: torrent.info.total_length() as u64,
+            ..Transfer::default()
+        };
+        Ok(self.announce(torrent, info_hash, transfer).await?.peers)
+    }
+
+    pub async fn announce(
+        &self,
+        torrent: &TorrentFile,
+        info_hash: [u8; 20],
+        transfer: Transfer,
+    ) -> anyhow::Result<Announced> {
+        self.announce_to(&torrent.announce, info_hash, transfer)
+            .await
+    }
+
+    // Announces to the tracker at `url`, for swarms we have no torrent file of yet.
+    pub async fn announce_to(
+        &self,
+        url: &str,
+        info_hash: [u8; 20],
+        transfer: Transfer,
+    ) -> anyhow::Result<Announced> {
         let tracker_url = reqwest::Url::parse(&format!(
             "{}?info_hash={}",
-            torrent.announce,
-            hash_encode(&torrent.info.hash()?)
+            url,
+            hash_encode(&info_hash)
         ))?;
 
         let client = Client::new().get(tracker_url).query(&TrackerRequest {
             peer_id: self.peer_id.to_owned(),
             port: self.port,
-            uploaded: 0,
-            downloaded: 0,
-            left: torrent.info.length,
+            uploaded: transfer.uploaded,
+            downloaded: transfer.downloaded,
+            left: transfer.left,
             compact: 1,
+            ipv6: self.ipv6,
         });
 
-        //eprintln!("{:?}", client);
-
         let response = client.send().await.context("Tracker request builder")?;
 
-        //eprintln!("{}", response.status());
-        //println!("{}", response.text().await?);
-
         let response = serde_bencode::from_bytes::<TrackerResponse>(&response.bytes().await?)
             .context("Decoding response")?;
 
-        eprintln!("interval {:?}", response.interval);
-        let peers: Vec<_> = response
+        let mut peers: Vec<_> = response
             .peers
             .chunks_exact(6)
             .map(|c| {
@@ -80,8 +157,73 @@ impl Tracker {
                 )
             })
             .collect();
+        peers.extend(response.peers6.chunks_exact(18).map(|c| {
+            let ip: [u8; 16] = c[..16].try_into().unwrap();
+            SocketAddr::new(IpAddr::V6(ip.into()), u16::from_be_bytes([c[16], c[17]]))
+        }));
+        debug!(
+            "{} peers from {}, next announce in {}s",
+            peers.len(),
+            url,
+            response.interval
+        );
+
+        Ok(Announced {
+            peers,
+            interval: Duration::from_secs(response.interval.into()),
+        })
+    }
+}
 
-        Ok(peers)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+    use tokio::net::TcpListener;
+
+    use crate::http::{self, Request};
+
+    #[tokio::test]
+    async fn announce() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let announce = format!("http://{}/announce", listener.local_addr().unwrap());
+        let torrent = TorrentFile::from_bytes(
+            format!(
+                "d8:announce{}:{announce}4:infod6:lengthi1000e4:name1:a\
+                 12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaaee",
+                announce.len()
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let request = Request::read(&mut stream).await.unwrap();
+            let mut body = b"d8:intervali900e5:peers6:".to_vec();
+            body.extend([127, 0, 0, 1, 0x1a, 0xe1]);
+            body.extend(b"e");
+            http::write_response(&mut stream, 200, "text/plain", &body)
+                .await
+                .unwrap();
+            request.target
+        });
 
+        let transfer = Transfer {
+            uploaded: 10,
+            downloaded: 20,
+            left: 30,
+        };
+        let announced = Tracker::with_port(7000)
+            .announce(&torrent, [1; 20], transfer)
+            .await
+            .unwrap();
+        assert_eq!(announced.peers, ["127.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(announced.interval, Duration::from_secs(900));
+
+        let target = server.await.unwrap();
+        for param in ["port=7000", "uploaded=10", "downloaded=20", "left=30"] {
+            assert!(target.contains(param), "{param} missing in {target}");
+        }
     }
 }
@@ -0,0 +1,486 @@
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::download::{DownloadOptions, PeerStatus};
+use crate::http::{self, Request};
+use crate::magnet::Magnet;
+use crate::priority::{FileFilter, FileSelection};
+use crate::session::{Session, TorrentId, TorrentState, TorrentStatus};
+use crate::torrent::TorrentFile;
+
+// Transmission compatible RPC, for the tools written against it.
+pub const RPC_PATH: &str = "/transmission/rpc";
+
+pub const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+
+// tr_torrent_activity
+const STATUS_STOPPED: u8 = 0;
+const STATUS_CHECK: u8 = 2;
+const STATUS_DOWNLOAD: u8 = 4;
+const STATUS_SEED: u8 = 6;
+
+// speed limits are in kB/s
+const KILO: u64 = 1000;
+
+// tr_stat_errtype
+const ERROR_NONE: u8 = 0;
+const ERROR_LOCAL: u8 = 3;
+
+// Clients must echo it back, a cheap CSRF guard. It changes with every daemon run.
+pub fn session_id() -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut hasher = Sha1::new();
+    hasher.update(now.as_nanos().to_be_bytes());
+    hasher.update(std::process::id().to_be_bytes());
+    hex::encode(hasher.finalize())
+}
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    method: String,
+    #[serde(default)]
+    arguments: Value,
+    tag: Option<Value>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+struct AddArguments {
+    // path or url of a torrent file, or magnet link
+    filename: Option<String>,
+    // base64 encoded torrent file
+    metainfo: Option<String>,
+    download_dir: Option<PathBuf>,
+    #[serde(default)]
+    paused: bool,
+    files_wanted: Option<Vec<usize>>,
+    #[serde(default)]
+    files_unwanted: Vec<usize>,
+    #[serde(default)]
+    priority_high: Vec<usize>,
+    #[serde(default)]
+    priority_low: Vec<usize>,
+}
+
+impl AddArguments {
+    fn selection(&self) -> FileSelection {
+        let filters = |indexes: &[usize]| indexes.iter().map(|&i| FileFilter::Index(i)).collect();
+        FileSelection {
+            include: filters(self.files_wanted.as_deref().unwrap_or_default()),
+            exclude: filters(&self.files_unwanted),
+            high: filters(&self.priority_high),
+            low: filters(&self.priority_low),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct GetArguments {
+    #[serde(default)]
+    fields: Vec<String>,
+    ids: Option<Value>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+struct IdsArguments {
+    ids: Option<Value>,
+    #[serde(default)]
+    delete_local_data: bool,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+struct SessionSetArguments {
+    speed_limit_down: Option<u64>,
+    speed_limit_down_enabled: Option<bool>,
+    speed_limit_up: Option<u64>,
+    speed_limit_up_enabled: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct TorrentSetArguments {
+    ids: Option<Value>,
+    download_limit: Option<u64>,
+    download_limited: Option<bool>,
+    upload_limit: Option<u64>,
+    upload_limited: Option<bool>,
+}
+
+// A limit in bytes per second after setting its kB/s value or turning it on or off. Turning on
+// a limit that was never set leaves it off.
+fn update_limit(current: Option<u64>, value: Option<u64>, enabled: Option<bool>) -> Option<u64> {
+    let value = value.map(|kb| kb * KILO).or(current);
+    if enabled.unwrap_or(current.is_some()) {
+        value
+    } else {
+        None
+    }
+}
+
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let (mut bits, mut acc) = (0, 0u32);
+    for c in s.bytes().filter(|c| !c.is_ascii_whitespace() && *c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+// Torrents named by `ids`: a number, a hash string, a list of them or all when missing.
+fn select(session: &Session, ids: Option<&Value>) -> Vec<TorrentId> {
+    let one = |id: &Value| match id {
+        Value::Number(n) => n.as_u64().map(|n| n as TorrentId),
+        Value::String(hash) => hex::decode(hash)
+            .ok()
+            .and_then(|h| h.try_into().ok())
+            .and_then(|h| session.find(&h)),
+        _ => None,
+    };
+    match ids {
+        None | Some(Value::Null) => session.list().iter().map(|s| s.id).collect(),
+        // we don't track activity, every torrent counts
+        Some(Value::String(s)) if s == "recently-active" => {
+            session.list().iter().map(|s| s.id).collect()
+        }
+        Some(Value::Array(ids)) => ids.iter().filter_map(one).collect(),
+        Some(id) => one(id).into_iter().collect(),
+    }
+}
+
+fn torrent_fields(status: &TorrentStatus, peers: &[PeerStatus]) -> Map<String, Value> {
+    let (code, error, error_string) = match &status.state {
+        TorrentState::Checking => (STATUS_CHECK, ERROR_NONE, String::new()),
+        TorrentState::Downloading => (STATUS_DOWNLOAD, ERROR_NONE, String::new()),
+        TorrentState::Seeding => (STATUS_SEED, ERROR_NONE, String::new()),
+        TorrentState::Paused => (STATUS_STOPPED, ERROR_NONE, String::new()),
+        TorrentState::Error(e) => (STATUS_STOPPED, ERROR_LOCAL, e.clone()),
+    };
+    // whole pieces, the last one may be shorter
+    let (wanted, missing) = (
+        status.wanted.unwrap_or(status.pieces),
+        status.missing.unwrap_or(status.pieces),
+    );
+    let size_when_done = (wanted as u64 * status.piece_length).min(status.size);
+    let left = (missing as u64 * status.piece_length).min(size_when_done);
+    let percent_done = if status.missing.is_none() {
+        0.0
+    } else if size_when_done == 0 {
+        1.0
+    } else {
+        (size_when_done - left) as f64 / size_when_done as f64
+    };
+    let download_dir = status
+        .output
+        .parent()
+        .map(|p| p.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let fields = json!({
+        "id": status.id,
+        "name": status.name,
+        "hashString": hex::encode(status.info_hash),
+        "status": code,
+        "error": error,
+        "errorString": error_string,
+        "percentDone": percent_done,
+        "totalSize": status.size,
+        "sizeWhenDone": size_when_done,
+        "leftUntilDone": left,
+        "isFinished": status.missing == Some(0),
+        "downloadDir": download_dir,
+        "pieceCount": status.pieces,
+        "pieceSize": status.piece_length,
+        "peersConnected": peers.len(),
+        "downloadLimit": status.download_limit.unwrap_or_default() / KILO,
+        "downloadLimited": status.download_limit.is_some(),
+        "uploadLimit": status.upload_limit.unwrap_or_default() / KILO,
+        "uploadLimited": status.upload_limit.is_some(),
+        "rateDownload": status.download_rate,
+        "rateUpload": status.upload_rate,
+        "downloadedEver": status.downloaded,
+        "uploadedEver": status.uploaded,
+        "eta": status.eta.map_or(-1, |eta| eta.as_secs() as i64),
+    });
+    match fields {
+        Value::Object(fields) => fields,
+        _ => unreachable!(),
+    }
+}
+
+async fn add(session: &Session, args: AddArguments) -> anyhow::Result<Value> {
+    let content = match (&args.filename, &args.metainfo) {
+        (_, Some(metainfo)) => {
+            base64_decode(metainfo).ok_or_else(|| anyhow!("Invalid base64 metainfo"))?
+        }
+        (Some(filename), None) if filename.starts_with("magnet:") => {
+            let magnet: Magnet = filename.parse()?;
+            if let Some(id) = session.find(&magnet.info_hash) {
+                return duplicate(session, id);
+            }
+            let torrent = session.fetch_torrent(&magnet).await?;
+            return add_torrent(session, &args, torrent).await;
+        }
+        (Some(url), None) if url.starts_with("http://") || url.starts_with("https://") => {
+            reqwest::get(url)
+                .await
+                .and_then(|r| r.error_for_status())
+                .with_context(|| format!("Fetching {url}"))?
+                .bytes()
+                .await?
+                .to_vec()
+        }
+        (Some(path), None) => {
+            std::fs::read(path).with_context(|| format!("Reading torrent file {path}"))?
+        }
+        (None, None) => anyhow::bail!("Either filename or metainfo is needed"),
+    };
+    let torrent = TorrentFile::from_bytes(&content)?;
+    if let Some(id) = session.find(&torrent.info.wire_hash()?) {
+        return duplicate(session, id);
+    }
+    add_torrent(session, &args, torrent).await
+}
+
+fn duplicate(session: &Session, id: TorrentId) -> anyhow::Result<Value> {
+    let status = session.status(id)?;
+    Ok(json!({ "torrent-duplicate": {
+        "id": id,
+        "name": status.name,
+        "hashString": hex::encode(status.info_hash),
+    }}))
+}
+
+async fn add_torrent(
+    session: &Session,
+    args: &AddArguments,
+    torrent: TorrentFile,
+) -> anyhow::Result<Value> {
+    let info_hash = torrent.info.wire_hash()?;
+    let dir = args
+        .download_dir
+        .clone()
+        .unwrap_or_else(|| session.download_dir().to_owned());
+    let output = dir.join(&torrent.info.name);
+    let name = torrent.info.name.clone();
+    let options = DownloadOptions {
+        threads: crate::verify::default_threads(),
+        selection: args.selection(),
+        ..Default::default()
+    };
+    let id = session.add(torrent, &output, options)?;
+    if args.paused {
+        session.pause(id).await?;
+    }
+    Ok(json!({ "torrent-added": {
+        "id": id,
+        "name": name,
+        "hashString": hex::encode(info_hash),
+    }}))
+}
+
+fn arguments<T: serde::de::DeserializeOwned + Default>(arguments: Value) -> anyhow::Result<T> {
+    match arguments {
+        Value::Null => Ok(T::default()),
+        arguments => serde_json::from_value(arguments).context("Invalid arguments"),
+    }
+}
+
+async fn dispatch(session: &Session, method: &str, args: Value) -> anyhow::Result<Value> {
+    match method {
+        "torrent-add" => add(session, arguments(args)?).await,
+        "torrent-get" => {
+            let args: GetArguments = arguments(args)?;
+            let mut torrents = vec![];
+            for id in select(session, args.ids.as_ref()) {
+                let Ok(status) = session.status(id) else {
+                    continue;
+                };
+                let peers = session.peers(id).unwrap_or_default();
+                let mut fields = torrent_fields(&status, &peers);
+                if !args.fields.is_empty() {
+                    fields.retain(|name, _| args.fields.contains(name));
+                }
+                torrents.push(Value::Object(fields));
+            }
+            Ok(json!({ "torrents": torrents }))
+        }
+        "torrent-set" => {
+            let args: TorrentSetArguments = arguments(args)?;
+            for id in select(session, args.ids.as_ref()) {
+                let status = session.status(id)?;
+                let download = update_limit(
+                    status.download_limit,
+                    args.download_limit,
+                    args.download_limited,
+                );
+                let upload =
+                    update_limit(status.upload_limit, args.upload_limit, args.upload_limited);
+                session.set_torrent_rate_limits(id, download, upload)?;
+            }
+            Ok(json!({}))
+        }
+        "torrent-start" | "torrent-start-now" => {
+            let args: IdsArguments = arguments(args)?;
+            for id in select(session, args.ids.as_ref()) {
+                if matches!(
+                    session.status(id)?.state,
+                    TorrentState::Paused | TorrentState::Error(_)
+                ) {
+                    session.resume(id)?;
+                }
+            }
+            Ok(json!({}))
+        }
+        "torrent-stop" => {
+            let args: IdsArguments = arguments(args)?;
+            for id in select(session, args.ids.as_ref()) {
+                session.pause(id).await?;
+            }
+            Ok(json!({}))
+        }
+        "torrent-remove" => {
+            let args: IdsArguments = arguments(args)?;
+            for id in select(session, args.ids.as_ref()) {
+                session.remove(id, args.delete_local_data).await?;
+            }
+            Ok(json!({}))
+        }
+        "session-stats" => {
+            let torrents = session.list();
+            let paused = torrents
+                .iter()
+                .filter(|t| t.state == TorrentState::Paused)
+                .count();
+            // counted since the torrents were started, nothing is kept across runs
+            let stats = json!({
+                "uploadedBytes": torrents.iter().map(|t| t.uploaded).sum::<u64>(),
+                "downloadedBytes": torrents.iter().map(|t| t.downloaded).sum::<u64>(),
+                "filesAdded": 0,
+                "sessionCount": 1,
+                "secondsActive": 0,
+            });
+            Ok(json!({
+                "activeTorrentCount": torrents.len() - paused,
+                "pausedTorrentCount": paused,
+                "torrentCount": torrents.len(),
+                "downloadSpeed": torrents.iter().map(|t| t.download_rate).sum::<u64>(),
+                "uploadSpeed": torrents.iter().map(|t| t.upload_rate).sum::<u64>(),
+                "cumulative-stats": stats,
+                "current-stats": stats,
+            }))
+        }
+        "session-set" => {
+            let args: SessionSetArguments = arguments(args)?;
+            let (download, upload) = session.rate_limits();
+            session.set_rate_limits(
+                update_limit(
+                    download,
+                    args.speed_limit_down,
+                    args.speed_limit_down_enabled,
+                ),
+                update_limit(upload, args.speed_limit_up, args.speed_limit_up_enabled),
+            );
+            Ok(json!({}))
+        }
+        "session-get" => {
+            let (download, upload) = session.rate_limits();
+            Ok(json!({
+                "download-dir": session.download_dir(),
+                "speed-limit-down": download.unwrap_or_default() / KILO,
+                "speed-limit-down-enabled": download.is_some(),
+                "speed-limit-up": upload.unwrap_or_default() / KILO,
+                "speed-limit-up-enabled": upload.is_some(),
+                "peer-port": session.local_addr().port(),
+                "rpc-version": 15,
+                "rpc-version-minimum": 1,
+                "version": env!("CARGO_PKG_VERSION"),
+            }))
+        }
+        _ => anyhow::bail!("method name not recognized"),
+    }
+}
+
+// Answers 409 with the session id when the request doesn't carry it, and then false.
+pub async fn check_session_id<W: AsyncWrite + Unpin>(
+    session_id: &str,
+    request: &Request,
+    writer: &mut W,
+) -> anyhow::Result<bool> {
+    if request.header(SESSION_ID_HEADER) == Some(session_id) {
+        return Ok(true);
+    }
+    let body = format!("<p>{SESSION_ID_HEADER}: {session_id}</p>\n");
+    http::write_head(
+        writer,
+        409,
+        &[
+            (SESSION_ID_HEADER, session_id.to_owned()),
+            ("Content-Type", "text/html; charset=utf-8".to_owned()),
+            ("Content-Length", body.len().to_string()),
+        ],
+    )
+    .await?;
+    writer.write_all(body.as_bytes()).await?;
+    writer.flush().await?;
+    Ok(false)
+}
+
+// Answers a request to `RPC_PATH`, after the session id handshake.
+pub async fn handle<W: AsyncWrite + Unpin>(
+    session: &Session,
+    session_id: &str,
+    request: &Request,
+    writer: &mut W,
+) -> anyhow::Result<()> {
+    if !check_session_id(session_id, request, writer).await? {
+        return Ok(());
+    }
+    if request.method != "POST" {
+        return http::write_response(writer, 405, "text/plain", b"Method not allowed\n").await;
+    }
+
+    let response = match serde_json::from_slice::<RpcRequest>(&request.body) {
+        Ok(rpc) => {
+            let (result, arguments) = match dispatch(session, &rpc.method, rpc.arguments).await {
+                Ok(arguments) => ("success".to_owned(), arguments),
+                Err(e) => (format!("{e:#}"), json!({})),
+            };
+            let mut response = json!({ "result": result, "arguments": arguments });
+            if let Some(tag) = rpc.tag {
+                response["tag"] = tag;
+            }
+            response
+        }
+        Err(e) => json!({ "result": format!("Invalid request: {e}"), "arguments": {} }),
+    };
+    http::write_response(
+        writer,
+        200,
+        "application/json",
+        &serde_json::to_vec(&response)?,
+    )
+    .await
+}
@@ -0,0 +1,346 @@
+use anyhow::{anyhow, bail, Context};
+use reqwest::{Client, Url};
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use crate::net;
+use crate::port_map::Protocol;
+
+// UPnP Internet Gateway Devices: found with an SSDP search, then told over SOAP which ports
+// to forward.
+const SSDP: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+// the services that forward ports, the newest first
+const SERVICE_TYPES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+// how long devices get to answer a search
+const SEARCH_WAIT: Duration = Duration::from_secs(2);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+// the device only keeps permanent mappings
+const ONLY_PERMANENT_LEASES: u32 = 725;
+
+// The port forwarding service of a gateway.
+#[derive(Debug, Clone)]
+pub struct Gateway {
+    client: Client,
+    control_url: Url,
+    service_type: &'static str,
+    // our address as the gateway sees it
+    local_ip: IpAddr,
+}
+
+impl Gateway {
+    // Searches the network for a gateway, the first that answers with a forwarding service
+    // is taken.
+    pub async fn discover() -> anyhow::Result<Self> {
+        Gateway::discover_at(SSDP).await
+    }
+
+    // Like `discover`, searching at `ssdp`.
+    pub async fn discover_at(ssdp: SocketAddr) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let search = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {SSDP}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\n\
+             ST: {SEARCH_TARGET}\r\n\r\n",
+            SEARCH_WAIT.as_secs()
+        );
+        socket
+            .send_to(search.as_bytes(), ssdp)
+            .await
+            .context("Searching for a UPnP gateway")?;
+
+        let client = Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        let deadline = tokio::time::Instant::now() + SEARCH_WAIT;
+        let mut buf = [0; 2048];
+        while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv(&mut buf)).await {
+            let len = received?;
+            let Some(location) = header(&buf[..len], "location") else {
+                continue;
+            };
+            match Gateway::describe(&client, &location).await {
+                Ok(gateway) => return Ok(gateway),
+                Err(e) => debug!("UPnP device at {location}: {e:#}"),
+            }
+        }
+        bail!("No UPnP gateway found")
+    }
+
+    // Reads the device description for the forwarding service.
+    async fn describe(client: &Client, location: &str) -> anyhow::Result<Self> {
+        let location = Url::parse(location)?;
+        let description = client
+            .get(location.clone())
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+        let (service_type, control_url) = SERVICE_TYPES
+            .iter()
+            .find_map(|service_type| {
+                description.split("<service>").skip(1).find_map(|service| {
+                    if tag(service, "serviceType")? != *service_type {
+                        return None;
+                    }
+                    Some((*service_type, tag(service, "controlURL")?))
+                })
+            })
+            .ok_or_else(|| anyhow!("No port forwarding service"))?;
+        let base = tag(&description, "URLBase")
+            .and_then(|base| Url::parse(base).ok())
+            .unwrap_or_else(|| location.clone());
+        let control_url = base.join(control_url)?;
+
+        let host = control_url
+            .host_str()
+            .and_then(|host| host.trim_matches(['[', ']']).parse().ok())
+            .ok_or_else(|| anyhow!("Bad control URL {control_url}"))?;
+        let local_ip = net::local_ip_for(host).ok_or_else(|| anyhow!("No route to {host}"))?;
+        Ok(Gateway {
+            client: client.clone(),
+            control_url,
+            service_type,
+            local_ip,
+        })
+    }
+
+    // Forwards `external_port` to `internal_port` for `lease`, forever when the gateway keeps
+    // only permanent mappings. Returns the lease granted.
+    pub async fn add_port_mapping(
+        &self,
+        protocol: Protocol,
+        internal_port: u16,
+        external_port: u16,
+        lease: Duration,
+    ) -> anyhow::Result<Duration> {
+        let add = |lease: Duration| {
+            self.soap(
+                "AddPortMapping",
+                vec![
+                    ("NewRemoteHost", String::new()),
+                    ("NewExternalPort", external_port.to_string()),
+                    ("NewProtocol", protocol.to_string()),
+                    ("NewInternalPort", internal_port.to_string()),
+                    ("NewInternalClient", self.local_ip.to_string()),
+                    ("NewEnabled", "1".to_owned()),
+                    ("NewPortMappingDescription", "bittorrent".to_owned()),
+                    ("NewLeaseDuration", lease.as_secs().to_string()),
+                ],
+            )
+        };
+        match add(lease).await? {
+            Ok(_) => Ok(lease),
+            Err(ONLY_PERMANENT_LEASES) => {
+                add(Duration::ZERO).await?.map_err(upnp_error)?;
+                Ok(Duration::ZERO)
+            }
+            Err(code) => Err(upnp_error(code)),
+        }
+    }
+
+    pub async fn delete_port_mapping(
+        &self,
+        protocol: Protocol,
+        external_port: u16,
+    ) -> anyhow::Result<()> {
+        self.soap(
+            "DeletePortMapping",
+            vec![
+                ("NewRemoteHost", String::new()),
+                ("NewExternalPort", external_port.to_string()),
+                ("NewProtocol", protocol.to_string()),
+            ],
+        )
+        .await?
+        .map_err(upnp_error)?;
+        Ok(())
+    }
+
+    // The response, or the error code the gateway answered with.
+    async fn soap(
+        &self,
+        action: &str,
+        arguments: Vec<(&str, String)>,
+    ) -> anyhow::Result<Result<String, u32>> {
+        let arguments: String = arguments
+            .iter()
+            .map(|(name, value)| format!("<{name}>{value}</{name}>"))
+            .collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\r\n\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{action} xmlns:u=\"{}\">{arguments}</u:{action}></s:Body></s:Envelope>",
+            self.service_type
+        );
+        let response = self
+            .client
+            .post(self.control_url.clone())
+            .header("Content-Type", "text/xml; charset=\"utf-8\"")
+            .header("SOAPAction", format!("\"{}#{action}\"", self.service_type))
+            .body(body)
+            .send()
+            .await
+            .with_context(|| format!("UPnP {action}"))?;
+        let status = response.status();
+        let text = response.text().await?;
+        if !status.is_success() {
+            return match tag(&text, "errorCode").and_then(|c| c.parse().ok()) {
+                Some(code) => Ok(Err(code)),
+                None => bail!("UPnP {action}: {status}"),
+            };
+        }
+        Ok(Ok(text))
+    }
+}
+
+fn upnp_error(code: u32) -> anyhow::Error {
+    anyhow!("UPnP error {code}")
+}
+
+// a header of an HTTP-like SSDP message, by its case-insensitive name
+fn header(message: &[u8], name: &str) -> Option<String> {
+    std::str::from_utf8(message)
+        .ok()?
+        .lines()
+        .skip(1)
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.trim()
+                .eq_ignore_ascii_case(name)
+                .then(|| value.trim().to_owned())
+        })
+}
+
+// the text of the first `<name>` element, good enough for device descriptions
+fn tag<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{name}>"))? + name.len() + 2;
+    let end = start + xml[start..].find(&format!("</{name}>"))?;
+    Some(xml[start..end].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+    use tokio::net::TcpListener;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::http::{self, Request};
+
+    const DESCRIPTION: &str = "<?xml version=\"1.0\"?><root><device><serviceList>\
+        <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+        <controlURL>/l3f</controlURL></service>\
+        <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+        <controlURL>/ctl/IPConn</controlURL></service>\
+        </serviceList></device></root>";
+
+    // An Internet Gateway Device on loopback: answers the SSDP search with where its
+    // description is, and SOAP actions on its control URL. With `permanent_only` it refuses
+    // leases. Returns the SSDP address and the actions with their bodies.
+    async fn fake_igd(permanent_only: bool) -> (SocketAddr, Arc<Mutex<Vec<(String, String)>>>) {
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let location = format!("http://{}/desc.xml", http.local_addr().unwrap());
+        let actions = Arc::new(Mutex::new(vec![]));
+        let seen = actions.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = http.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                let request = Request::read(&mut stream).await.unwrap();
+                if request.path() == "/desc.xml" {
+                    http::write_response(&mut stream, 200, "text/xml", DESCRIPTION.as_bytes())
+                        .await
+                        .unwrap();
+                    continue;
+                }
+                assert_eq!(request.path(), "/ctl/IPConn");
+                let action = request.header("soapaction").unwrap().trim_matches('"');
+                let action = action.split_once('#').unwrap().1.to_owned();
+                let body = String::from_utf8(request.body.clone()).unwrap();
+                let refused = permanent_only
+                    && action == "AddPortMapping"
+                    && tag(&body, "NewLeaseDuration") != Some("0");
+                seen.lock().unwrap().push((action.clone(), body));
+                let (status, response) = if refused {
+                    (
+                        500,
+                        format!("<errorCode>{ONLY_PERMANENT_LEASES}</errorCode>"),
+                    )
+                } else {
+                    (200, format!("<u:{action}Response/>"))
+                };
+                http::write_response(&mut stream, status, "text/xml", response.as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let ssdp = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let ssdp_addr = ssdp.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 2048];
+            loop {
+                let (len, from) = ssdp.recv_from(&mut buf).await.unwrap();
+                let search = std::str::from_utf8(&buf[..len]).unwrap();
+                assert!(search.starts_with("M-SEARCH * HTTP/1.1"));
+                assert!(search.contains(SEARCH_TARGET));
+                let answer = format!(
+                    "HTTP/1.1 200 OK\r\nST: {SEARCH_TARGET}\r\nLOCATION: {location}\r\n\r\n"
+                );
+                ssdp.send_to(answer.as_bytes(), from).await.unwrap();
+            }
+        });
+        (ssdp_addr, actions)
+    }
+
+    #[tokio::test]
+    async fn add_and_delete() {
+        let (ssdp, actions) = fake_igd(false).await;
+        let gateway = Gateway::discover_at(ssdp).await.unwrap();
+        assert_eq!(gateway.service_type, SERVICE_TYPES[1]);
+        assert_eq!(gateway.control_url.path(), "/ctl/IPConn");
+
+        let lease = Duration::from_secs(3600);
+        let granted = gateway
+            .add_port_mapping(Protocol::Tcp, 6881, 40000, lease)
+            .await
+            .unwrap();
+        assert_eq!(granted, lease);
+        gateway
+            .delete_port_mapping(Protocol::Tcp, 40000)
+            .await
+            .unwrap();
+
+        let actions = actions.lock().unwrap();
+        let (action, body) = &actions[0];
+        assert_eq!(action, "AddPortMapping");
+        assert_eq!(tag(body, "NewExternalPort"), Some("40000"));
+        assert_eq!(tag(body, "NewInternalPort"), Some("6881"));
+        assert_eq!(tag(body, "NewProtocol"), Some("TCP"));
+        assert_eq!(tag(body, "NewInternalClient"), Some("127.0.0.1"));
+        assert_eq!(tag(body, "NewLeaseDuration"), Some("3600"));
+        let (action, body) = &actions[1];
+        assert_eq!(action, "DeletePortMapping");
+        assert_eq!(tag(body, "NewExternalPort"), Some("40000"));
+    }
+
+    #[tokio::test]
+    async fn permanent_leases_only() {
+        let (ssdp, actions) = fake_igd(true).await;
+        let gateway = Gateway::discover_at(ssdp).await.unwrap();
+        let granted = gateway
+            .add_port_mapping(Protocol::Udp, 6881, 6881, Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert_eq!(granted, Duration::ZERO);
+        assert_eq!(actions.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,1121 @@
+use anyhow::{anyhow, Context};
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context as TaskContext, Poll, Waker};
+use std::time::Duration;
+
+use crate::mse::ByteStream;
+use crate::net;
+
+// uTorrent Transport Protocol (BEP 29): reliable streams over UDP whose LEDBAT congestion
+// control backs off as soon as it sees queuing delay, leaving the link to other traffic.
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 20;
+
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+
+const EXT_SELECTIVE_ACK: u8 = 1;
+// most packets past a gap a selective ack covers
+const MAX_SACK_BITS: usize = 256;
+
+// small enough to get through tunnels without fragmenting
+const MAX_PAYLOAD: usize = 1400 - HEADER_LEN;
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+// LEDBAT: the one way delay we aim to add, and by how much the window may grow per round trip
+const TARGET_DELAY: i64 = 100_000;
+const MAX_CWND_INCREASE: f64 = MAX_PAYLOAD as f64;
+// delay samples are compared to the lowest one seen over about two minutes
+const BASE_DELAY_BUCKET: Duration = Duration::from_secs(60);
+const BASE_DELAY_BUCKETS: usize = 2;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MIN_RTO: Duration = Duration::from_millis(500);
+const MAX_RTO: Duration = Duration::from_secs(30);
+// a packet sent this many times without an ack kills the connection
+const MAX_TRANSMISSIONS: u32 = 6;
+const DUPLICATE_ACKS: u32 = 3;
+
+const RECV_BUFFER: usize = 1024 * 1024;
+const SEND_BUFFER: usize = 1024 * 1024;
+// sequence numbers are 16 bits, keep well within half of them
+const MAX_IN_FLIGHT: usize = 4096;
+
+const TICK: Duration = Duration::from_millis(50);
+const KEEPALIVE: Duration = Duration::from_secs(29);
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// before falling back to TCP
+const UTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// incoming connections waiting for `accept`
+const BACKLOG: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    kind: u8,
+    conn_id: u16,
+    timestamp: u32,
+    timestamp_diff: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+}
+
+// a received packet, borrowing the datagram
+struct Packet<'a> {
+    header: Header,
+    // selective ack bitmask
+    sack: Option<&'a [u8]>,
+    payload: &'a [u8],
+}
+
+impl Header {
+    // `None` for anything that isn't a uTP packet
+    fn parse(buf: &[u8]) -> Option<Packet<'_>> {
+        if buf.len() < HEADER_LEN || buf[0] & 0x0f != VERSION || buf[0] >> 4 > ST_SYN {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_be_bytes([buf[i], buf[i + 1]]);
+        let u32_at = |i: usize| u32::from_be_bytes(buf[i..i + 4].try_into().unwrap());
+        let header = Header {
+            kind: buf[0] >> 4,
+            conn_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+        };
+
+        let mut sack = None;
+        let (mut next, mut pos) = (buf[1], HEADER_LEN);
+        while next != 0 {
+            let len = *buf.get(pos + 1)? as usize;
+            let data = buf.get(pos + 2..pos + 2 + len)?;
+            if next == EXT_SELECTIVE_ACK {
+                sack = Some(data);
+            }
+            next = buf[pos];
+            pos += 2 + len;
+        }
+        Some(Packet {
+            header,
+            sack,
+            payload: &buf[pos..],
+        })
+    }
+
+    fn encode(&self, sack: Option<&[u8]>, payload: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + payload.len() + 34);
+        buf.push(self.kind << 4 | VERSION);
+        buf.push(if sack.is_some() { EXT_SELECTIVE_ACK } else { 0 });
+        buf.extend(self.conn_id.to_be_bytes());
+        buf.extend(self.timestamp.to_be_bytes());
+        buf.extend(self.timestamp_diff.to_be_bytes());
+        buf.extend(self.wnd_size.to_be_bytes());
+        buf.extend(self.seq_nr.to_be_bytes());
+        buf.extend(self.ack_nr.to_be_bytes());
+        if let Some(sack) = sack {
+            buf.push(0);
+            buf.push(sack.len() as u8);
+            buf.extend(sack);
+        }
+        buf.extend(payload);
+        buf
+    }
+}
+
+// microseconds on a clock of our own, only differences matter
+fn micros() -> u32 {
+    static START: OnceLock<std::time::Instant> = OnceLock::new();
+    START
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_micros() as u32
+}
+
+fn random_u16() -> u16 {
+    RandomState::new().hash_one(micros()) as u16
+}
+
+// `a` comes after `b`, sequence numbers wrap
+fn seq_after(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    SynSent,
+    Connected,
+}
+
+// a packet waiting for its ack
+#[derive(Debug)]
+struct Sent {
+    kind: u8,
+    seq_nr: u16,
+    payload: Bytes,
+    sent_at: Instant,
+    transmissions: u32,
+}
+
+// Lowest one way delay seen recently, per LEDBAT.
+#[derive(Debug)]
+struct BaseDelay {
+    // minimum of each past bucket, and of the current one
+    history: VecDeque<u32>,
+    current: Option<u32>,
+    bucket_start: Instant,
+}
+
+impl BaseDelay {
+    fn new() -> Self {
+        BaseDelay {
+            history: VecDeque::new(),
+            current: None,
+            bucket_start: Instant::now(),
+        }
+    }
+
+    // Records a sample, returning how far it is above the base delay.
+    fn sample(&mut self, delay: u32) -> i64 {
+        let lower = |a: u32, b: u32| if (a.wrapping_sub(b) as i32) < 0 { a } else { b };
+        if self.bucket_start.elapsed() >= BASE_DELAY_BUCKET {
+            self.history.extend(self.current.take());
+            if self.history.len() > BASE_DELAY_BUCKETS {
+                self.history.pop_front();
+            }
+            self.bucket_start = Instant::now();
+        }
+        self.current = Some(self.current.map_or(delay, |c| lower(c, delay)));
+        let base = self.history.iter().fold(delay, |base, &d| lower(base, d));
+        let base = lower(base, self.current.unwrap());
+        delay.wrapping_sub(base) as i32 as i64
+    }
+}
+
+struct State {
+    phase: Phase,
+    error: Option<io::ErrorKind>,
+    // ids in the packets we receive and send
+    recv_id: u16,
+    send_id: u16,
+    // next sequence number we send, and the last one received in order
+    seq_nr: u16,
+    ack_nr: u16,
+
+    // written but not sent yet
+    pending: BytesMut,
+    in_flight: VecDeque<Sent>,
+    // congestion window in bytes, and what the peer can take
+    cwnd: f64,
+    peer_wnd: usize,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    rto: Duration,
+    base_delay: BaseDelay,
+    last_ack: u16,
+    duplicate_acks: u32,
+    // the window is only halved once for losses up to this packet
+    loss_recovery: Option<u16>,
+
+    recv_buf: BytesMut,
+    out_of_order: HashMap<u16, Bytes>,
+    fin_seq: Option<u16>,
+    eof: bool,
+    // delay of the last packet received, told to the peer
+    reply_micros: u32,
+
+    last_recv: Instant,
+    last_sent: Instant,
+    // FIN goes out once everything written is sent
+    shutdown: bool,
+    fin_sent: bool,
+    dropped: bool,
+
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    connect_waker: Option<Waker>,
+}
+
+impl State {
+    fn new(phase: Phase, recv_id: u16, send_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        State {
+            phase,
+            error: None,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr,
+            pending: BytesMut::new(),
+            in_flight: VecDeque::new(),
+            cwnd: MAX_PAYLOAD as f64 * 2.0,
+            peer_wnd: MAX_PAYLOAD,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            rto: INITIAL_RTO,
+            base_delay: BaseDelay::new(),
+            last_ack: seq_nr.wrapping_sub(1),
+            duplicate_acks: 0,
+            loss_recovery: None,
+            recv_buf: BytesMut::new(),
+            out_of_order: HashMap::new(),
+            fin_seq: None,
+            eof: false,
+            reply_micros: 0,
+            last_recv: Instant::now(),
+            last_sent: Instant::now(),
+            shutdown: false,
+            fin_sent: false,
+            dropped: false,
+            read_waker: None,
+            write_waker: None,
+            connect_waker: None,
+        }
+    }
+
+    fn header(&self, kind: u8, seq_nr: u16) -> Header {
+        Header {
+            kind,
+            conn_id: if kind == ST_SYN {
+                self.recv_id
+            } else {
+                self.send_id
+            },
+            timestamp: micros(),
+            timestamp_diff: self.reply_micros,
+            wnd_size: RECV_BUFFER.saturating_sub(self.recv_buf.len()) as u32,
+            seq_nr,
+            ack_nr: self.ack_nr,
+        }
+    }
+
+    fn bytes_in_flight(&self) -> usize {
+        self.in_flight.iter().map(|p| p.payload.len()).sum()
+    }
+
+    // Packets received past a gap, bit `i` is `ack_nr + 2 + i`.
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+        let mut mask = vec![0u8; MAX_SACK_BITS / 8];
+        let mut len = 0;
+        for seq in self.out_of_order.keys() {
+            let bit = seq.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+            if bit < MAX_SACK_BITS {
+                mask[bit / 8] |= 1 << (bit % 8);
+                len = len.max(bit / 32 * 4 + 4);
+            }
+        }
+        mask.truncate(len);
+        Some(mask)
+    }
+
+    fn wake_all(&mut self) {
+        for waker in [
+            self.read_waker.take(),
+            self.write_waker.take(),
+            self.connect_waker.take(),
+        ]
+        .into_iter()
+        .flatten()
+        {
+            waker.wake();
+        }
+    }
+
+    fn fail(&mut self, kind: io::ErrorKind) {
+        if self.error.is_none() {
+            self.error = Some(kind);
+        }
+        self.wake_all();
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+            Some(rtt) => {
+                let delta = rtt.abs_diff(sample);
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+        }
+        self.rto = (self.rtt.unwrap() + self.rtt_var * 4).clamp(MIN_RTO, MAX_RTO);
+    }
+
+    fn on_loss(&mut self, seq_nr: u16) {
+        if self
+            .loss_recovery
+            .is_some_and(|until| !seq_after(seq_nr, until))
+        {
+            return;
+        }
+        self.cwnd = (self.cwnd / 2.0).max(MAX_PAYLOAD as f64);
+        self.loss_recovery = Some(self.seq_nr.wrapping_sub(1));
+    }
+}
+
+// One connection, shared by its stream and the socket that feeds it packets.
+struct Conn {
+    udp: Arc<UdpSocket>,
+    remote: SocketAddr,
+    // where its datagrams go, IPv4 addresses are mapped on a dual-stack socket
+    to: SocketAddr,
+    state: Mutex<State>,
+}
+
+impl Conn {
+    // Datagrams that don't fit in the socket buffer are lost like on the wire, the
+    // retransmission takes care of them.
+    fn send(&self, state: &mut State, packet: &[u8]) {
+        let _ = self.udp.try_send_to(packet, self.to);
+        state.last_sent = Instant::now();
+    }
+
+    fn send_state(&self, state: &mut State) {
+        let sack = state.selective_ack();
+        let packet = state
+            .header(ST_STATE, state.seq_nr)
+            .encode(sack.as_deref(), &[]);
+        self.send(state, &packet);
+    }
+
+    fn send_reset(&self, state: &mut State) {
+        let packet = state.header(ST_RESET, state.seq_nr).encode(None, &[]);
+        self.send(state, &packet);
+    }
+
+    fn transmit(&self, state: &mut State, index: usize) {
+        let sent = &state.in_flight[index];
+        let packet = state
+            .header(sent.kind, sent.seq_nr)
+            .encode(None, &sent.payload);
+        let sent = &mut state.in_flight[index];
+        sent.sent_at = Instant::now();
+        sent.transmissions += 1;
+        self.send(state, &packet);
+    }
+
+    // Queues a packet that takes a sequence number and sends it.
+    fn push(&self, state: &mut State, kind: u8, payload: Bytes) {
+        state.in_flight.push_back(Sent {
+            kind,
+            seq_nr: state.seq_nr,
+            payload,
+            sent_at: Instant::now(),
+            transmissions: 0,
+        });
+        state.seq_nr = state.seq_nr.wrapping_add(1);
+        self.transmit(state, state.in_flight.len() - 1);
+    }
+
+    // Sends what the congestion and receive windows allow.
+    fn flush(&self, state: &mut State) {
+        if state.phase != Phase::Connected || state.error.is_some() {
+            return;
+        }
+        let window = (state.cwnd as usize).min(state.peer_wnd);
+        let mut in_flight = state.bytes_in_flight();
+        while !state.pending.is_empty() && state.in_flight.len() < MAX_IN_FLIGHT {
+            let len = state.pending.len().min(MAX_PAYLOAD);
+            // one packet always goes, it probes a closed window
+            if !state.in_flight.is_empty() && in_flight + len > window {
+                break;
+            }
+            let payload = state.pending.split_to(len).freeze();
+            self.push(state, ST_DATA, payload);
+            in_flight += len;
+        }
+        if state.pending.len() < SEND_BUFFER {
+            if let Some(waker) = state.write_waker.take() {
+                waker.wake();
+            }
+        }
+        if state.shutdown && state.pending.is_empty() && !state.fin_sent {
+            self.push(state, ST_FIN, Bytes::new());
+            state.fin_sent = true;
+        }
+    }
+
+    fn on_packet(&self, header: Header, sack: Option<&[u8]>, payload: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        state.last_recv = Instant::now();
+        state.reply_micros = micros().wrapping_sub(header.timestamp);
+        state.peer_wnd = header.wnd_size as usize;
+
+        match header.kind {
+            ST_RESET => return state.fail(io::ErrorKind::ConnectionReset),
+            // our answer got lost
+            ST_SYN => return self.send_state(state),
+            _ => {}
+        }
+        if state.phase == Phase::SynSent {
+            if header.kind != ST_STATE {
+                return;
+            }
+            state.phase = Phase::Connected;
+            // the peer's first data packet takes this sequence number
+            state.ack_nr = header.seq_nr.wrapping_sub(1);
+            state.last_ack = header.ack_nr;
+            if let Some(waker) = state.connect_waker.take() {
+                waker.wake();
+            }
+        }
+
+        self.on_ack(state, &header, sack);
+        if header.kind == ST_DATA || header.kind == ST_FIN {
+            self.on_data(state, &header, payload);
+        }
+        self.flush(state);
+    }
+
+    fn on_ack(&self, state: &mut State, header: &Header, sack: Option<&[u8]>) {
+        let mut acked = 0;
+        let mut rtt = None;
+        while let Some(sent) = state.in_flight.front() {
+            if seq_after(sent.seq_nr, header.ack_nr) {
+                break;
+            }
+            if sent.transmissions == 1 {
+                rtt = Some(sent.sent_at.elapsed());
+            }
+            acked += sent.payload.len();
+            state.in_flight.pop_front();
+        }
+
+        // with few packets in flight there are never three to report a loss, so fewer do
+        let threshold = DUPLICATE_ACKS
+            .min(state.in_flight.len().saturating_sub(1) as u32)
+            .max(1);
+
+        // packets past the gap: one is lost once enough sent after it got through
+        let mut lost = vec![];
+        if let Some(mask) = sack {
+            let received = |sent: &Sent| {
+                let bit = sent.seq_nr.wrapping_sub(header.ack_nr).wrapping_sub(2) as usize;
+                bit < mask.len() * 8 && mask[bit / 8] >> (bit % 8) & 1 == 1
+            };
+            let mut later = 0;
+            for sent in state.in_flight.iter().rev() {
+                if received(sent) {
+                    later += 1;
+                } else if later >= threshold && sent.transmissions == 1 {
+                    lost.push(sent.seq_nr);
+                }
+            }
+            state.in_flight.retain(|sent| {
+                if received(sent) {
+                    acked += sent.payload.len();
+                    if sent.transmissions == 1 {
+                        rtt = Some(sent.sent_at.elapsed());
+                    }
+                }
+                !received(sent)
+            });
+        }
+
+        if header.kind == ST_STATE
+            && header.ack_nr == state.last_ack
+            && acked == 0
+            && !state.in_flight.is_empty()
+        {
+            state.duplicate_acks += 1;
+        } else if header.ack_nr != state.last_ack {
+            state.duplicate_acks = 0;
+        }
+        state.last_ack = header.ack_nr;
+
+        if state.duplicate_acks >= threshold {
+            if let Some(front) = state.in_flight.front() {
+                if front.transmissions == 1 {
+                    lost.push(front.seq_nr);
+                }
+            }
+            state.duplicate_acks = 0;
+        }
+        for seq_nr in lost.into_iter().rev() {
+            state.on_loss(seq_nr);
+            if let Some(index) = state.in_flight.iter().position(|s| s.seq_nr == seq_nr) {
+                self.transmit(state, index);
+            }
+        }
+
+        if let Some(rtt) = rtt {
+            state.update_rtt(rtt);
+        }
+        if acked > 0 && header.timestamp_diff != 0 {
+            // LEDBAT: grow while the queuing delay is under target, shrink above it
+            let delay = state.base_delay.sample(header.timestamp_diff);
+            let off_target = (TARGET_DELAY - delay) as f64 / TARGET_DELAY as f64;
+            let gain = MAX_CWND_INCREASE * off_target * acked as f64 / state.cwnd;
+            state.cwnd = (state.cwnd + gain).clamp(MAX_PAYLOAD as f64, SEND_BUFFER as f64);
+        }
+    }
+
+    fn on_data(&self, state: &mut State, header: &Header, payload: &[u8]) {
+        let offset = header.seq_nr.wrapping_sub(state.ack_nr);
+        // offset 0 and below were received already, the ack got lost
+        if offset > 0 && (offset as usize) <= MAX_IN_FLIGHT {
+            if header.kind == ST_FIN {
+                state.fin_seq = Some(header.seq_nr);
+            }
+            state
+                .out_of_order
+                .insert(header.seq_nr, Bytes::copy_from_slice(payload));
+            let mut delivered = false;
+            while let Some(data) = state.out_of_order.remove(&state.ack_nr.wrapping_add(1)) {
+                state.ack_nr = state.ack_nr.wrapping_add(1);
+                state.recv_buf.extend_from_slice(&data);
+                delivered = true;
+            }
+            if state.fin_seq == Some(state.ack_nr) {
+                state.eof = true;
+            }
+            if delivered || state.eof {
+                if let Some(waker) = state.read_waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+        self.send_state(state);
+    }
+
+    // Retransmits and keeps the connection alive, returns whether it is finished with.
+    fn tick(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if state.error.is_some() {
+            return true;
+        }
+        let now = Instant::now();
+        let expired: Vec<usize> = (0..state.in_flight.len())
+            .filter(|&i| now - state.in_flight[i].sent_at >= state.rto)
+            .collect();
+        if !expired.is_empty() {
+            if expired
+                .iter()
+                .any(|&i| state.in_flight[i].transmissions >= MAX_TRANSMISSIONS)
+            {
+                self.send_reset(state);
+                state.fail(io::ErrorKind::TimedOut);
+                return true;
+            }
+            state.rto = (state.rto * 2).min(MAX_RTO);
+            state.cwnd = MAX_PAYLOAD as f64;
+            for index in expired {
+                self.transmit(state, index);
+            }
+        }
+        if now - state.last_recv >= IDLE_TIMEOUT {
+            state.fail(io::ErrorKind::TimedOut);
+            return true;
+        }
+        if state.phase == Phase::Connected && now - state.last_sent >= KEEPALIVE {
+            self.send_state(state);
+        }
+        self.flush(state);
+        state.dropped && state.fin_sent && state.in_flight.is_empty()
+    }
+}
+
+// A uTP connection.
+pub struct UtpStream {
+    conn: Arc<Conn>,
+}
+
+impl UtpStream {
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.conn.remote
+    }
+}
+
+impl fmt::Debug for UtpStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpStream")
+            .field("remote", &self.conn.remote)
+            .finish()
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut state = self.conn.state.lock().unwrap();
+        let state = &mut *state;
+        if !state.recv_buf.is_empty() {
+            let was_full = state.recv_buf.len() + MAX_PAYLOAD > RECV_BUFFER;
+            let n = state.recv_buf.len().min(buf.remaining());
+            buf.put_slice(&state.recv_buf[..n]);
+            state.recv_buf.advance(n);
+            // the peer waits for the window to open
+            if was_full {
+                self.conn.send_state(state);
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if state.eof {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(kind) = state.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.conn.state.lock().unwrap();
+        let state = &mut *state;
+        if let Some(kind) = state.error {
+            return Poll::Ready(Err(kind.into()));
+        }
+        if state.shutdown {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let room = SEND_BUFFER.saturating_sub(state.pending.len());
+        if room == 0 {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = room.min(buf.len());
+        state.pending.extend_from_slice(&buf[..n]);
+        self.conn.flush(state);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.conn.state.lock().unwrap();
+        state.shutdown = true;
+        self.conn.flush(&mut state);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut state = self.conn.state.lock().unwrap();
+        if state.phase == Phase::SynSent {
+            return state.fail(io::ErrorKind::ConnectionAborted);
+        }
+        state.shutdown = true;
+        state.dropped = true;
+        self.conn.flush(&mut state);
+    }
+}
+
+struct SocketInner {
+    udp: Arc<UdpSocket>,
+    local_addr: SocketAddr,
+    // by remote address and the connection id of the packets we receive
+    conns: Mutex<HashMap<(SocketAddr, u16), Arc<Conn>>>,
+    incoming: mpsc::Sender<UtpStream>,
+    // datagrams that aren't uTP, for a DHT sharing the port
+    other: Mutex<Option<mpsc::Sender<(Bytes, SocketAddr)>>>,
+}
+
+impl SocketInner {
+    fn on_datagram(&self, buf: &[u8], addr: SocketAddr) {
+        let addr = net::canonical(addr);
+        let Some(Packet {
+            header,
+            sack,
+            payload,
+        }) = Header::parse(buf)
+        else {
+            if let Some(other) = &*self.other.lock().unwrap() {
+                let _ = other.try_send((Bytes::copy_from_slice(buf), addr));
+            }
+            return;
+        };
+
+        let conn = if header.kind == ST_SYN {
+            // the connection we accepted already, if the SYN is sent again
+            let key = (addr, header.conn_id.wrapping_add(1));
+            let existing = self.conns.lock().unwrap().get(&key).cloned();
+            match existing {
+                Some(conn) => conn,
+                None => return self.accept(header, addr),
+            }
+        } else {
+            match self
+                .conns
+                .lock()
+                .unwrap()
+                .get(&(addr, header.conn_id))
+                .cloned()
+            {
+                Some(conn) => conn,
+                None => return,
+            }
+        };
+        conn.on_packet(header, sack, payload);
+    }
+
+    fn accept(&self, syn: Header, addr: SocketAddr) {
+        let mut state = State::new(
+            Phase::Connected,
+            syn.conn_id.wrapping_add(1),
+            syn.conn_id,
+            random_u16(),
+            syn.seq_nr,
+        );
+        state.reply_micros = micros().wrapping_sub(syn.timestamp);
+        state.peer_wnd = syn.wnd_size as usize;
+        let conn = Arc::new(Conn {
+            udp: self.udp.clone(),
+            remote: addr,
+            to: net::to_family(addr, self.local_addr),
+            state: Mutex::new(state),
+        });
+        let mut state = conn.state.lock().unwrap();
+        if self.incoming.capacity() == 0 {
+            conn.send_reset(&mut state);
+            return;
+        }
+        conn.send_state(&mut state);
+        drop(state);
+        self.conns
+            .lock()
+            .unwrap()
+            .insert((addr, syn.conn_id.wrapping_add(1)), conn.clone());
+        let _ = self.incoming.try_send(UtpStream { conn });
+    }
+}
+
+// A UDP socket carrying uTP connections both ways, and handing other datagrams to whoever
+// shares the port.
+pub struct UtpSocket {
+    inner: Arc<SocketInner>,
+    local_addr: SocketAddr,
+    incoming: tokio::sync::Mutex<mpsc::Receiver<UtpStream>>,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl fmt::Debug for UtpSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UtpSocket")
+            .field("local_addr", &self.local_addr)
+            .finish()
+    }
+}
+
+impl UtpSocket {
+    pub async fn bind(addr: impl ToSocketAddrs) -> anyhow::Result<Self> {
+        let udp = Arc::new(UdpSocket::bind(addr).await.context("Binding uTP socket")?);
+        let local_addr = udp.local_addr()?;
+        let (incoming_tx, incoming_rx) = mpsc::channel(BACKLOG);
+        let inner = Arc::new(SocketInner {
+            udp,
+            local_addr,
+            conns: Mutex::new(HashMap::new()),
+            incoming: incoming_tx,
+            other: Mutex::new(None),
+        });
+
+        let receiving = inner.clone();
+        let receiver = tokio::spawn(async move {
+            let mut buf = vec![0; MAX_DATAGRAM];
+            loop {
+                match receiving.udp.recv_from(&mut buf).await {
+                    Ok((len, addr)) => receiving.on_datagram(&buf[..len], addr),
+                    // ICMP errors of earlier datagrams show up here on some systems
+                    Err(_) => tokio::time::sleep(TICK).await,
+                }
+            }
+        });
+        let ticking = inner.clone();
+        let ticker = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TICK);
+            loop {
+                interval.tick().await;
+                let conns: Vec<_> = ticking
+                    .conns
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(key, conn)| (*key, conn.clone()))
+                    .collect();
+                for (key, conn) in conns {
+                    if conn.tick() {
+                        ticking.conns.lock().unwrap().remove(&key);
+                    }
+                }
+            }
+        });
+
+        Ok(UtpSocket {
+            inner,
+            local_addr,
+            incoming: tokio::sync::Mutex::new(incoming_rx),
+            tasks: [receiver, ticker],
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub async fn connect(&self, addr: SocketAddr) -> anyhow::Result<UtpStream> {
+        let addr = net::canonical(addr);
+        let conn = {
+            let mut conns = self.inner.conns.lock().unwrap();
+            let recv_id = loop {
+                let id = random_u16();
+                if !conns.contains_key(&(addr, id)) {
+                    break id;
+                }
+            };
+            let conn = Arc::new(Conn {
+                udp: self.inner.udp.clone(),
+                remote: addr,
+                to: net::to_family(addr, self.local_addr),
+                state: Mutex::new(State::new(
+                    Phase::SynSent,
+                    recv_id,
+                    recv_id.wrapping_add(1),
+                    1,
+                    0,
+                )),
+            });
+            conns.insert((addr, recv_id), conn.clone());
+            conn
+        };
+        {
+            let mut state = conn.state.lock().unwrap();
+            conn.push(&mut state, ST_SYN, Bytes::new());
+        }
+
+        let stream = UtpStream { conn };
+        std::future::poll_fn(|cx| {
+            let mut state = stream.conn.state.lock().unwrap();
+            if let Some(kind) = state.error {
+                return Poll::Ready(Err(io::Error::from(kind)));
+            }
+            if state.phase == Phase::Connected {
+                return Poll::Ready(Ok(()));
+            }
+            state.connect_waker = Some(cx.waker().clone());
+            Poll::Pending
+        })
+        .await
+        .with_context(|| format!("uTP connection to {addr}"))?;
+        Ok(stream)
+    }
+
+    pub async fn accept(&self) -> anyhow::Result<UtpStream> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| anyhow!("uTP socket closed"))
+    }
+
+    // Datagrams that aren't uTP from now on, a DHT on the same port reads its queries here.
+    pub fn other_datagrams(&self, capacity: usize) -> mpsc::Receiver<(Bytes, SocketAddr)> {
+        let (tx, rx) = mpsc::channel(capacity);
+        *self.inner.other.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> anyhow::Result<()> {
+        self.inner
+            .udp
+            .send_to(buf, net::to_family(addr, self.local_addr))
+            .await?;
+        Ok(())
+    }
+}
+
+impl Drop for UtpSocket {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+// How to reach a peer: TCP, or uTP falling back to TCP.
+#[derive(Debug, Clone, Default)]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Utp(Arc<UtpSocket>),
+}
+
+impl Transport {
+    pub async fn dial(&self, addr: SocketAddr) -> anyhow::Result<Box<dyn ByteStream>> {
+        match self {
+            // an IPv4 socket can't reach IPv6 peers
+            Transport::Utp(socket) if addr.is_ipv4() || socket.local_addr().is_ipv6() => {
+                // peers without uTP don't answer at all
+                if let Ok(Ok(stream)) =
+                    tokio::time::timeout(UTP_CONNECT_TIMEOUT, socket.connect(addr)).await
+                {
+                    return Ok(Box::new(stream));
+                }
+            }
+            _ => {}
+        }
+        let stream = TcpStream::connect(addr)
+            .await
+            .context("Connecting to peer")?;
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn socket() -> UtpSocket {
+        UtpSocket::bind("127.0.0.1:0").await.unwrap()
+    }
+
+    // Sends `sent` from the connecting side to the accepting one, which answers once it has
+    // read up to the connecting side's FIN.
+    async fn transfer(connecting: &UtpSocket, to: SocketAddr, accepting: &UtpSocket, sent: &[u8]) {
+        let (client, server) = tokio::join!(connecting.connect(to), accepting.accept());
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+
+        let sending = async {
+            client.write_all(sent).await.unwrap();
+            client.shutdown().await.unwrap();
+        };
+        let receiving = async {
+            let mut received = vec![];
+            server.read_to_end(&mut received).await.unwrap();
+            received
+        };
+        let (_, received) = tokio::join!(sending, receiving);
+        assert!(
+            received == sent,
+            "{} of {} bytes",
+            received.len(),
+            sent.len()
+        );
+
+        server.write_all(b"bye").await.unwrap();
+        server.shutdown().await.unwrap();
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"bye");
+    }
+
+    #[tokio::test]
+    async fn bulk_transfer() {
+        let (a, b) = (socket().await, socket().await);
+        let sent = data(3 * RECV_BUFFER);
+        tokio::time::timeout(
+            Duration::from_secs(30),
+            transfer(&a, b.local_addr(), &b, &sent),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn lost_packet_is_sent_again() {
+        let (a, b) = (socket().await, socket().await);
+        // between the two, dropping the fifth data packet once
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let server = b.local_addr();
+        let resent = Arc::new(AtomicBool::new(false));
+        let relaying = tokio::spawn({
+            let resent = resent.clone();
+            async move {
+                let (mut client, mut data_packets, mut dropped) = (None, 0, None);
+                let mut buf = vec![0; MAX_DATAGRAM];
+                loop {
+                    let (len, from) = relay.recv_from(&mut buf).await.unwrap();
+                    if from == server {
+                        relay.send_to(&buf[..len], client.unwrap()).await.unwrap();
+                        continue;
+                    }
+                    client = Some(from);
+                    let header = Header::parse(&buf[..len]).unwrap().header;
+                    if header.kind == ST_DATA {
+                        data_packets += 1;
+                        if data_packets == 5 {
+                            dropped = Some(header.seq_nr);
+                            continue;
+                        }
+                        if dropped == Some(header.seq_nr) {
+                            resent.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    relay.send_to(&buf[..len], server).await.unwrap();
+                }
+            }
+        });
+
+        let sent = data(100 * MAX_PAYLOAD);
+        tokio::time::timeout(Duration::from_secs(30), transfer(&a, relay_addr, &b, &sent))
+            .await
+            .unwrap();
+        relaying.abort();
+        assert!(resent.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn other_datagrams() {
+        let socket = socket().await;
+        let mut other = socket.other_datagrams(4);
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let query = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        peer.send_to(query, socket.local_addr()).await.unwrap();
+
+        let (received, from) = other.recv().await.unwrap();
+        assert_eq!(&received[..], query);
+        assert_eq!(from, peer.local_addr().unwrap());
+
+        socket.send_to(b"answer", from).await.unwrap();
+        let mut buf = [0; 16];
+        let (len, _) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], b"answer");
+    }
+}
@@ -0,0 +1,259 @@
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::bitfield::Bitfield;
+use crate::merkle;
+use crate::piece::PieceCheck;
+use crate::storage::{FileLayout, FsStorage, PieceStore};
+use crate::torrent::{TorrentFile, V2File};
+
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    pub path: PathBuf,
+    pub length: u64,
+    pub exists: bool,
+    // found on disk, 0 when missing
+    pub size: u64,
+    // pieces overlapping the file, and how many of them are valid
+    pub pieces: usize,
+    pub verified: usize,
+}
+
+impl FileReport {
+    pub fn is_complete(&self) -> bool {
+        self.exists && self.size == self.length && self.pieces == self.verified
+    }
+}
+
+pub fn default_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+// Reads every piece found at `paths` on `threads` threads and maps it with `f`.
+pub fn map_pieces<T, F>(layout: &FileLayout, paths: &[PathBuf], threads: usize, f: F) -> Vec<T>
+where
+    T: Send,
+    F: Fn(usize, anyhow::Result<Vec<u8>>) -> T + Sync,
+{
+    let num_pieces = layout.num_pieces();
+    let next_piece = AtomicUsize::new(0);
+    let results = Mutex::new((0..num_pieces).map(|_| None).collect::<Vec<_>>());
+
+    std::thread::scope(|s| {
+        for _ in 0..threads.clamp(1, num_pieces.max(1)) {
+            s.spawn(|| {
+                let mut store = PieceStore::new(
+                    layout.clone(),
+                    Box::new(FsStorage::read_only(paths.to_vec())),
+                );
+                loop {
+                    let index = next_piece.fetch_add(1, Ordering::Relaxed);
+                    if index >= num_pieces {
+                        break;
+                    }
+                    let result = f(index, store.read_piece(index));
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|r| r.expect("every piece is mapped"))
+        .collect()
+}
+
+// Hashes the pieces found at `paths`. Unreadable pieces count as invalid.
+pub fn check_pieces(
+    layout: &FileLayout,
+    paths: &[PathBuf],
+    checks: &[PieceCheck],
+    threads: usize,
+) -> Bitfield {
+    let valid = map_pieces(layout, paths, threads, |index, piece| {
+        piece.is_ok_and(|piece| checks.get(index).is_some_and(|check| check.verify(&piece)))
+    });
+
+    let mut have = Bitfield::new(checks.len());
+    valid
+        .iter()
+        .enumerate()
+        .filter(|(_, &valid)| valid)
+        .for_each(|(index, _)| have.set(index));
+    have
+}
+
+pub fn file_reports(layout: &FileLayout, paths: &[PathBuf], have: &Bitfield) -> Vec<FileReport> {
+    layout
+        .files
+        .iter()
+        .zip(paths)
+        .map(|(file, path)| {
+            let pieces = if file.length == 0 {
+                0..0
+            } else {
+                let first = (file.offset / layout.piece_length) as usize;
+                let last = ((file.offset + file.length - 1) / layout.piece_length) as usize;
+                first..last + 1
+            };
+            let meta = std::fs::metadata(path).ok().filter(|m| m.is_file());
+            FileReport {
+                path: path.clone(),
+                length: file.length,
+                exists: meta.is_some(),
+                size: meta.map_or(0, |m| m.len()),
+                verified: pieces.clone().filter(|&i| have.has(i)).count(),
+                pieces: pieces.len(),
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct MerkleReport {
+    pub path: PathBuf,
+    pub length: u64,
+    pub pieces: usize,
+    pub verified: usize,
+    pub error: Option<String>,
+}
+
+impl MerkleReport {
+    pub fn is_complete(&self) -> bool {
+        self.error.is_none() && self.pieces == self.verified
+    }
+}
+
+// data paths of the v2 files, following the same layout as v1 downloads
+pub fn v2_paths(torrent: &TorrentFile, output: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let multi_file = torrent.info.is_multi_file();
+    Ok(torrent
+        .info
+        .file_list()?
+        .into_iter()
+        .zip(torrent.info.v2_files()?)
+        .map(|((path, _), _)| {
+            if multi_file {
+                output.join(path)
+            } else {
+                output.to_owned()
+            }
+        })
+        .collect())
+}
+
+fn check_file_v2(
+    torrent: &TorrentFile,
+    file: &V2File,
+    path: &Path,
+) -> anyhow::Result<(usize, usize)> {
+    let Some(root) = file.pieces_root else {
+        anyhow::ensure!(path.is_file(), "Missing file");
+        return Ok((0, 0));
+    };
+
+    let data = File::open(path)?;
+    anyhow::ensure!(data.metadata()?.len() == file.length, "Invalid file size");
+
+    let piece_length = torrent.info.piece_length;
+    if file.length <= piece_length as u64 {
+        let mut buf = vec![0; file.length as usize];
+        data.read_exact_at(&mut buf, 0)?;
+        let valid = merkle::small_file_root(&buf) == root;
+        return Ok((1, valid as usize));
+    }
+
+    let layer = torrent.piece_layer(file)?;
+    let mut verified = 0;
+    let mut buf = vec![0; piece_length];
+    for (index, piece_hash) in layer.iter().enumerate() {
+        let offset = (index * piece_length) as u64;
+        let len = std::cmp::min(piece_length as u64, file.length - offset) as usize;
+        data.read_exact_at(&mut buf[..len], offset)?;
+        if merkle::piece_hash(&buf[..len], piece_length) == *piece_hash {
+            verified += 1;
+        }
+    }
+    Ok((layer.len(), verified))
+}
+
+// Checks every file of a v2 torrent against its merkle tree, files are spread over `threads`.
+pub fn check_files_v2(
+    torrent: &TorrentFile,
+    paths: &[PathBuf],
+    threads: usize,
+) -> anyhow::Result<Vec<MerkleReport>> {
+    let files = torrent.info.v2_files()?;
+    let next_file = AtomicUsize::new(0);
+    let reports = Mutex::new(vec![None; files.len()]);
+
+    std::thread::scope(|s| {
+        for _ in 0..threads.clamp(1, files.len().max(1)) {
+            s.spawn(|| loop {
+                let index = next_file.fetch_add(1, Ordering::Relaxed);
+                let (Some(file), Some(path)) = (files.get(index), paths.get(index)) else {
+                    break;
+                };
+                let (pieces, verified, error) = match check_file_v2(torrent, file, path) {
+                    Ok((pieces, verified)) => (pieces, verified, None),
+                    Err(e) => (0, 0, Some(e.to_string())),
+                };
+                reports.lock().unwrap()[index] = Some(MerkleReport {
+                    path: path.clone(),
+                    length: file.length,
+                    pieces,
+                    verified,
+                    error,
+                });
+            });
+        }
+    });
+
+    Ok(reports
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::{self, CreateOptions};
+
+    #[test]
+    fn longer_file_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data");
+        std::fs::write(&path, vec![7; 40000]).unwrap();
+        let options = CreateOptions {
+            threads: 1,
+            ..Default::default()
+        };
+        let torrent = create::create_torrent(&path, &options).unwrap();
+        let layout = FileLayout::new(&torrent.info).unwrap();
+        let checks = crate::piece::v1_checks(&torrent, &layout).unwrap();
+        let paths = vec![path.clone()];
+
+        let have = check_pieces(&layout, &paths, &checks, 1);
+        let reports = file_reports(&layout, &paths, &have);
+        assert!(reports[0].is_complete());
+
+        // every piece still checks out, only the size gives it away
+        std::fs::write(&path, vec![7; 40010]).unwrap();
+        let have = check_pieces(&layout, &paths, &checks, 1);
+        assert_eq!(have.count(), have.len());
+        let reports = file_reports(&layout, &paths, &have);
+        assert_eq!(reports[0].size, 40010);
+        assert!(!reports[0].is_complete());
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::Context;
+use bytes::{Bytes, BytesMut};
+use reqwest::header::RANGE;
+use reqwest::{Client, StatusCode, Url};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::piece::PieceCheck;
+use crate::storage::FileLayout;
+
+// BEP 19 web seed: an HTTP server holding the torrent files, read with range requests.
+pub struct WebSeed {
+    pub url: Url,
+    client: Client,
+    file_urls: Vec<Url>,
+    layout: FileLayout,
+    // the server answered a range request with the whole file, it is no use for pieces
+    no_ranges: AtomicBool,
+}
+
+impl WebSeed {
+    pub fn new(url: &str, name: &str, layout: FileLayout) -> anyhow::Result<Self> {
+        let url = Url::parse(url).with_context(|| format!("Invalid web seed {url}"))?;
+
+        // multi-file torrents live below `url/name/`, single files are at `url` unless it
+        // names a directory
+        let file_urls = if layout.is_multi_file() {
+            layout
+                .files
+                .iter()
+                .map(|f| {
+                    let components = f.path.iter().map(|c| c.to_string_lossy().into_owned());
+                    join_url(&url, std::iter::once(name.to_owned()).chain(components))
+                })
+                .collect::<anyhow::Result<_>>()?
+        } else if url.path().ends_with('/') {
+            vec![join_url(&url, [name.to_owned()])?]
+        } else {
+            vec![url.clone()]
+        };
+
+        Ok(WebSeed {
+            url,
+            client: Client::new(),
+            file_urls,
+            layout,
+            no_ranges: AtomicBool::new(false),
+        })
+    }
+
+    // false once the server turned out to ignore ranges
+    pub fn supports_ranges(&self) -> bool {
+        !self.no_ranges.load(Ordering::Relaxed)
+    }
+
+    pub async fn download_piece(&self, index: usize, check: PieceCheck) -> anyhow::Result<Bytes> {
+        let start = self.layout.piece_offset(index);
+        let mut piece = BytesMut::zeroed(self.layout.piece_data_len(index));
+
+        for span in self.layout.piece_spans(index) {
+            let data = self
+                .fetch(span.file_index, span.file_offset, span.len)
+                .await?;
+            let at = (span.offset - start) as usize;
+            piece[at..at + span.len].copy_from_slice(&data);
+        }
+
+        anyhow::ensure!(
+            check.verify(&piece),
+            "Invalid hash of piece {index} from {}",
+            self.url
+        );
+        Ok(piece.freeze())
+    }
+
+    async fn fetch(&self, file_index: usize, offset: u64, len: usize) -> anyhow::Result<Bytes> {
+        let url = &self.file_urls[file_index];
+        let response = self
+            .client
+            .get(url.clone())
+            .header(
+                RANGE,
+                format!("bytes={}-{}", offset, offset + len as u64 - 1),
+            )
+            .send()
+            .await
+            .with_context(|| format!("Requesting {url}"))?;
+
+        let status = response.status();
+        let whole_file = offset == 0 && len as u64 == self.layout.files[file_index].length;
+        let data = match status {
+            StatusCode::PARTIAL_CONTENT => response.bytes().await?,
+            StatusCode::OK if whole_file => response.bytes().await?,
+            // the whole file for every piece, the body is left unread
+            StatusCode::OK => {
+                self.no_ranges.store(true, Ordering::Relaxed);
+                anyhow::bail!("{url}: no range support")
+            }
+            _ => anyhow::bail!("{url}: {status}"),
+        };
+        anyhow::ensure!(data.len() == len, "{url}: short read {}/{len}", data.len());
+        Ok(data)
+    }
+}
+
+fn join_url(base: &Url, components: impl IntoIterator<Item = String>) -> anyhow::Result<Url> {
+    let mut url = base.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid web seed {base}"))?
+        .pop_if_empty()
+        .extend(components);
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+    use tokio::io::{AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use crate::http::{self, Request};
+
+    // A web server on loopback holding `files` by path. It answers ranges with 206, or with
+    // the whole file without `ranges`.
+    async fn server(files: HashMap<&'static str, Vec<u8>>, ranges: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let files = Arc::new(files);
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let files = files.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let request = Request::read(&mut stream).await.unwrap();
+                    let path = http::percent_decode(request.path());
+                    let Some(file) = files.get(path.as_str()) else {
+                        http::write_response(&mut stream, 404, "text/plain", b"")
+                            .await
+                            .unwrap();
+                        return;
+                    };
+                    let range = request
+                        .header("range")
+                        .filter(|_| ranges)
+                        .and_then(|range| http::byte_range(range, file.len() as u64));
+                    let Some((start, end)) = range else {
+                        http::write_response(&mut stream, 200, "application/octet-stream", file)
+                            .await
+                            .unwrap();
+                        return;
+                    };
+                    let headers = [
+                        ("Content-Length", (end - start + 1).to_string()),
+                        (
+                            "Content-Range",
+                            format!("bytes {start}-{end}/{}", file.len()),
+                        ),
+                    ];
+                    http::write_head(&mut stream, 206, &headers).await.unwrap();
+                    stream
+                        .write_all(&file[start as usize..=end as usize])
+                        .await
+                        .unwrap();
+                    stream.flush().await.unwrap();
+                });
+            }
+        });
+        format!("http://{addr}")
+    }
+
+    fn check(data: &[u8], length: usize) -> PieceCheck {
+        PieceCheck::Sha1 {
+            hash: Sha1::digest(data).into(),
+            length,
+        }
+    }
+
+    async fn download_all(seed: &WebSeed, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        let layout = &seed.layout;
+        let mut out = vec![];
+        for index in 0..layout.num_pieces() {
+            let start = layout.piece_offset(index) as usize;
+            let piece = &data[start..start + layout.piece_len(index)];
+            out.extend(
+                seed.download_piece(index, check(piece, piece.len()))
+                    .await?,
+            );
+        }
+        Ok(out)
+    }
+
+    #[tokio::test]
+    async fn multi_file_ranges() {
+        let data: Vec<u8> = (0..17).collect();
+        let files = HashMap::from([
+            ("/seed/dir/a", data[..5].to_vec()),
+            ("/seed/dir/sub dir/b", data[5..].to_vec()),
+        ]);
+        let url = server(files, true).await;
+        let layout =
+            FileLayout::from_files(vec![("a".into(), 5), ("sub dir/b".into(), 12)], 4, true);
+        let seed = WebSeed::new(&format!("{url}/seed/"), "dir", layout).unwrap();
+        assert_eq!(download_all(&seed, &data).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn single_file_urls() {
+        let data: Vec<u8> = (0..10).collect();
+        let files = HashMap::from([("/file.bin", data.clone()), ("/dir/name.bin", data.clone())]);
+        let url = server(files, true).await;
+        let layout = FileLayout::from_files(vec![("name.bin".into(), 10)], 4, false);
+
+        let seed = WebSeed::new(&format!("{url}/file.bin"), "name.bin", layout.clone()).unwrap();
+        assert_eq!(download_all(&seed, &data).await.unwrap(), data);
+        // a directory holds the file under the torrent name
+        let seed = WebSeed::new(&format!("{url}/dir/"), "name.bin", layout).unwrap();
+        assert_eq!(download_all(&seed, &data).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn no_range_support() {
+        let data: Vec<u8> = (0..10).collect();
+        let url = server(HashMap::from([("/file.bin", data.clone())]), false).await;
+
+        let layout = FileLayout::from_files(vec![("file.bin".into(), 10)], 4, false);
+        let seed = WebSeed::new(&format!("{url}/file.bin"), "file.bin", layout).unwrap();
+        assert!(download_all(&seed, &data).await.is_err());
+        assert!(!seed.supports_ranges());
+
+        // a file in one piece is asked for whole anyway
+        let layout = FileLayout::from_files(vec![("file.bin".into(), 10)], 16, false);
+        let seed = WebSeed::new(&format!("{url}/file.bin"), "file.bin", layout).unwrap();
+        assert_eq!(download_all(&seed, &data).await.unwrap(), data);
+        assert!(seed.supports_ranges());
+    }
+}